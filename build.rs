@@ -7,12 +7,90 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also generates the board's pin assignment from `board.toml` (or the
+//! file in the `BOARD` environment variable), see [`Board`].
 
+use std::collections::HashSet;
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
+use serde::Deserialize;
+
+const DEFAULT_BOARD: &str = "board.toml";
+const GPIO_COUNT: u8 = 30;
+const GROUP_SIZE: usize = 8;
+const MAX_GROUPS: usize = 4;
+const ADC_PINS: std::ops::RangeInclusive<u8> = 26..=29;
+const UART0_TX_PINS: [u8; 4] = [0, 12, 16, 28];
+/// Function of a GPIO on its SPI block, by the GPIO number modulo 4
+const SPI_RX: u8 = 0;
+const SPI_CS: u8 = 1;
+const SPI_SCK: u8 = 2;
+const SPI_TX: u8 = 3;
+
+/// Pin assignment of a board, all pins are RP2040 GPIO numbers
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Board {
+    int_out: u8,
+    en_out: u8,
+    led: u8,
+    ws2812: u8,
+    #[serde(default)]
+    adc_pins: Vec<u8>,
+    i2c: I2c,
+    uart: Uart,
+    /// Pins of the optional SPI slave transport, which enables the `spi_transport` cfg
+    spi: Option<Spi>,
+    groups: Vec<Group>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct I2c {
+    sda: u8,
+    scl: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Uart {
+    tx: u8,
+    rx: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Spi {
+    sck: u8,
+    /// Data from the host, an SPI RX pin
+    mosi: u8,
+    /// Data to the host, an SPI TX pin
+    miso: u8,
+    cs: u8,
+}
+
+impl Spi {
+    /// The SPI block the pins belong to
+    fn block(&self) -> u8 {
+        self.sck / 8 % 2
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Group {
+    pins: Vec<u8>,
+    #[serde(default)]
+    trigger_int_out: bool,
+    #[serde(default)]
+    default_modes: u8,
+}
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -29,6 +107,20 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    let board_path = env::var("BOARD").unwrap_or_else(|_| DEFAULT_BOARD.into());
+    println!("cargo:rerun-if-env-changed=BOARD");
+    println!("cargo:rerun-if-changed={board_path}");
+    let board = fs::read_to_string(&board_path)
+        .unwrap_or_else(|e| panic!("Failed to read board file {board_path}: {e}"));
+    let board: Board = toml::from_str(&board)
+        .unwrap_or_else(|e| panic!("Failed to parse board file {board_path}: {e}"));
+    validate(&board);
+    println!("cargo:rustc-check-cfg=cfg(spi_transport)");
+    if board.spi.is_some() {
+        println!("cargo:rustc-cfg=spi_transport");
+    }
+    fs::write(out.join("board.rs"), generate(&board)).unwrap();
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
@@ -40,3 +132,199 @@ fn main() {
     println!("cargo:rustc-link-arg-tests=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-tests=-Tdefmt.x");
 }
+
+fn validate(board: &Board) {
+    assert!(
+        !board.groups.is_empty() && board.groups.len() <= MAX_GROUPS,
+        "A board needs between 1 and {MAX_GROUPS} groups"
+    );
+    assert!(
+        board.i2c.sda.is_multiple_of(4) && board.i2c.scl == board.i2c.sda + 1,
+        "I2C pins must be an I2C0 pair (SDA = 4n, SCL = 4n + 1)"
+    );
+    assert!(
+        UART0_TX_PINS.contains(&board.uart.tx) && board.uart.rx == board.uart.tx + 1,
+        "UART pins must be a UART0 pair (TX = 0, 12, 16 or 28, RX = TX + 1)"
+    );
+    if let Some(spi) = &board.spi {
+        for (pin, function) in [
+            (spi.sck, SPI_SCK),
+            (spi.mosi, SPI_RX),
+            (spi.miso, SPI_TX),
+            (spi.cs, SPI_CS),
+        ] {
+            assert!(
+                pin % 4 == function && pin / 8 % 2 == spi.block(),
+                "SPI pins must be on one SPI block (SCK = 4n + 2, MOSI = 4n, MISO = 4n + 3, \
+                 CS = 4n + 1), GPIO {pin} isn't"
+            );
+        }
+    }
+
+    let mut used = HashSet::new();
+    let single_pins = [
+        board.int_out,
+        board.en_out,
+        board.led,
+        board.ws2812,
+        board.i2c.sda,
+        board.i2c.scl,
+        board.uart.tx,
+        board.uart.rx,
+    ];
+    for pin in board.adc_pins.iter() {
+        assert!(ADC_PINS.contains(pin), "GPIO {pin} isn't an ADC pin");
+    }
+    let spi_pins = board
+        .spi
+        .iter()
+        .flat_map(|spi| [spi.sck, spi.mosi, spi.miso, spi.cs]);
+    let group_pins = board.groups.iter().flat_map(|group| group.pins.iter());
+    let pins = single_pins
+        .iter()
+        .copied()
+        .chain(spi_pins)
+        .chain(board.adc_pins.iter().copied())
+        .chain(group_pins.copied());
+    for pin in pins {
+        assert!(pin < GPIO_COUNT, "GPIO {pin} doesn't exist");
+        assert!(used.insert(pin), "GPIO {pin} is assigned more than once");
+    }
+    for (i, group) in board.groups.iter().enumerate() {
+        assert!(
+            group.pins.len() <= GROUP_SIZE,
+            "Group {i} has more than {GROUP_SIZE} pins"
+        );
+    }
+}
+
+fn generate(board: &Board) -> String {
+    let mut code = String::new();
+    writeln!(
+        code,
+        "// @generated by build.rs from the board file, do not edit"
+    )
+    .unwrap();
+    for (name, pin) in [
+        ("P_INT_OUT", board.int_out),
+        ("P_EN_OUT", board.en_out),
+        ("P_LED", board.led),
+        ("P_WS2812", board.ws2812),
+        ("P_SDA", board.i2c.sda),
+        ("P_SCL", board.i2c.scl),
+        ("P_UART_TX", board.uart.tx),
+        ("P_UART_RX", board.uart.rx),
+    ] {
+        writeln!(code, "#[allow(non_camel_case_types)]").unwrap();
+        writeln!(
+            code,
+            "pub type {name} = embassy_rp::peripherals::PIN_{pin};"
+        )
+        .unwrap();
+    }
+
+    let mut spi_fields = String::new();
+    if let Some(spi) = &board.spi {
+        writeln!(code, "pub const SPI_BLOCK: u8 = {};", spi.block()).unwrap();
+        for (name, field, pin) in [
+            ("P_SPI_SCK", "spi_sck", spi.sck),
+            ("P_SPI_MOSI", "spi_mosi", spi.mosi),
+            ("P_SPI_MISO", "spi_miso", spi.miso),
+            ("P_SPI_CS", "spi_cs", spi.cs),
+        ] {
+            writeln!(code, "#[allow(non_camel_case_types)]").unwrap();
+            writeln!(
+                code,
+                "pub type {name} = embassy_rp::peripherals::PIN_{pin};"
+            )
+            .unwrap();
+            write!(spi_fields, "\n            {field}: $p.PIN_{pin},").unwrap();
+        }
+    }
+
+    let groups = board.groups.len();
+    writeln!(code, "pub const GROUPS: usize = {groups};").unwrap();
+    let default_modes: Vec<_> = board
+        .groups
+        .iter()
+        .map(|group| format!("{:#010b}", group.default_modes))
+        .collect();
+    writeln!(
+        code,
+        "pub const DEFAULT_PIN_MODES: [u8; GROUPS] = [{}];",
+        default_modes.join(", ")
+    )
+    .unwrap();
+
+    let adc_channels: Vec<_> = board
+        .adc_pins
+        .iter()
+        .map(|pin| (pin - ADC_PINS.start()).to_string())
+        .collect();
+    writeln!(code, "pub const ADC_PINS: usize = {};", adc_channels.len()).unwrap();
+    writeln!(
+        code,
+        "pub const ADC_CHANNELS: [u8; ADC_PINS] = [{}];",
+        adc_channels.join(", ")
+    )
+    .unwrap();
+    let adc_exprs: Vec<_> = board
+        .adc_pins
+        .iter()
+        .map(|pin| {
+            format!("embassy_rp::adc::Channel::new_pin($p.PIN_{pin}, embassy_rp::gpio::Pull::None)")
+        })
+        .collect();
+
+    let group_exprs: Vec<_> = board
+        .groups
+        .iter()
+        .map(|group| {
+            let pins: Vec<_> = group
+                .pins
+                .iter()
+                .map(|pin| format!("embassy_rp::gpio::Pin::degrade($p.PIN_{pin})"))
+                .collect();
+            format!(
+                "$crate::gpios::PinGroup::new([{}], {})",
+                pins.join(", "),
+                group.trigger_int_out
+            )
+        })
+        .collect();
+    writeln!(
+        code,
+        r#"
+/// Take the board's pins out of `embassy_rp::Peripherals`, building a [`BoardPins`].
+/// The remaining peripherals can still be used afterwards.
+#[macro_export]
+macro_rules! board_pins {{
+    ($p:ident) => {{
+        $crate::board::BoardPins {{
+            int_out: $p.PIN_{int_out},
+            en_out: $p.PIN_{en_out},
+            led: $p.PIN_{led},
+            ws2812: $p.PIN_{ws2812},
+            sda: $p.PIN_{sda},
+            scl: $p.PIN_{scl},
+            uart_tx: $p.PIN_{uart_tx},
+            uart_rx: $p.PIN_{uart_rx},{spi_fields}
+            adc_channels: [{adc}],
+            groups: [{groups}],
+        }}
+    }};
+}}"#,
+        int_out = board.int_out,
+        en_out = board.en_out,
+        led = board.led,
+        ws2812 = board.ws2812,
+        sda = board.i2c.sda,
+        scl = board.i2c.scl,
+        uart_tx = board.uart.tx,
+        uart_rx = board.uart.rx,
+        adc = adc_exprs.join(", "),
+        groups = group_exprs.join(", "),
+    )
+    .unwrap();
+    code
+}
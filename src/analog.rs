@@ -0,0 +1,196 @@
+//! Analog inputs, sampled in the background by [`crate::tasks::adc_task`].
+//!
+//! Channels 0-3 are GPIO26-29 (only those listed as `adc_pins` in the board file are available)
+//! and channel 4 is the internal temperature sensor. Readings are 12-bit and averaged over a
+//! configurable number of samples.
+//!
+//! Each channel can also have a low and high threshold. When a reading crosses one, the
+//! channel's flag is set and INT_OUT is asserted. It won't trip again until the reading has
+//! moved back past the threshold by the channel's hysteresis.
+
+use core::cell::RefCell;
+
+use defmt::{error, info, Format};
+use embassy_rp::adc::{Adc, Async, Channel};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use portable_atomic::{AtomicU16, AtomicU8, Ordering};
+
+use crate::SET_INT_OUT;
+
+/// ADC channel of the internal temperature sensor. The temperature in °C is roughly
+/// `27 - (reading * 3.3 / 4096 - 0.706) / 0.001721`.
+pub const TEMP_SENSOR_CHANNEL: u8 = 4;
+pub const CHANNELS: usize = 5;
+pub const DEFAULT_AVERAGING: u8 = 8;
+/// Time between each round of sampling all the enabled channels
+pub const SAMPLE_PERIOD_MS: u64 = 10;
+
+static READINGS: [AtomicU16; CHANNELS] = [
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+    AtomicU16::new(0),
+];
+static AVAILABLE: AtomicU8 = AtomicU8::new(0);
+static ENABLED: AtomicU8 = AtomicU8::new(0xFF);
+static AVERAGING: AtomicU8 = AtomicU8::new(DEFAULT_AVERAGING);
+static THRESHOLDS: Mutex<CriticalSectionRawMutex, RefCell<[Threshold; CHANNELS]>> =
+    Mutex::new(RefCell::new([Threshold::DISABLED; CHANNELS]));
+/// Channels that have gone above their high threshold in the low byte, and below their low
+/// threshold in the high byte
+static THRESHOLD_FLAGS: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+enum ThresholdState {
+    Normal,
+    AboveHigh,
+    BelowLow,
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+struct Threshold {
+    low: u16,
+    high: u16,
+    hysteresis: u16,
+    state: ThresholdState,
+}
+
+impl Threshold {
+    /// Readings are 12-bit, so these can never trip
+    const DISABLED: Self = Self {
+        low: 0,
+        high: u16::MAX,
+        hysteresis: 0,
+        state: ThresholdState::Normal,
+    };
+
+    /// Returns the flag to set if `reading` tripped a threshold
+    fn update(&mut self, channel: u8, reading: u16) -> Option<u16> {
+        match self.state {
+            ThresholdState::Normal if reading > self.high => {
+                self.state = ThresholdState::AboveHigh;
+                Some(1 << channel)
+            }
+            ThresholdState::Normal if reading < self.low => {
+                self.state = ThresholdState::BelowLow;
+                Some(1 << (channel + 8))
+            }
+            ThresholdState::AboveHigh if reading < self.high.saturating_sub(self.hysteresis) => {
+                self.state = ThresholdState::Normal;
+                None
+            }
+            ThresholdState::BelowLow if reading > self.low.saturating_add(self.hysteresis) => {
+                self.state = ThresholdState::Normal;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Mask of the channels that exist on this board
+pub fn available() -> u8 {
+    AVAILABLE.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_available(mask: u8) {
+    AVAILABLE.store(mask, Ordering::Relaxed);
+}
+
+/// Mask of the channels that are both available and being sampled
+pub fn enabled() -> u8 {
+    ENABLED.load(Ordering::Relaxed) & available()
+}
+
+pub fn set_enabled(mask: u8) {
+    ENABLED.store(mask, Ordering::Relaxed);
+}
+
+pub fn averaging() -> u8 {
+    AVERAGING.load(Ordering::Relaxed)
+}
+
+/// Set how many samples are averaged for each reading, 0 is treated as 1
+pub fn set_averaging(samples: u8) {
+    AVERAGING.store(samples.max(1), Ordering::Relaxed);
+}
+
+pub fn is_enabled(channel: u8) -> bool {
+    (channel as usize) < CHANNELS && enabled() & (1 << channel) != 0
+}
+
+/// Latest averaged reading of `channel`, or `None` if it isn't enabled
+pub fn reading(channel: u8) -> Option<u16> {
+    if is_enabled(channel) {
+        Some(READINGS[channel as usize].load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+pub(crate) fn store_reading(channel: u8, value: u16) {
+    READINGS[channel as usize].store(value, Ordering::Relaxed);
+    let tripped = THRESHOLDS
+        .lock(|thresholds| thresholds.borrow_mut()[channel as usize].update(channel, value));
+    if let Some(flag) = tripped {
+        info!("[ADC] CHANNEL {} THRESHOLD TRIPPED: {}", channel, value);
+        THRESHOLD_FLAGS.fetch_or(flag, Ordering::Relaxed);
+        SET_INT_OUT.signal(true);
+    }
+}
+
+/// Set the thresholds of `channel`, a reading above `high` or below `low` will trip them. Use
+/// `low = 0` and `high = 0xFFFF` to disable them.
+pub fn set_threshold(channel: u8, low: u16, high: u16) -> Option<()> {
+    THRESHOLDS.lock(|thresholds| {
+        let mut thresholds = thresholds.borrow_mut();
+        let threshold = thresholds.get_mut(channel as usize)?;
+        threshold.low = low;
+        threshold.high = high;
+        threshold.state = ThresholdState::Normal;
+        Some(())
+    })
+}
+
+pub fn set_hysteresis(channel: u8, hysteresis: u16) -> Option<()> {
+    THRESHOLDS.lock(|thresholds| {
+        thresholds
+            .borrow_mut()
+            .get_mut(channel as usize)?
+            .hysteresis = hysteresis;
+        Some(())
+    })
+}
+
+/// The flags of the thresholds that have tripped and haven't been taken yet, see
+/// [`THRESHOLD_FLAGS`]
+pub fn threshold_flags() -> u16 {
+    THRESHOLD_FLAGS.load(Ordering::Relaxed)
+}
+
+/// Returns the flags of the thresholds that have tripped since the last call, see
+/// [`THRESHOLD_FLAGS`]
+pub fn take_threshold_flags() -> u16 {
+    THRESHOLD_FLAGS.swap(0, Ordering::Relaxed)
+}
+
+/// Take an averaged reading of `channel` from `source` if it's enabled
+pub(crate) async fn sample(adc: &mut Adc<'static, Async>, channel: u8, source: &mut Channel<'_>) {
+    if !is_enabled(channel) {
+        return;
+    }
+    let samples = averaging();
+    let mut sum = 0u32;
+    for _ in 0..samples {
+        match adc.read(source).await {
+            Ok(value) => sum += value as u32,
+            Err(e) => {
+                error!("[ADC] CHANNEL {} READ_ERROR: {:?}", channel, e);
+                return;
+            }
+        }
+    }
+    store_reading(channel, (sum / samples as u32) as u16);
+}
@@ -0,0 +1,30 @@
+//! Pin assignment of the board, generated by `build.rs` from `board.toml` (or the file in the
+//! `BOARD` environment variable) so hardware variants don't need source changes.
+
+use crate::gpios::PinGroup;
+use embassy_rp::adc::Channel;
+
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
+
+/// Every pin used by the board, see [`crate::board_pins`]
+pub struct BoardPins {
+    pub int_out: P_INT_OUT,
+    pub en_out: P_EN_OUT,
+    pub led: P_LED,
+    pub ws2812: P_WS2812,
+    pub sda: P_SDA,
+    pub scl: P_SCL,
+    pub uart_tx: P_UART_TX,
+    pub uart_rx: P_UART_RX,
+    #[cfg(spi_transport)]
+    pub spi_sck: P_SPI_SCK,
+    #[cfg(spi_transport)]
+    pub spi_mosi: P_SPI_MOSI,
+    #[cfg(spi_transport)]
+    pub spi_miso: P_SPI_MISO,
+    #[cfg(spi_transport)]
+    pub spi_cs: P_SPI_CS,
+    /// ADC channels [`ADC_CHANNELS`], in the same order
+    pub adc_channels: [Channel<'static>; ADC_PINS],
+    pub groups: [PinGroup; GROUPS],
+}
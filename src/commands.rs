@@ -1,20 +1,205 @@
-use byte::ctx::Endian;
+use byte::ctx::{Bytes, Endian};
 use byte::{BytesExt, TryRead};
 use defmt::{error, Format};
 
+use crate::gpios::DriveStrength;
+use crate::measure::Quantity;
+
 #[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
 #[repr(u8)]
 pub enum GpioCommand {
     ReadIoModes = 0x01,
     WriteAllOutputs(u8, u8) = 0x02,
     SetIoModes(u8, u8) = 0x03,
+    /// Select which pair of groups the commands taking a byte per group address, bank `n`
+    /// addresses groups `2n` and `2n + 1`
+    SelectBank(u8) = 0x0E,
+    ReadBank = 0x0F,
+    /// Returns the index of the command the last batch failed at, or
+    /// [`crate::device::BATCH_OK`], then the number of its commands that were applied, see
+    /// [`crate::device::Device::handle_write_command`]
+    ReadBatchStatus = 0x10,
     WriteOutputs1(u8) = 0x11,
     WriteOutputs2(u8) = 0x12,
+    /// Returns the output latches (OLAT) of the selected bank. Writes to input pins are kept in
+    /// the latch and driven once the pins become outputs
+    ReadOutputLatch = 0x13,
+    /// Returns the modes, pull ups, pull downs, output latches, inputs and interrupt flags of
+    /// each group in the selected bank, then a status byte, all read at once. Clears the
+    /// interrupt flags and INT_OUT like a plain read, see [`crate::device::Device::snapshot`]
+    ReadSnapshot = 0x20,
     ReadInputs1 = 0x21,
     ReadInputs2 = 0x22,
     SetPullDowns(u8, u8) = 0x30,
     SetPullUps(u8, u8) = 0x31,
     SetPullNone(u8, u8) = 0x32,
+    /// Invert the logical value of the masked input pins
+    SetPolarity(u8, u8) = 0x40,
+    ReadPolarity = 0x41,
+    /// Configure the masked output pins as open drain, unmasked outputs are push-pull
+    SetOpenDrain(u8, u8) = 0x42,
+    ReadOpenDrain = 0x43,
+    /// Set the drive strength of the masked pins, see [`crate::gpios::DriveStrength`]
+    SetDriveStrength(u8, u8, DriveStrength) = 0x44,
+    /// Masked pins use a fast slew rate, unmasked pins a slow slew rate
+    SetSlewRates(u8, u8) = 0x45,
+    /// Enable the Schmitt trigger on the masked pins, disable it on the rest
+    SetSchmitt(u8, u8) = 0x46,
+    /// Returns 2 bits per pin for the given group, little endian
+    ReadDriveStrength(u8) = 0x47,
+    ReadSlewRates = 0x48,
+    ReadSchmitt = 0x49,
+    /// Save the current pin configuration to flash, it will be restored on the next boot
+    SaveConfig = 0x50,
+    /// Erase the saved configuration, the defaults will be used on the next boot
+    ClearConfig = 0x51,
+    /// Stage the modes of the selected bank, applied by [`Self::CommitStaged`]
+    StageIoModes(u8, u8) = 0x52,
+    /// Stage the pull ups of the selected bank, the masked pins get a pull up and the rest lose
+    /// it. Pins that get neither a pull up nor a pull down have no pull
+    StagePullUps(u8, u8) = 0x53,
+    /// Stage the pull downs of the selected bank, the masked pins get a pull down and the rest
+    /// lose it. A pull up takes precedence
+    StagePullDowns(u8, u8) = 0x54,
+    /// Stage the output levels of the selected bank, including pins that are inputs until the
+    /// staged modes make them outputs
+    StageOutputs(u8, u8) = 0x55,
+    /// Stage the input polarity of the selected bank
+    StagePolarity(u8, u8) = 0x56,
+    /// Apply everything staged to every group at once, setting the output levels and pulls
+    /// before any pin changes direction, see [`crate::gpios::PinGroup::apply_staged`]
+    CommitStaged = 0x57,
+    /// Drop everything staged
+    DiscardStaged = 0x58,
+    /// Returns the latest 12-bit reading of an ADC channel, little endian, see [`crate::analog`]
+    ReadAnalog(u8) = 0x60,
+    /// Select which ADC channels are sampled, bit `n` is channel `n`
+    SetAnalogChannels(u8) = 0x61,
+    /// Returns the mask of available channels followed by the mask of enabled channels
+    ReadAnalogChannels = 0x62,
+    /// Set the number of samples averaged for each ADC reading
+    SetAnalogAveraging(u8) = 0x63,
+    /// Set the (channel, low, high) thresholds of an ADC channel, both little endian
+    SetAnalogThreshold(u8, u16, u16) = 0x64,
+    /// Set the (channel, hysteresis) of an ADC channel's thresholds, little endian
+    SetAnalogHysteresis(u8, u16) = 0x65,
+    /// Returns and clears the masks of channels that went above their high threshold and below
+    /// their low threshold respectively
+    ReadAnalogFlags = 0x66,
+    /// Count rising edges on the masked input pins
+    SetCountRising(u8, u8) = 0x70,
+    /// Count falling edges on the masked input pins
+    SetCountFalling(u8, u8) = 0x71,
+    ReadCountRising = 0x72,
+    ReadCountFalling = 0x73,
+    /// Returns and clears the 32-bit edge count of a (group, pin), little endian
+    ReadCounter(u8, u8) = 0x74,
+    /// Reset the edge counts of the masked pins
+    ClearCounts(u8, u8) = 0x75,
+    /// Measure the frequency and pulse widths of the masked input pins, see [`crate::measure`]
+    SetMeasured(u8, u8) = 0x78,
+    ReadMeasured = 0x79,
+    /// Set the measurement gate time in ms, little endian
+    SetMeasureGate(u16) = 0x7A,
+    /// Returns a 32-bit measurement of a (group, pin), little endian
+    ReadMeasurement(u8, u8, Quantity) = 0x7B,
+    /// Decode a (group, A pin, B pin, index pin, flags) as a quadrature encoder. Use
+    /// [`crate::device::NO_INDEX_PIN`] for no index pin, the flags are
+    /// [`crate::device::ENCODER_TRIGGER_INT_OUT`]
+    SetEncoder(u8, u8, u8, u8, u8) = 0x80,
+    /// Stop decoding the encoder in a group
+    ClearEncoder(u8) = 0x81,
+    /// Returns the signed 32-bit position of the encoder in a group, little endian
+    ReadEncoder(u8) = 0x82,
+    ResetEncoder(u8) = 0x83,
+    /// Drive a (group, pin) to a level, 0 for low, for a duration in ms, little endian. It's
+    /// then restored to its previous level, see [`crate::timed`]
+    Pulse(u8, u8, u8, u16) = 0x90,
+    /// [`Self::Pulse`] with a duration in µs
+    PulseMicros(u8, u8, u8, u16) = 0x91,
+    /// Drive a (group, pin) to a level, 0 for low, after a delay in ms, little endian. Returns
+    /// the slot the change is in, so it's sent on its own rather than in a batch. There are
+    /// [`crate::timed::MAX_CHANGES`] slots, pulses don't use them
+    ScheduleOutput(u8, u8, u8, u16) = 0x92,
+    /// Remove the change in a slot without applying it
+    CancelScheduled(u8) = 0x93,
+    /// Returns the mask of slots holding a change, followed by the
+    /// [`crate::timed::take_status`] flags
+    ReadSchedule = 0x94,
+    /// Returns the change in a slot, see [`crate::device::Device::scheduled`]
+    ReadScheduled(u8) = 0x95,
+    /// Stop a (pattern) and clear its steps, it will play the masked output pins of each group
+    /// in the selected bank (loops) times, 0 repeats forever, see [`crate::pattern`]
+    DefinePattern(u8, u8, u8, u8) = 0xA0,
+    /// Add a step to the end of a (pattern) holding the pins of each group at a level for a
+    /// duration in ms, little endian
+    AddPatternStep(u8, u8, u8, u16) = 0xA1,
+    /// Start a pattern from its first step, or resume it if it's paused
+    StartPattern(u8) = 0xA2,
+    PausePattern(u8) = 0xA3,
+    /// Stop a pattern, leaving its pins as they are
+    StopPattern(u8) = 0xA4,
+    /// Returns the state, current step, number of steps and completed loops of a pattern
+    ReadPattern(u8) = 0xA5,
+    /// Set the rule in a (slot) driving (output group, output pin) from the masked pins of
+    /// (input group), with [`crate::rules`] flags. Rules are saved with the config
+    SetRule(u8, u8, u8, u8, u8, u8) = 0xB0,
+    ClearRule(u8) = 0xB1,
+    /// Returns the input group, input mask, output packed as `group << 3 | pin` and flags of
+    /// the rule in a slot, [`crate::device::RULE_LATCHED`] is set if it has latched
+    ReadRule(u8) = 0xB2,
+    /// Release a latched rule
+    ResetLatch(u8) = 0xB3,
+    /// Drive (group, pin) as a servo if the last byte is non-zero, or return it to its group.
+    /// The pin must be an output and can't share its PWM slice with a measured pin
+    SetServo(u8, u8, u8) = 0xC0,
+    /// Limit the pulse width of a servo to (min µs, max µs), which the angles are spread across
+    SetServoLimits(u8, u8, u16, u16) = 0xC1,
+    /// Fastest a servo moves in µs per second, 0 moves it immediately
+    SetServoSlew(u8, u8, u16) = 0xC2,
+    WriteServoMicros(u8, u8, u16) = 0xC3,
+    /// Move a servo to an angle from 0 to 180 degrees
+    WriteServoAngle(u8, u8, u8) = 0xC4,
+    /// Returns the current and target pulse widths of a servo in µs, little endian
+    ReadServo(u8, u8) = 0xC5,
+    /// Drive (stepper) from the (STEP pin, DIR pin) outputs of (group), with
+    /// [`crate::stepper`] flags
+    SetStepper(u8, u8, u8, u8, u8) = 0xD0,
+    ClearStepper(u8) = 0xD1,
+    /// Move a stepper (steps) at up to (steps/s) accelerating at (steps/s²), 0 doesn't ramp
+    MoveStepper(u8, i32, u16, u16) = 0xD2,
+    /// Decelerate a stepper to a stop
+    StopStepper(u8) = 0xD3,
+    SetStepperPosition(u8, i32) = 0xD4,
+    /// Returns the position of a stepper, little endian
+    ReadStepperPosition(u8) = 0xD5,
+    /// Returns the mask of the moving steppers and the mask of those that have completed a move
+    /// since the last read, clearing the latter and INT_OUT
+    ReadStepperStatus = 0xD6,
+    /// Set a pixel of the WS2812 strip to (r, g, b), it's shown once the pixels are latched
+    SetPixel(u8, u8, u8, u8) = 0xE0,
+    /// Set (count) pixels from (start) to (r, g, b)
+    FillPixels(u8, u8, u8, u8, u8) = 0xE1,
+    /// Scale every pixel by a brightness out of 255 as it's sent
+    SetPixelBrightness(u8) = 0xE2,
+    /// Number of pixels sent to the strip
+    SetPixelCount(u8) = 0xE3,
+    /// Send the pixels to the strip
+    LatchPixels = 0xE4,
+    /// Returns the (r, g, b) of a pixel, before brightness is applied
+    ReadPixel(u8) = 0xE5,
+    /// Set the UART to (baud) with a [`crate::uart::UartConfig`] format byte
+    SetUartConfig(u32, u8) = 0xF0,
+    /// Raise INT_OUT for the (enabled) [`crate::uart`] flags, at (receive threshold) and
+    /// (transmit threshold) bytes
+    SetUartInterrupts(u8, u8, u8) = 0xF1,
+    /// Transmit the (count) bytes that follow
+    WriteUart(u8) = 0xF2,
+    /// Returns the number of received bytes read, up to (max), followed by the bytes
+    ReadUart(u8) = 0xF3,
+    /// Returns the number of received bytes, the room left to transmit and the flags, clearing
+    /// the flags and INT_OUT
+    ReadUartStatus = 0xF4,
 }
 
 impl GpioCommand {
@@ -28,64 +213,230 @@ impl<'a> TryRead<'a, Endian> for GpioCommand {
     fn try_read(bytes: &'a [u8], ctx: Endian) -> byte::Result<(Self, usize)> {
         let mut offset = 0;
         let command_byte = bytes.read_with::<u8>(&mut offset, ctx)?;
+        let mut arg = || bytes.read_with::<u8>(&mut offset, ctx);
         let command = match command_byte {
-            cmd if cmd == Self::ReadIoModes.discriminant() => Ok(Self::ReadIoModes),
-            cmd_with_args
-                if cmd_with_args < Self::WriteOutputs1(0).discriminant()
-                    || cmd_with_args >= Self::SetPullDowns(0, 0).discriminant() =>
-            {
-                let gpio_group_1 = bytes.read_with::<u8>(&mut offset, ctx)?;
-                let gpio_group_2 = bytes.read_with::<u8>(&mut offset, ctx)?;
-                match cmd_with_args {
-                    cmd if cmd == Self::WriteAllOutputs(0, 0).discriminant() => {
-                        Ok(Self::WriteAllOutputs(gpio_group_1, gpio_group_2))
-                    }
-                    cmd if cmd == Self::SetIoModes(0, 0).discriminant() => {
-                        Ok(Self::SetIoModes(gpio_group_1, gpio_group_2))
-                    }
-                    cmd if cmd == Self::SetPullDowns(0, 0).discriminant() => {
-                        Ok(Self::SetPullDowns(gpio_group_1, gpio_group_2))
-                    }
-                    cmd if cmd == Self::SetPullUps(0, 0).discriminant() => {
-                        Ok(Self::SetPullUps(gpio_group_1, gpio_group_2))
-                    }
-                    cmd if cmd == Self::SetPullNone(0, 0).discriminant() => {
-                        Ok(Self::SetPullNone(gpio_group_1, gpio_group_2))
-                    }
-                    otherwise => {
-                        error!("Invalid command byte with 2 args: {:x}", otherwise);
-                        Err(byte::Error::BadInput {
-                            err: "Invalid command byte",
-                        })
-                    }
-                }
-            }
-            cmd_with_arg if cmd_with_arg < Self::ReadInputs1.discriminant() => {
-                let gpio_group = bytes.read_with::<u8>(&mut offset, ctx)?;
-                match cmd_with_arg {
-                    cmd if cmd == Self::WriteOutputs1(0).discriminant() => {
-                        Ok(Self::WriteOutputs1(gpio_group))
-                    }
-                    cmd if cmd == Self::WriteOutputs2(0).discriminant() => {
-                        Ok(Self::WriteOutputs2(gpio_group))
-                    }
-                    otherwise => {
-                        error!("Invalid command byte with 1 arg: {:x}", otherwise);
-                        Err(byte::Error::BadInput {
-                            err: "Invalid command byte",
-                        })
-                    }
-                }
-            }
-            cmd if cmd == Self::ReadInputs1.discriminant() => Ok(Self::ReadInputs1),
-            cmd if cmd == Self::ReadInputs2.discriminant() => Ok(Self::ReadInputs2),
+            cmd if cmd == Self::ReadIoModes.discriminant() => Self::ReadIoModes,
+            cmd if cmd == Self::WriteAllOutputs(0, 0).discriminant() => {
+                Self::WriteAllOutputs(arg()?, arg()?)
+            }
+            cmd if cmd == Self::SetIoModes(0, 0).discriminant() => Self::SetIoModes(arg()?, arg()?),
+            cmd if cmd == Self::SelectBank(0).discriminant() => Self::SelectBank(arg()?),
+            cmd if cmd == Self::ReadBank.discriminant() => Self::ReadBank,
+            cmd if cmd == Self::ReadBatchStatus.discriminant() => Self::ReadBatchStatus,
+            cmd if cmd == Self::WriteOutputs1(0).discriminant() => Self::WriteOutputs1(arg()?),
+            cmd if cmd == Self::WriteOutputs2(0).discriminant() => Self::WriteOutputs2(arg()?),
+            cmd if cmd == Self::ReadOutputLatch.discriminant() => Self::ReadOutputLatch,
+            cmd if cmd == Self::ReadSnapshot.discriminant() => Self::ReadSnapshot,
+            cmd if cmd == Self::ReadInputs1.discriminant() => Self::ReadInputs1,
+            cmd if cmd == Self::ReadInputs2.discriminant() => Self::ReadInputs2,
+            cmd if cmd == Self::SetPullDowns(0, 0).discriminant() => {
+                Self::SetPullDowns(arg()?, arg()?)
+            }
+            cmd if cmd == Self::SetPullUps(0, 0).discriminant() => Self::SetPullUps(arg()?, arg()?),
+            cmd if cmd == Self::SetPullNone(0, 0).discriminant() => {
+                Self::SetPullNone(arg()?, arg()?)
+            }
+            cmd if cmd == Self::SetPolarity(0, 0).discriminant() => {
+                Self::SetPolarity(arg()?, arg()?)
+            }
+            cmd if cmd == Self::ReadPolarity.discriminant() => Self::ReadPolarity,
+            cmd if cmd == Self::SetOpenDrain(0, 0).discriminant() => {
+                Self::SetOpenDrain(arg()?, arg()?)
+            }
+            cmd if cmd == Self::ReadOpenDrain.discriminant() => Self::ReadOpenDrain,
+            cmd if cmd == Self::SetDriveStrength(0, 0, DriveStrength::_2mA).discriminant() => {
+                let (gpio_group_0, gpio_group_1) = (arg()?, arg()?);
+                let strength = DriveStrength::from_u8(arg()?).ok_or(byte::Error::BadInput {
+                    err: "Invalid drive strength",
+                })?;
+                Self::SetDriveStrength(gpio_group_0, gpio_group_1, strength)
+            }
+            cmd if cmd == Self::SetSlewRates(0, 0).discriminant() => {
+                Self::SetSlewRates(arg()?, arg()?)
+            }
+            cmd if cmd == Self::SetSchmitt(0, 0).discriminant() => Self::SetSchmitt(arg()?, arg()?),
+            cmd if cmd == Self::ReadDriveStrength(0).discriminant() => {
+                Self::ReadDriveStrength(arg()?)
+            }
+            cmd if cmd == Self::ReadSlewRates.discriminant() => Self::ReadSlewRates,
+            cmd if cmd == Self::ReadSchmitt.discriminant() => Self::ReadSchmitt,
+            cmd if cmd == Self::SaveConfig.discriminant() => Self::SaveConfig,
+            cmd if cmd == Self::ClearConfig.discriminant() => Self::ClearConfig,
+            cmd if cmd == Self::StageIoModes(0, 0).discriminant() => {
+                Self::StageIoModes(arg()?, arg()?)
+            }
+            cmd if cmd == Self::StagePullUps(0, 0).discriminant() => {
+                Self::StagePullUps(arg()?, arg()?)
+            }
+            cmd if cmd == Self::StagePullDowns(0, 0).discriminant() => {
+                Self::StagePullDowns(arg()?, arg()?)
+            }
+            cmd if cmd == Self::StageOutputs(0, 0).discriminant() => {
+                Self::StageOutputs(arg()?, arg()?)
+            }
+            cmd if cmd == Self::StagePolarity(0, 0).discriminant() => {
+                Self::StagePolarity(arg()?, arg()?)
+            }
+            cmd if cmd == Self::CommitStaged.discriminant() => Self::CommitStaged,
+            cmd if cmd == Self::DiscardStaged.discriminant() => Self::DiscardStaged,
+            cmd if cmd == Self::ReadAnalog(0).discriminant() => Self::ReadAnalog(arg()?),
+            cmd if cmd == Self::SetAnalogChannels(0).discriminant() => {
+                Self::SetAnalogChannels(arg()?)
+            }
+            cmd if cmd == Self::ReadAnalogChannels.discriminant() => Self::ReadAnalogChannels,
+            cmd if cmd == Self::SetAnalogAveraging(0).discriminant() => {
+                Self::SetAnalogAveraging(arg()?)
+            }
+            cmd if cmd == Self::SetAnalogThreshold(0, 0, 0).discriminant() => {
+                let channel = arg()?;
+                let low = u16::from_le_bytes([arg()?, arg()?]);
+                let high = u16::from_le_bytes([arg()?, arg()?]);
+                Self::SetAnalogThreshold(channel, low, high)
+            }
+            cmd if cmd == Self::SetAnalogHysteresis(0, 0).discriminant() => {
+                let channel = arg()?;
+                Self::SetAnalogHysteresis(channel, u16::from_le_bytes([arg()?, arg()?]))
+            }
+            cmd if cmd == Self::ReadAnalogFlags.discriminant() => Self::ReadAnalogFlags,
+            cmd if cmd == Self::SetCountRising(0, 0).discriminant() => {
+                Self::SetCountRising(arg()?, arg()?)
+            }
+            cmd if cmd == Self::SetCountFalling(0, 0).discriminant() => {
+                Self::SetCountFalling(arg()?, arg()?)
+            }
+            cmd if cmd == Self::ReadCountRising.discriminant() => Self::ReadCountRising,
+            cmd if cmd == Self::ReadCountFalling.discriminant() => Self::ReadCountFalling,
+            cmd if cmd == Self::ReadCounter(0, 0).discriminant() => {
+                Self::ReadCounter(arg()?, arg()?)
+            }
+            cmd if cmd == Self::ClearCounts(0, 0).discriminant() => {
+                Self::ClearCounts(arg()?, arg()?)
+            }
+            cmd if cmd == Self::SetMeasured(0, 0).discriminant() => {
+                Self::SetMeasured(arg()?, arg()?)
+            }
+            cmd if cmd == Self::ReadMeasured.discriminant() => Self::ReadMeasured,
+            cmd if cmd == Self::SetMeasureGate(0).discriminant() => {
+                Self::SetMeasureGate(u16::from_le_bytes([arg()?, arg()?]))
+            }
+            cmd if cmd == Self::ReadMeasurement(0, 0, Quantity::Frequency).discriminant() => {
+                let (group, pin) = (arg()?, arg()?);
+                let quantity = Quantity::from_u8(arg()?).ok_or(byte::Error::BadInput {
+                    err: "Invalid quantity",
+                })?;
+                Self::ReadMeasurement(group, pin, quantity)
+            }
+            cmd if cmd == Self::SetEncoder(0, 0, 0, 0, 0).discriminant() => {
+                Self::SetEncoder(arg()?, arg()?, arg()?, arg()?, arg()?)
+            }
+            cmd if cmd == Self::ClearEncoder(0).discriminant() => Self::ClearEncoder(arg()?),
+            cmd if cmd == Self::ReadEncoder(0).discriminant() => Self::ReadEncoder(arg()?),
+            cmd if cmd == Self::ResetEncoder(0).discriminant() => Self::ResetEncoder(arg()?),
+            cmd if cmd == Self::Pulse(0, 0, 0, 0).discriminant() => {
+                let (group, pin, level) = (arg()?, arg()?, arg()?);
+                Self::Pulse(group, pin, level, u16::from_le_bytes([arg()?, arg()?]))
+            }
+            cmd if cmd == Self::PulseMicros(0, 0, 0, 0).discriminant() => {
+                let (group, pin, level) = (arg()?, arg()?, arg()?);
+                Self::PulseMicros(group, pin, level, u16::from_le_bytes([arg()?, arg()?]))
+            }
+            cmd if cmd == Self::ScheduleOutput(0, 0, 0, 0).discriminant() => {
+                let (group, pin, level) = (arg()?, arg()?, arg()?);
+                Self::ScheduleOutput(group, pin, level, u16::from_le_bytes([arg()?, arg()?]))
+            }
+            cmd if cmd == Self::CancelScheduled(0).discriminant() => Self::CancelScheduled(arg()?),
+            cmd if cmd == Self::ReadSchedule.discriminant() => Self::ReadSchedule,
+            cmd if cmd == Self::ReadScheduled(0).discriminant() => Self::ReadScheduled(arg()?),
+            cmd if cmd == Self::DefinePattern(0, 0, 0, 0).discriminant() => {
+                Self::DefinePattern(arg()?, arg()?, arg()?, arg()?)
+            }
+            cmd if cmd == Self::AddPatternStep(0, 0, 0, 0).discriminant() => {
+                let (pattern, gpio_group_0, gpio_group_1) = (arg()?, arg()?, arg()?);
+                let duration_ms = u16::from_le_bytes([arg()?, arg()?]);
+                Self::AddPatternStep(pattern, gpio_group_0, gpio_group_1, duration_ms)
+            }
+            cmd if cmd == Self::StartPattern(0).discriminant() => Self::StartPattern(arg()?),
+            cmd if cmd == Self::PausePattern(0).discriminant() => Self::PausePattern(arg()?),
+            cmd if cmd == Self::StopPattern(0).discriminant() => Self::StopPattern(arg()?),
+            cmd if cmd == Self::ReadPattern(0).discriminant() => Self::ReadPattern(arg()?),
+            cmd if cmd == Self::SetRule(0, 0, 0, 0, 0, 0).discriminant() => {
+                Self::SetRule(arg()?, arg()?, arg()?, arg()?, arg()?, arg()?)
+            }
+            cmd if cmd == Self::ClearRule(0).discriminant() => Self::ClearRule(arg()?),
+            cmd if cmd == Self::ReadRule(0).discriminant() => Self::ReadRule(arg()?),
+            cmd if cmd == Self::ResetLatch(0).discriminant() => Self::ResetLatch(arg()?),
+            cmd if cmd == Self::SetServo(0, 0, 0).discriminant() => {
+                Self::SetServo(arg()?, arg()?, arg()?)
+            }
+            cmd if cmd == Self::SetServoLimits(0, 0, 0, 0).discriminant() => Self::SetServoLimits(
+                arg()?,
+                arg()?,
+                u16::from_le_bytes([arg()?, arg()?]),
+                u16::from_le_bytes([arg()?, arg()?]),
+            ),
+            cmd if cmd == Self::SetServoSlew(0, 0, 0).discriminant() => {
+                Self::SetServoSlew(arg()?, arg()?, u16::from_le_bytes([arg()?, arg()?]))
+            }
+            cmd if cmd == Self::WriteServoMicros(0, 0, 0).discriminant() => {
+                Self::WriteServoMicros(arg()?, arg()?, u16::from_le_bytes([arg()?, arg()?]))
+            }
+            cmd if cmd == Self::WriteServoAngle(0, 0, 0).discriminant() => {
+                Self::WriteServoAngle(arg()?, arg()?, arg()?)
+            }
+            cmd if cmd == Self::ReadServo(0, 0).discriminant() => Self::ReadServo(arg()?, arg()?),
+            cmd if cmd == Self::SetStepper(0, 0, 0, 0, 0).discriminant() => {
+                Self::SetStepper(arg()?, arg()?, arg()?, arg()?, arg()?)
+            }
+            cmd if cmd == Self::ClearStepper(0).discriminant() => Self::ClearStepper(arg()?),
+            cmd if cmd == Self::MoveStepper(0, 0, 0, 0).discriminant() => Self::MoveStepper(
+                arg()?,
+                i32::from_le_bytes([arg()?, arg()?, arg()?, arg()?]),
+                u16::from_le_bytes([arg()?, arg()?]),
+                u16::from_le_bytes([arg()?, arg()?]),
+            ),
+            cmd if cmd == Self::StopStepper(0).discriminant() => Self::StopStepper(arg()?),
+            cmd if cmd == Self::SetStepperPosition(0, 0).discriminant() => {
+                Self::SetStepperPosition(
+                    arg()?,
+                    i32::from_le_bytes([arg()?, arg()?, arg()?, arg()?]),
+                )
+            }
+            cmd if cmd == Self::ReadStepperPosition(0).discriminant() => {
+                Self::ReadStepperPosition(arg()?)
+            }
+            cmd if cmd == Self::ReadStepperStatus.discriminant() => Self::ReadStepperStatus,
+            cmd if cmd == Self::SetPixel(0, 0, 0, 0).discriminant() => {
+                Self::SetPixel(arg()?, arg()?, arg()?, arg()?)
+            }
+            cmd if cmd == Self::FillPixels(0, 0, 0, 0, 0).discriminant() => {
+                Self::FillPixels(arg()?, arg()?, arg()?, arg()?, arg()?)
+            }
+            cmd if cmd == Self::SetPixelBrightness(0).discriminant() => {
+                Self::SetPixelBrightness(arg()?)
+            }
+            cmd if cmd == Self::SetPixelCount(0).discriminant() => Self::SetPixelCount(arg()?),
+            cmd if cmd == Self::LatchPixels.discriminant() => Self::LatchPixels,
+            cmd if cmd == Self::ReadPixel(0).discriminant() => Self::ReadPixel(arg()?),
+            cmd if cmd == Self::SetUartConfig(0, 0).discriminant() => {
+                Self::SetUartConfig(u32::from_le_bytes([arg()?, arg()?, arg()?, arg()?]), arg()?)
+            }
+            cmd if cmd == Self::SetUartInterrupts(0, 0, 0).discriminant() => {
+                Self::SetUartInterrupts(arg()?, arg()?, arg()?)
+            }
+            cmd if cmd == Self::WriteUart(0).discriminant() => {
+                let count = arg()?;
+                bytes.read_with::<&[u8]>(&mut offset, Bytes::Len(count as usize))?;
+                Self::WriteUart(count)
+            }
+            cmd if cmd == Self::ReadUart(0).discriminant() => Self::ReadUart(arg()?),
+            cmd if cmd == Self::ReadUartStatus.discriminant() => Self::ReadUartStatus,
             otherwise => {
                 error!("Invalid command byte: {:x}", otherwise);
-                Err(byte::Error::BadInput {
+                return Err(byte::Error::BadInput {
                     err: "Invalid command byte",
-                })
+                });
             }
-        }?;
+        };
 
         Ok((command, offset))
     }
@@ -96,6 +447,82 @@ impl GpioCommand {
         let (command, _) = Self::try_read(bytes, Endian::default()).map_err(Error::from)?;
         Ok(command)
     }
+
+    /// The commands concatenated in `bytes`, each with the bytes it was parsed from. Zeros
+    /// after a command are padding, as 0x00 isn't a command, so a write padded to a fixed length
+    /// is still a single command.
+    pub fn batch(bytes: &[u8]) -> Batch<'_> {
+        Batch { bytes, first: true }
+    }
+
+    /// Whether the command responds with data, so has to be sent as a write-read
+    pub fn responds(&self) -> bool {
+        matches!(
+            self,
+            Self::ReadIoModes
+                | Self::ReadBank
+                | Self::ReadBatchStatus
+                | Self::ReadOutputLatch
+                | Self::ReadSnapshot
+                | Self::ReadInputs1
+                | Self::ReadInputs2
+                | Self::ReadPolarity
+                | Self::ReadOpenDrain
+                | Self::ReadDriveStrength(..)
+                | Self::ReadSlewRates
+                | Self::ReadSchmitt
+                | Self::ReadCountRising
+                | Self::ReadCountFalling
+                | Self::ReadCounter(..)
+                | Self::ReadEncoder(..)
+                | Self::ScheduleOutput(..)
+                | Self::ReadSchedule
+                | Self::ReadScheduled(..)
+                | Self::ReadPattern(..)
+                | Self::ReadMeasured
+                | Self::ReadMeasurement(..)
+                | Self::ReadAnalog(..)
+                | Self::ReadAnalogChannels
+                | Self::ReadAnalogFlags
+                | Self::ReadServo(..)
+                | Self::ReadStepperPosition(..)
+                | Self::ReadStepperStatus
+                | Self::ReadPixel(..)
+                | Self::ReadUart(..)
+                | Self::ReadUartStatus
+                | Self::ReadRule(..)
+        )
+    }
+}
+
+/// Iterator over the commands in a write, see [`GpioCommand::batch`]. A command that fails to
+/// parse ends it, as the next one can't be found.
+pub struct Batch<'a> {
+    bytes: &'a [u8],
+    first: bool,
+}
+
+impl<'a> Iterator for Batch<'a> {
+    type Item = Result<(GpioCommand, &'a [u8]), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let padding = !self.first && self.bytes.iter().all(|&byte| byte == 0);
+        if self.bytes.is_empty() || padding {
+            return None;
+        }
+        self.first = false;
+        match GpioCommand::try_read(self.bytes, Endian::default()) {
+            Ok((command, len)) => {
+                let (command_bytes, rest) = self.bytes.split_at(len);
+                self.bytes = rest;
+                Some(Ok((command, command_bytes)))
+            }
+            Err(e) => {
+                self.bytes = &[];
+                Some(Err(e.into()))
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
@@ -110,7 +537,7 @@ impl From<byte::Error> for Error {
         match e {
             byte::Error::BadOffset { .. } => Error::BadOffset,
             byte::Error::BadInput { .. } => Error::BadInput,
-            byte::Error::Incomplete { .. } => Error::Incomplete,
+            byte::Error::Incomplete => Error::Incomplete,
         }
     }
 }
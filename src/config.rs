@@ -0,0 +1,196 @@
+//! Persistent device configuration, stored in the last sector of flash so that pin
+//! configuration survives a power cycle.
+
+use byte::ctx::Endian;
+use byte::{BytesExt, TryRead, TryWrite};
+use defmt::{info, warn, Format};
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use heapless::Vec;
+
+use crate::gpios::MAX_GROUPS;
+use crate::rules::{Rule, MAX_RULES};
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+const CONFIG_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+const MAGIC: u32 = 0x4750_4358;
+const VERSION: u8 = 3;
+/// Oldest version that can still be loaded, version 2 had no rules
+const MIN_VERSION: u8 = 2;
+const CONFIG_LEN: usize = 128;
+
+/// Snapshot of the configuration of a single [`crate::gpios::PinGroup`]
+#[derive(Debug, Clone, Copy, Default, Format, Eq, PartialEq)]
+pub struct GroupConfig {
+    pub pin_modes: u8,
+    pub pull_ups: u8,
+    pub pull_downs: u8,
+    pub polarity: u8,
+    pub open_drain: u8,
+    pub drive_strength: u16,
+    pub slew_fast: u8,
+    pub schmitt: u8,
+}
+
+impl<'a> TryRead<'a, Endian> for GroupConfig {
+    fn try_read(bytes: &'a [u8], ctx: Endian) -> byte::Result<(Self, usize)> {
+        let mut offset = 0;
+        let config = Self {
+            pin_modes: bytes.read_with(&mut offset, ctx)?,
+            pull_ups: bytes.read_with(&mut offset, ctx)?,
+            pull_downs: bytes.read_with(&mut offset, ctx)?,
+            polarity: bytes.read_with(&mut offset, ctx)?,
+            open_drain: bytes.read_with(&mut offset, ctx)?,
+            drive_strength: bytes.read_with(&mut offset, ctx)?,
+            slew_fast: bytes.read_with(&mut offset, ctx)?,
+            schmitt: bytes.read_with(&mut offset, ctx)?,
+        };
+        Ok((config, offset))
+    }
+}
+
+impl TryWrite<Endian> for GroupConfig {
+    fn try_write(self, bytes: &mut [u8], ctx: Endian) -> byte::Result<usize> {
+        let mut offset = 0;
+        bytes.write_with(&mut offset, self.pin_modes, ctx)?;
+        bytes.write_with(&mut offset, self.pull_ups, ctx)?;
+        bytes.write_with(&mut offset, self.pull_downs, ctx)?;
+        bytes.write_with(&mut offset, self.polarity, ctx)?;
+        bytes.write_with(&mut offset, self.open_drain, ctx)?;
+        bytes.write_with(&mut offset, self.drive_strength, ctx)?;
+        bytes.write_with(&mut offset, self.slew_fast, ctx)?;
+        bytes.write_with(&mut offset, self.schmitt, ctx)?;
+        Ok(offset)
+    }
+}
+
+#[derive(Debug, Clone, Default, Format, Eq, PartialEq)]
+pub struct Config {
+    pub groups: Vec<GroupConfig, MAX_GROUPS>,
+    /// Stored as a mask of the used slots followed by each rule
+    pub rules: [Option<Rule>; MAX_RULES],
+}
+
+impl<'a> TryRead<'a, Endian> for Config {
+    fn try_read(bytes: &'a [u8], ctx: Endian) -> byte::Result<(Self, usize)> {
+        let mut offset = 0;
+        if bytes.read_with::<u32>(&mut offset, ctx)? != MAGIC {
+            return Err(byte::Error::BadInput {
+                err: "No stored config",
+            });
+        }
+        let version = bytes.read_with::<u8>(&mut offset, ctx)?;
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(byte::Error::BadInput {
+                err: "Unsupported config version",
+            });
+        }
+        let count = bytes.read_with::<u8>(&mut offset, ctx)?;
+        let mut config = Self::default();
+        for _ in 0..count {
+            config
+                .groups
+                .push(bytes.read_with(&mut offset, ctx)?)
+                .map_err(|_| byte::Error::BadInput {
+                    err: "Too many groups",
+                })?;
+        }
+        if version >= 3 {
+            let used = bytes.read_with::<u8>(&mut offset, ctx)?;
+            for (slot, rule) in config.rules.iter_mut().enumerate() {
+                if used & 1 << slot != 0 {
+                    *rule = Some(bytes.read_with(&mut offset, ctx)?);
+                }
+            }
+        }
+        Ok((config, offset))
+    }
+}
+
+impl TryWrite<Endian> for &Config {
+    fn try_write(self, bytes: &mut [u8], ctx: Endian) -> byte::Result<usize> {
+        let mut offset = 0;
+        bytes.write_with(&mut offset, MAGIC, ctx)?;
+        bytes.write_with(&mut offset, VERSION, ctx)?;
+        bytes.write_with(&mut offset, self.groups.len() as u8, ctx)?;
+        for group in self.groups.iter() {
+            bytes.write_with(&mut offset, *group, ctx)?;
+        }
+        let used = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.is_some())
+            .fold(0u8, |used, (slot, _)| used | 1 << slot);
+        bytes.write_with(&mut offset, used, ctx)?;
+        for rule in self.rules.iter().flatten() {
+            bytes.write_with(&mut offset, *rule, ctx)?;
+        }
+        Ok(offset)
+    }
+}
+
+/// Reads and writes the [`Config`] in flash.
+///
+/// Erasing and writing block for tens of ms, and the code runs from the flash, so nothing else
+/// runs until they're done, not even the high priority tasks or interrupts. The device refuses
+/// to touch the flash while anything depends on their timing, see [`Error::Busy`].
+pub struct Storage {
+    flash: Flash<'static, FLASH, Blocking, FLASH_SIZE>,
+}
+
+impl Storage {
+    pub fn new(flash: FLASH) -> Self {
+        Self {
+            flash: Flash::new_blocking(flash),
+        }
+    }
+
+    /// Returns the stored config, or `None` if nothing valid has been saved
+    pub fn load(&mut self) -> Option<Config> {
+        let mut buf = [0u8; CONFIG_LEN];
+        if let Err(e) = self.flash.blocking_read(CONFIG_OFFSET, &mut buf) {
+            warn!("[CONFIG] READ_ERROR: {:?}", e);
+            return None;
+        }
+        match buf.read_with::<Config>(&mut 0, Endian::default()) {
+            Ok(config) => Some(config),
+            Err(_) => {
+                info!("[CONFIG] NO STORED CONFIG");
+                None
+            }
+        }
+    }
+
+    pub fn save(&mut self, config: &Config) -> Result<(), Error> {
+        let mut buf = [0xFFu8; CONFIG_LEN];
+        buf.write_with(&mut 0, config, Endian::default())
+            .map_err(|_| Error::Serialise)?;
+        self.clear()?;
+        self.flash.blocking_write(CONFIG_OFFSET, &buf)?;
+        Ok(())
+    }
+
+    /// Erase the stored config so that the defaults are used on the next boot
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.flash
+            .blocking_erase(CONFIG_OFFSET, CONFIG_OFFSET + ERASE_SIZE as u32)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub enum Error {
+    Flash(embassy_rp::flash::Error),
+    Serialise,
+    NoStorage,
+    /// Timed changes are pending, or a pattern or stepper is running, and would stall while the
+    /// flash is erased or written
+    Busy,
+}
+
+impl From<embassy_rp::flash::Error> for Error {
+    fn from(e: embassy_rp::flash::Error) -> Self {
+        Self::Flash(e)
+    }
+}
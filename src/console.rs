@@ -0,0 +1,280 @@
+//! Text console over USB CDC-ACM for bench work, served by [`crate::tasks::console_task`].
+//!
+//! Each line is a command from [`HELP`], whose numbers can be decimal, `0x` hex or `0b` binary.
+//! The commands are turned into the same [`Request`]s the host makes over I2C, so they act on the
+//! shared [`crate::device::Device`] through [`transport::transact`].
+
+use core::fmt::Write as _;
+
+use defmt::{info, Format};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::driver::EndpointError;
+use heapless::{String, Vec};
+
+use crate::commands::GpioCommand;
+use crate::transport::{self, Request, Response, MAX_REQUEST_LEN};
+
+/// Size of the USB packets
+pub const PACKET_SIZE: usize = 64;
+/// Longest line the console accepts
+pub const MAX_LINE_LEN: usize = 128;
+const MAX_OUTPUT_LEN: usize = 1024;
+
+pub const HELP: &str = "\
+read                    inputs of every group\r
+write <g0> <g1>         outputs of the selected bank\r
+modes <g0> <g1>         set bits are outputs\r
+pull up|down|none <g0> <g1>\r
+config                  dump the pin configuration\r
+save                    save the configuration to flash\r
+clear                   erase the saved configuration\r
+cmd <byte>...           send a raw command\r
+query <byte>...         send a raw command and print its response\r
+";
+
+/// Commands that read the configuration, with their names
+const CONFIG_READS: [(&str, GpioCommand); 6] = [
+    ("bank", GpioCommand::ReadBank),
+    ("modes", GpioCommand::ReadIoModes),
+    ("polarity", GpioCommand::ReadPolarity),
+    ("open drain", GpioCommand::ReadOpenDrain),
+    ("slew rates", GpioCommand::ReadSlewRates),
+    ("schmitt", GpioCommand::ReadSchmitt),
+];
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub enum Error {
+    UnknownCommand,
+    InvalidNumber,
+    MissingArgument,
+    TooManyArguments,
+}
+
+#[derive(Debug, Clone, Format, Eq, PartialEq)]
+pub enum Action {
+    Help,
+    /// Read and print every [`CONFIG_READS`]
+    Config,
+    /// Make a request, printing the response in binary for a read, or in hex
+    Send(Request),
+}
+
+fn parse_byte(word: &str) -> Result<u8, Error> {
+    let (digits, radix) = if let Some(hex) = word.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(binary) = word.strip_prefix("0b") {
+        (binary, 2)
+    } else {
+        (word, 10)
+    };
+    let mut value = None;
+    // `_` separates digits, e.g. 0b0000_1111
+    for c in digits.chars().filter(|&c| c != '_') {
+        let digit = c.to_digit(radix).ok_or(Error::InvalidNumber)? as u8;
+        value = value
+            .unwrap_or(0u8)
+            .checked_mul(radix as u8)
+            .and_then(|value| value.checked_add(digit));
+        if value.is_none() {
+            return Err(Error::InvalidNumber);
+        }
+    }
+    value.ok_or(Error::InvalidNumber)
+}
+
+fn parse_bytes<'a>(
+    words: impl Iterator<Item = &'a str>,
+) -> Result<Vec<u8, MAX_REQUEST_LEN>, Error> {
+    let mut bytes = Vec::new();
+    for word in words {
+        bytes
+            .push(parse_byte(word)?)
+            .map_err(|_| Error::TooManyArguments)?;
+    }
+    Ok(bytes)
+}
+
+/// A command taking exactly a byte per group of the selected bank
+fn banked<'a>(opcode: u8, mut words: impl Iterator<Item = &'a str>) -> Result<Request, Error> {
+    let mut bytes = [opcode, 0, 0];
+    for byte in bytes[1..].iter_mut() {
+        *byte = parse_byte(words.next().ok_or(Error::MissingArgument)?)?;
+    }
+    if words.next().is_some() {
+        return Err(Error::TooManyArguments);
+    }
+    Ok(Request::write(&bytes))
+}
+
+/// Parse a line of the console
+pub fn parse(line: &str) -> Result<Action, Error> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Err(Error::MissingArgument);
+    };
+    let no_arguments = |action: Action, mut words: core::str::SplitWhitespace| match words.next() {
+        Some(_) => Err(Error::TooManyArguments),
+        None => Ok(action),
+    };
+    match command {
+        "help" | "?" => no_arguments(Action::Help, words),
+        "read" => no_arguments(Action::Send(Request::Read), words),
+        "config" => no_arguments(Action::Config, words),
+        "save" => no_arguments(
+            Action::Send(Request::write(&[GpioCommand::SaveConfig.discriminant()])),
+            words,
+        ),
+        "clear" => no_arguments(
+            Action::Send(Request::write(&[GpioCommand::ClearConfig.discriminant()])),
+            words,
+        ),
+        "write" => {
+            banked(GpioCommand::WriteAllOutputs(0, 0).discriminant(), words).map(Action::Send)
+        }
+        "modes" => banked(GpioCommand::SetIoModes(0, 0).discriminant(), words).map(Action::Send),
+        "pull" => {
+            let opcode = match words.next().ok_or(Error::MissingArgument)? {
+                "up" => GpioCommand::SetPullUps(0, 0),
+                "down" => GpioCommand::SetPullDowns(0, 0),
+                "none" => GpioCommand::SetPullNone(0, 0),
+                _ => return Err(Error::UnknownCommand),
+            }
+            .discriminant();
+            banked(opcode, words).map(Action::Send)
+        }
+        "cmd" | "query" => {
+            let bytes = parse_bytes(words)?;
+            if bytes.is_empty() {
+                return Err(Error::MissingArgument);
+            }
+            Ok(Action::Send(match command {
+                "cmd" => Request::Write(bytes),
+                _ => Request::WriteRead(bytes),
+            }))
+        }
+        _ => Err(Error::UnknownCommand),
+    }
+}
+
+/// Print `response` as binary for a read, or as hex
+fn print_response<const L: usize>(out: &mut String<L>, binary: bool, response: &Response) {
+    for byte in response {
+        let _ = if binary {
+            write!(out, " {:#010b}", byte)
+        } else {
+            write!(out, " {:02x}", byte)
+        };
+    }
+    let _ = write!(out, "\r\n");
+}
+
+/// Run a line, writing what it prints to `out`
+pub async fn execute<const L: usize>(line: &str, out: &mut String<L>) {
+    let action = match parse(line) {
+        Ok(action) => action,
+        Err(e) => {
+            let _ = write!(out, "error: {:?}, try help\r\n", e);
+            return;
+        }
+    };
+    info!("[CONSOLE] {:?}", action);
+    match action {
+        Action::Help => {
+            let _ = out.push_str(HELP);
+        }
+        Action::Config => {
+            for (name, command) in CONFIG_READS {
+                let request = Request::write_read(&[command.discriminant()]);
+                let _ = write!(out, "{}:", name);
+                match transport::transact(request).await {
+                    Some(response) => print_response(out, true, &response),
+                    None => {
+                        let _ = write!(out, " failed\r\n");
+                    }
+                }
+            }
+        }
+        Action::Send(request) => {
+            let binary = request == Request::Read;
+            let responds = !matches!(request, Request::Write(_));
+            match transport::transact(request).await {
+                Some(response) => {
+                    let _ = write!(out, "ok");
+                    print_response(out, binary, &response);
+                }
+                None if responds => {
+                    let _ = write!(out, "failed\r\n");
+                }
+                // Writes don't report whether they succeeded
+                None => {
+                    let _ = write!(out, "sent\r\n");
+                }
+            }
+        }
+    }
+}
+
+async fn write_all(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB>>,
+    bytes: &[u8],
+) -> Result<(), EndpointError> {
+    for packet in bytes.chunks(PACKET_SIZE) {
+        class.write_packet(packet).await?;
+    }
+    // A full last packet needs a zero length one to end the transfer
+    if bytes.len().is_multiple_of(PACKET_SIZE) {
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
+
+/// Echo and collect lines from the host, executing each one
+async fn serve(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB>>,
+) -> Result<(), EndpointError> {
+    let mut packet = [0u8; PACKET_SIZE];
+    let mut line: String<MAX_LINE_LEN> = String::new();
+    let mut out: String<MAX_OUTPUT_LEN> = String::new();
+    write_all(class, b"> ").await?;
+    loop {
+        let len = class.read_packet(&mut packet).await?;
+        for &byte in &packet[..len] {
+            out.clear();
+            match byte {
+                b'\r' | b'\n' => {
+                    let _ = out.push_str("\r\n");
+                    if !line.trim().is_empty() {
+                        execute(&line, &mut out).await;
+                    }
+                    let _ = out.push_str("> ");
+                    line.clear();
+                }
+                // Backspace or delete
+                0x08 | 0x7F if line.pop().is_some() => {
+                    let _ = out.push_str("\x08 \x08");
+                }
+                byte if (byte.is_ascii_graphic() || byte == b' ')
+                    && line.push(byte as char).is_ok() =>
+                {
+                    let _ = out.push(byte as char);
+                }
+                _ => {}
+            }
+            if !out.is_empty() {
+                write_all(class, out.as_bytes()).await?;
+            }
+        }
+    }
+}
+
+/// Serve the console whenever a terminal is connected
+pub(crate) async fn run(mut class: CdcAcmClass<'static, Driver<'static, USB>>) -> ! {
+    loop {
+        class.wait_connection().await;
+        info!("[CONSOLE] CONNECTED");
+        let _ = serve(&mut class).await;
+        info!("[CONSOLE] DISCONNECTED");
+    }
+}
@@ -1,129 +1,1286 @@
+use crate::analog;
 use crate::commands::GpioCommand;
-use crate::gpios::{PinGroup0, PinGroup1};
-use crate::SET_INT_OUT;
-use defmt::{info, Format};
-use embassy_rp::gpio::Pull;
+use crate::config::{Config, Storage};
+use crate::edges;
+use crate::gpios::staging::StagedGroup;
+use crate::gpios::{encoder, DriveStrength, Encoder, PinGroup, PinMask, GROUP_SIZE};
+use crate::measure::{self, Quantity};
+use crate::pattern;
+use crate::rules::{self, Rule, MAX_RULES};
+use crate::servo;
+use crate::stepper::{self, Stepper};
+use crate::timed::{self, OutputPin};
+use crate::uart::{self, UartConfig};
+use crate::ws2812::{self, Rgb};
+use crate::{DEFAULT_PIN_MODES, SET_INT_OUT};
+use defmt::{info, warn, Format};
+use embassy_rp::gpio::{AnyPin, Pull};
+use embassy_time::{Duration, Instant};
 // use embassy_futures::yield_now;
 
-pub struct Device {
-    pub gpio_group_0: PinGroup0,
-    pub gpio_group_1: PinGroup1,
+/// Number of groups addressed by the commands that take one byte per group
+pub const BANK_SIZE: usize = 2;
+/// [`GpioCommand::SetEncoder`] index pin for an encoder without one
+pub const NO_INDEX_PIN: u8 = 0xFF;
+/// [`GpioCommand::SetEncoder`] flag to assert INT_OUT when the position changes
+pub const ENCODER_TRIGGER_INT_OUT: u8 = 0b0000_0001;
+/// [`Device::scheduled`] flag for a change that drives the pin high
+pub const SCHEDULED_HIGH: u8 = 0b0000_0001;
+/// [`GpioCommand::ReadRule`] flag for a latching rule that has been activated
+pub const RULE_LATCHED: u8 = 0b1000_0000;
+/// [`Device::snapshot`] status bit set when a latching rule has activated
+pub const STATUS_RULE_LATCHED: u8 = 0b0000_0100;
+/// [`Device::snapshot`] status bit set while a stepper is moving
+pub const STATUS_STEPPER_BUSY: u8 = 0b0000_1000;
+/// [`GpioCommand::ReadBatchStatus`] index when every command of the last batch was applied
+pub const BATCH_OK: u8 = 0xFF;
+/// Length of a [`GpioCommand::ReadSnapshot`] response
+pub const SNAPSHOT_LEN: usize = 6 * BANK_SIZE + 1;
+/// Length of the longest response to a write-read command, a full [`GpioCommand::ReadUart`]
+pub const MAX_RESPONSE_LEN: usize = 1 + uart::MAX_READ;
+
+/// An expander made up of `N` [`PinGroup`]s.
+///
+/// Commands that take a byte per group address the pair of groups in the currently selected
+/// bank, so the same command set works for any number of groups. Bank 0 (groups 0 and 1) is
+/// selected by default.
+pub struct Device<const N: usize = 2> {
+    groups: [PinGroup; N],
+    bank: usize,
+    storage: Option<Storage>,
+    /// Settings waiting for [`GpioCommand::CommitStaged`]
+    staged: [StagedGroup; N],
+    /// The [`GpioCommand::ReadBatchStatus`] response for the last batch
+    batch_status: [u8; 2],
 }
 
-impl Device {
-    pub fn new(gpio_group_0: PinGroup0, gpio_group_1: PinGroup1) -> Self {
+impl<const N: usize> Device<N> {
+    pub fn new(groups: [PinGroup; N]) -> Self {
         Self {
-            gpio_group_0,
-            gpio_group_1,
+            groups,
+            bank: 0,
+            storage: None,
+            staged: [StagedGroup::default(); N],
+            batch_status: [BATCH_OK, 0],
+        }
+    }
+
+    /// Split `pins` into groups of 8, in order. If there aren't enough pins to fill every group
+    /// then the last groups will be partial or empty. Every group triggers INT_OUT.
+    pub fn from_pins(pins: impl IntoIterator<Item = AnyPin>) -> Self {
+        let mut pins = pins.into_iter();
+        Self::new(core::array::from_fn(|_| {
+            PinGroup::new(pins.by_ref().take(GROUP_SIZE), true)
+        }))
+    }
+
+    /// Use `storage` to persist the pin configuration
+    pub fn attach_storage(&mut self, storage: Storage) {
+        self.storage = Some(storage);
+    }
+
+    pub fn group(&self, group: usize) -> Option<&PinGroup> {
+        self.groups.get(group)
+    }
+
+    pub fn group_mut(&mut self, group: usize) -> Option<&mut PinGroup> {
+        self.groups.get_mut(group)
+    }
+
+    /// Select which pair of groups the per-group commands address
+    pub fn select_bank(&mut self, bank: u8) -> Result<(), Error> {
+        if bank as usize * BANK_SIZE >= N {
+            return Err(Error::InvalidBank(bank));
+        }
+        self.bank = bank as usize;
+        Ok(())
+    }
+
+    pub fn get_bank(&self) -> u8 {
+        self.bank as u8
+    }
+
+    fn banked_group(&self, index: usize) -> Option<&PinGroup> {
+        self.groups.get(self.bank * BANK_SIZE + index)
+    }
+
+    fn banked_group_mut(&mut self, index: usize) -> Option<&mut PinGroup> {
+        self.groups.get_mut(self.bank * BANK_SIZE + index)
+    }
+
+    /// Apply each byte to its group in the selected bank, missing groups are skipped
+    fn set_banked(&mut self, bytes: &[u8; BANK_SIZE], mut f: impl FnMut(&mut PinGroup, u8)) {
+        for (index, byte) in bytes.iter().enumerate() {
+            if let Some(group) = self.banked_group_mut(index) {
+                f(group, *byte);
+            }
+        }
+    }
+
+    /// Read a byte from each group in the selected bank, missing groups read as 0
+    fn get_banked(&self, out: &mut [u8; BANK_SIZE], f: impl Fn(&PinGroup) -> u8) {
+        for (index, byte) in out.iter_mut().enumerate() {
+            *byte = self.banked_group(index).map_or(0, &f);
+        }
+    }
+}
+
+/// Configuration persistence
+impl<const N: usize> Device<N> {
+    pub fn config(&self) -> Config {
+        Config {
+            groups: self.groups.iter().map(PinGroup::config).collect(),
+            rules: rules::all(),
+        }
+    }
+
+    pub fn apply_config(&mut self, config: &Config) {
+        for (group, group_config) in self.groups.iter_mut().zip(config.groups.iter()) {
+            group.apply_config(group_config);
+        }
+        // A config from flash might be corrupt, or from a board with more groups
+        for (slot, rule) in config.rules.iter().enumerate() {
+            let rule = rule.filter(|rule| match self.check_rule(rule) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("[CONFIG] INVALID_RULE: {:?} {:?}", rule, e);
+                    false
+                }
+            });
+            rules::set(slot, rule.map(|rule| self.wire_rule(&rule)));
+        }
+        self.apply_rules();
+    }
+
+    /// Apply the config stored in flash, falling back to [`DEFAULT_PIN_MODES`] if there isn't one
+    pub fn load_config(&mut self) {
+        match self.storage.as_mut().and_then(Storage::load) {
+            Some(config) => {
+                info!("[CONFIG] LOADED: {:?}", config);
+                self.apply_config(&config);
+            }
+            None => {
+                for (group, modes) in self.groups.iter_mut().zip(DEFAULT_PIN_MODES) {
+                    group.set_pin_modes(modes);
+                }
+            }
+        }
+    }
+
+    pub fn save_config(&mut self) -> Result<(), crate::config::Error> {
+        self.check_storage()?;
+        let config = self.config();
+        let storage = self
+            .storage
+            .as_mut()
+            .ok_or(crate::config::Error::NoStorage)?;
+        storage.save(&config)
+    }
+
+    pub fn clear_config(&mut self) -> Result<(), crate::config::Error> {
+        self.check_storage()?;
+        let storage = self
+            .storage
+            .as_mut()
+            .ok_or(crate::config::Error::NoStorage)?;
+        storage.clear()
+    }
+
+    /// Check that the flash can be written without stalling anything that's timed, see
+    /// [`Storage`]
+    fn check_storage(&self) -> Result<(), crate::config::Error> {
+        if timed::pending() != 0 || pattern::playing() != 0 || stepper::busy() != 0 {
+            return Err(crate::config::Error::Busy);
+        }
+        match self.storage {
+            Some(_) => Ok(()),
+            None => Err(crate::config::Error::NoStorage),
         }
     }
 }
 
 /// Pin related methods
-impl Device {
-    pub fn read(&self, out: &mut [u8; 2]) {
-        out[0] = self.gpio_group_0.read_pins();
-        out[1] = self.gpio_group_1.read_pins();
+impl<const N: usize> Device<N> {
+    /// The state of the selected bank in one go: for each group its modes, then its pull ups,
+    /// pull downs, output latches, inputs and interrupt flags, followed by a status byte holding
+    /// the bank, [`STATUS_RULE_LATCHED`] and [`STATUS_STEPPER_BUSY`].
+    ///
+    /// Reading the inputs clears the interrupt flags, as a plain read does, see
+    /// [`Self::update_int_out`].
+    pub fn snapshot(&mut self) -> [u8; SNAPSHOT_LEN] {
+        let mut snapshot = [0u8; SNAPSHOT_LEN];
+        let fields: [fn(&mut PinGroup) -> u8; 6] = [
+            |group| group.get_pin_modes(),
+            |group| group.get_pin_pulls().0,
+            |group| group.get_pin_pulls().1,
+            |group| group.read_output_latches(),
+            |group| group.read_pins(),
+            PinGroup::take_int_flags,
+        ];
+        for (chunk, field) in snapshot.chunks_exact_mut(BANK_SIZE).zip(fields) {
+            for (index, byte) in chunk.iter_mut().enumerate() {
+                *byte = self.banked_group_mut(index).map_or(0, field);
+            }
+        }
+        let mut status = self.get_bank();
+        if rules::latched() != 0 {
+            status |= STATUS_RULE_LATCHED;
+        }
+        if stepper::busy() != 0 {
+            status |= STATUS_STEPPER_BUSY;
+        }
+        snapshot[SNAPSHOT_LEN - 1] = status;
+        self.update_int_out();
+        snapshot
+    }
+
+    /// Assert INT_OUT while any of its sources has something the host hasn't read: the groups'
+    /// interrupt flags, tripped analog thresholds, completed stepper moves or the UART. Reading
+    /// one source only releases INT_OUT if the others are clear.
+    pub fn update_int_out(&self) {
+        let pending = self.groups.iter().any(|group| group.get_int_flags() != 0)
+            || analog::threshold_flags() != 0
+            || stepper::interrupt_pending()
+            || uart::interrupt_pending();
+        SET_INT_OUT.signal(pending);
+    }
+
+    pub fn read(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::read_pins);
+    }
+
+    /// Read every group, regardless of the selected bank
+    pub fn read_all(&self, out: &mut [u8; N]) {
+        for (byte, group) in out.iter_mut().zip(self.groups.iter()) {
+            *byte = group.read_pins();
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8; BANK_SIZE]) {
+        self.set_banked(bytes, PinGroup::write_pins);
+    }
+
+    /// Write to a single group in the selected bank
+    pub fn write_group(&mut self, index: usize, byte: u8) {
+        if let Some(group) = self.banked_group_mut(index) {
+            group.write_pins(byte);
+        }
+    }
+
+    pub fn set_pin_modes(&mut self, bytes: &[u8; BANK_SIZE]) {
+        self.set_banked(bytes, PinGroup::set_pin_modes);
+    }
+
+    pub fn get_pin_modes(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::get_pin_modes);
+    }
+
+    pub fn get_output_latch(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::read_output_latches);
+    }
+
+    pub fn set_pin_pulls(&mut self, bytes: &[u8; BANK_SIZE], pull: Pull) {
+        self.set_banked(bytes, |group, byte| group.set_pin_pulls(byte, pull));
+    }
+
+    pub fn set_pin_polarity(&mut self, bytes: &[u8; BANK_SIZE]) {
+        self.set_banked(bytes, PinGroup::set_pin_polarity);
+    }
+
+    pub fn get_pin_polarity(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::get_pin_polarity);
+    }
+
+    pub fn set_pin_open_drain(&mut self, bytes: &[u8; BANK_SIZE]) {
+        self.set_banked(bytes, PinGroup::set_pin_open_drain);
+    }
+
+    pub fn get_pin_open_drain(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::get_pin_open_drain);
     }
 
-    pub fn write(&mut self, bytes: &[u8; 2]) {
-        self.gpio_group_0.write_pins(bytes[0]);
-        self.gpio_group_1.write_pins(bytes[1]);
+    pub fn set_pin_drive_strengths(&mut self, bytes: &[u8; BANK_SIZE], strength: DriveStrength) {
+        self.set_banked(bytes, |group, byte| {
+            group.set_pin_drive_strengths(byte, strength)
+        });
     }
 
-    pub fn write1(&mut self, byte: u8) {
-        self.gpio_group_0.write_pins(byte);
+    pub fn set_pin_slew_rates(&mut self, bytes: &[u8; BANK_SIZE]) {
+        self.set_banked(bytes, PinGroup::set_pin_slew_rates);
     }
 
-    pub fn write2(&mut self, byte: u8) {
-        self.gpio_group_1.write_pins(byte);
+    pub fn get_pin_slew_rates(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::get_pin_slew_rates);
     }
 
-    pub fn set_pin_modes(&mut self, bytes: &[u8; 2]) {
-        self.gpio_group_0.set_pin_modes(bytes[0]);
-        self.gpio_group_1.set_pin_modes(bytes[1]);
+    pub fn set_pin_schmitt(&mut self, bytes: &[u8; BANK_SIZE]) {
+        self.set_banked(bytes, PinGroup::set_pin_schmitt);
     }
 
-    pub fn get_pin_modes(&self, out: &mut [u8; 2]) {
-        out[0] = self.gpio_group_0.get_pin_modes();
-        out[1] = self.gpio_group_1.get_pin_modes();
+    pub fn get_pin_schmitt(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::get_pin_schmitt);
     }
 
-    pub fn set_pin_pulls(&mut self, bytes: &[u8; 2], pull: Pull) {
-        self.gpio_group_0.set_pin_pulls(bytes[0], pull);
-        self.gpio_group_1.set_pin_pulls(bytes[1], pull);
+    pub fn set_count_rising(&mut self, bytes: &[u8; BANK_SIZE]) {
+        self.set_banked(bytes, PinGroup::set_count_rising);
+    }
+
+    pub fn get_count_rising(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::get_count_rising);
+    }
+
+    pub fn set_count_falling(&mut self, bytes: &[u8; BANK_SIZE]) {
+        self.set_banked(bytes, PinGroup::set_count_falling);
+    }
+
+    pub fn get_count_falling(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::get_count_falling);
+    }
+
+    pub fn clear_counts(&mut self, bytes: &[u8; BANK_SIZE]) {
+        self.set_banked(bytes, PinGroup::clear_counts);
+    }
+
+    /// Check that the masked pins are inputs that can all be measured at once
+    fn check_measured(&self, bytes: &[u8; BANK_SIZE]) -> Result<(), Error> {
+        let mut released = [0; BANK_SIZE];
+        for (index, byte) in bytes.iter().enumerate() {
+            if let Some(group) = self.banked_group(index) {
+                released[index] = group.get_pin_measured() & !byte;
+            }
+        }
+        measure::check(self.gpio_mask(bytes), self.gpio_mask(&released))
+            .map_err(Error::CantMeasure)?;
+        for (index, byte) in bytes.iter().enumerate() {
+            if let Some(group) = self.banked_group(index) {
+                let outputs = byte & group.get_pin_modes();
+                if outputs != 0 {
+                    return Err(Error::NotAnInput(outputs.trailing_zeros() as u8));
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub async fn wait_for_any_edge(&mut self) {
-        self.gpio_group_0.wait_for_any_edge().await;
+    /// Measure the masked pins, see [`crate::measure`]. Nothing changes unless every pin can be
+    /// measured.
+    pub fn set_pin_measured(&mut self, bytes: &[u8; BANK_SIZE]) -> Result<(), Error> {
+        self.check_measured(bytes)?;
+        // Every group releases its slices before any are claimed again
+        self.set_banked(bytes, PinGroup::release_measured);
+        for (index, byte) in bytes.iter().enumerate() {
+            if let Some(group) = self.banked_group_mut(index) {
+                group.set_pin_measured(*byte).map_err(Error::CantMeasure)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_pin_measured(&self, out: &mut [u8; BANK_SIZE]) {
+        self.get_banked(out, PinGroup::get_pin_measured);
+    }
+
+    /// Latest measurement of a pin, it must be being measured
+    pub fn measurement(&self, group: u8, pin: u8, quantity: Quantity) -> Result<u32, Error> {
+        let gpio = self.pin_gpio(group, pin)?;
+        let measurement = measure::measurement(gpio).ok_or(Error::NotMeasured(pin))?;
+        Ok(measurement.get(quantity))
+    }
+
+    fn pin_gpio(&self, group: u8, pin: u8) -> Result<u8, Error> {
+        self.group(group as usize)
+            .ok_or(Error::InvalidGroup(group))?
+            .gpio(pin)
+            .ok_or(Error::InvalidPin(pin))
+    }
+
+    /// Drive an output pin as a servo, or return it to its group, see [`crate::servo`]
+    pub fn set_servo(&mut self, group: u8, pin: u8, enable: bool) -> Result<(), Error> {
+        if enable {
+            let gpio = self.output_pin(group, pin)?.gpio;
+            servo::enable(gpio)?;
+        } else {
+            servo::disable(self.pin_gpio(group, pin)?);
+        }
+        Ok(())
+    }
+
+    /// The servo driven by `pin` of `group`
+    pub fn servo(&self, group: u8, pin: u8) -> Result<servo::Servo, Error> {
+        let gpio = self.pin_gpio(group, pin)?;
+        Ok(servo::get(gpio).ok_or(servo::Error::NotAServo(gpio))?)
+    }
+
+    /// Drive a stepper from the STEP and DIR output pins of `group`, see [`crate::stepper`]
+    pub fn set_stepper(
+        &mut self,
+        stepper: u8,
+        group: u8,
+        step_pin: u8,
+        dir_pin: u8,
+        flags: u8,
+    ) -> Result<(), Error> {
+        let step = self.output_pin(group, step_pin)?;
+        let dir = self.output_pin(group, dir_pin)?;
+        if step_pin == dir_pin {
+            return Err(Error::InvalidPin(dir_pin));
+        }
+        stepper::configure(stepper, Some(Stepper::new(step, dir, flags)))?;
+        Ok(())
+    }
+
+    /// The output `pin` of `group`, for driving it from [`crate::timed`]
+    pub fn output_pin(&self, group: u8, pin: u8) -> Result<OutputPin, Error> {
+        let pin_group = self
+            .group(group as usize)
+            .ok_or(Error::InvalidGroup(group))?;
+        let gpio = pin_group.gpio(pin).ok_or(Error::InvalidPin(pin))?;
+        let pin_mask = &PinMask::ARR[pin as usize];
+        if !pin_group.is_pin_output(pin_mask) {
+            return Err(Error::NotAnOutput(pin));
+        }
+        Ok(OutputPin { gpio })
+    }
+
+    /// Drive an output pin to `high` for `duration`, see [`timed::pulse`]
+    pub fn pulse(
+        &mut self,
+        group: u8,
+        pin: u8,
+        high: bool,
+        duration: Duration,
+    ) -> Result<(), Error> {
+        timed::pulse(self.output_pin(group, pin)?, high, duration)?;
+        Ok(())
+    }
+
+    /// Drive an output pin to `high` after `delay`, returning the slot of the change, see
+    /// [`timed::schedule`]
+    pub fn schedule_output(
+        &mut self,
+        group: u8,
+        pin: u8,
+        high: bool,
+        delay: Duration,
+    ) -> Result<u8, Error> {
+        Ok(timed::schedule(self.output_pin(group, pin)?, high, delay)?)
+    }
+
+    /// The (group, pin) of `gpio`
+    fn locate(&self, gpio: u8) -> Option<(u8, u8)> {
+        self.groups
+            .iter()
+            .enumerate()
+            .find_map(|(group, pin_group)| {
+                let pin =
+                    (0..pin_group.len() as u8).find(|&pin| pin_group.gpio(pin) == Some(gpio))?;
+                Some((group as u8, pin))
+            })
+    }
+
+    /// Describe the change in `slot` as the (group, pin) packed as `group << 3 | pin` (0xFF if it
+    /// isn't one of this device's pins), the [`SCHEDULED_HIGH`] flag, and the ms until it
+    /// happens, little endian
+    pub fn scheduled(&self, slot: u8) -> Result<[u8; 4], Error> {
+        let change = timed::get(slot).ok_or(timed::Error::EmptySlot(slot))?;
+        let location = self
+            .locate(change.pin.gpio)
+            .map_or(0xFF, |(group, pin)| group << 3 | pin);
+        let flags = if change.high { SCHEDULED_HIGH } else { 0 };
+        let remaining = change
+            .at
+            .saturating_duration_since(Instant::now())
+            .as_millis();
+        let [low, high] = (remaining.min(u16::MAX as u64) as u16).to_le_bytes();
+        Ok([location, flags, low, high])
+    }
+
+    /// Stage a byte for each group in the selected bank into the `field` of its
+    /// [`StagedGroup`], missing groups are skipped
+    pub fn stage(
+        &mut self,
+        bytes: &[u8; BANK_SIZE],
+        field: impl Fn(&mut StagedGroup) -> &mut Option<u8>,
+    ) {
+        let first = self.bank * BANK_SIZE;
+        for (staged, byte) in self.staged.iter_mut().skip(first).zip(bytes) {
+            *field(staged) = Some(*byte);
+        }
+    }
+
+    pub fn staged(&self, group: usize) -> Option<&StagedGroup> {
+        self.staged.get(group)
+    }
+
+    /// Apply everything staged, see [`PinGroup::apply_staged`]
+    pub fn commit_staged(&mut self) {
+        for (group, staged) in self.groups.iter_mut().zip(self.staged.iter_mut()) {
+            if !staged.is_empty() {
+                group.apply_staged(staged);
+                *staged = StagedGroup::default();
+            }
+        }
+    }
+
+    pub fn discard_staged(&mut self) {
+        self.staged = [StagedGroup::default(); N];
+    }
+
+    /// Translate a byte per group in the selected bank to a mask of GPIO numbers
+    pub fn gpio_mask(&self, bytes: &[u8; BANK_SIZE]) -> u32 {
+        let mut mask = 0;
+        for (index, byte) in bytes.iter().enumerate() {
+            if let Some(group) = self.banked_group(index) {
+                for pin in (0..group.len() as u8).filter(|pin| byte & 1 << pin != 0) {
+                    mask |= group.gpio(pin).map_or(0, |gpio| 1 << gpio);
+                }
+            }
+        }
+        mask
+    }
+
+    /// Play the masked output pins in `pattern`, see [`pattern::define`]
+    pub fn define_pattern(
+        &mut self,
+        pattern: u8,
+        bytes: &[u8; BANK_SIZE],
+        loops: u8,
+    ) -> Result<(), Error> {
+        for (index, byte) in bytes.iter().enumerate() {
+            if let Some(group) = self.banked_group(index) {
+                let inputs = byte & !group.get_pin_modes();
+                if inputs != 0 {
+                    return Err(Error::NotAnOutput(inputs.trailing_zeros() as u8));
+                }
+            }
+        }
+        pattern::define(pattern, self.gpio_mask(bytes), loops)?;
+        Ok(())
+    }
+
+    /// Decode a pair of pins in `group` as a quadrature encoder, or stop decoding with `None`
+    pub fn set_encoder(&mut self, group: u8, encoder: Option<Encoder>) -> Result<(), Error> {
+        self.group_mut(group as usize)
+            .ok_or(Error::InvalidGroup(group))?
+            .set_encoder(encoder)?;
+        Ok(())
+    }
+
+    fn encoder_group_mut(&mut self, group: u8) -> Result<&mut PinGroup, Error> {
+        self.group_mut(group as usize)
+            .filter(|group| group.encoder().is_some())
+            .ok_or(Error::NoEncoder(group))
+    }
+
+    /// Check that the input group and the output pin of `rule` exist
+    fn check_rule(&self, rule: &Rule) -> Result<(), Error> {
+        self.group(rule.input_group as usize)
+            .ok_or(Error::InvalidGroup(rule.input_group))?;
+        self.group(rule.output_group as usize)
+            .ok_or(Error::InvalidGroup(rule.output_group))?
+            .gpio(rule.output_pin)
+            .ok_or(Error::InvalidPin(rule.output_pin))?;
+        Ok(())
+    }
+
+    /// `rule` with its pins translated to GPIO numbers for [`rules::set`]. The rule must have
+    /// passed [`Self::check_rule`].
+    fn wire_rule(&self, rule: &Rule) -> (Rule, u32, bool, u8) {
+        let input_group = &self.groups[rule.input_group as usize];
+        let inputs = input_group.gpio_mask(rule.inputs);
+        let complete = input_group.pin_mask(inputs) == rule.inputs;
+        let output = self.groups[rule.output_group as usize]
+            .gpio(rule.output_pin)
+            .unwrap_or_default();
+        (*rule, inputs, complete, output)
+    }
+
+    /// Put `rule` in `slot`, replacing any rule that's already there, see [`crate::rules`]
+    pub fn set_rule(&mut self, slot: u8, rule: Rule) -> Result<(), Error> {
+        self.check_rule(&rule)?;
+        if slot as usize >= MAX_RULES {
+            return Err(Error::InvalidRule(slot));
+        }
+        rules::set(slot as usize, Some(self.wire_rule(&rule)));
+        self.apply_rules();
+        Ok(())
+    }
+
+    pub fn clear_rule(&mut self, slot: u8) -> Result<(), Error> {
+        if slot as usize >= MAX_RULES {
+            return Err(Error::InvalidRule(slot));
+        }
+        rules::set(slot as usize, None);
+        Ok(())
+    }
+
+    pub fn rule(&self, slot: u8) -> Result<Rule, Error> {
+        rules::get(slot as usize).ok_or(Error::InvalidRule(slot))
+    }
+
+    pub fn is_latched(&self, slot: u8) -> bool {
+        rules::latched() & 1 << slot != 0
+    }
+
+    /// Release a latched rule, its output follows its inputs again
+    pub fn reset_latch(&mut self, slot: u8) -> Result<(), Error> {
+        self.rule(slot)?;
+        rules::reset_latch(slot as usize);
+        self.apply_rules();
+        Ok(())
+    }
+
+    /// Drive the output of every rule from its inputs. The GPIO interrupt does the same on
+    /// every edge, see [`edges::handle`].
+    pub fn apply_rules(&mut self) {
+        rules::apply();
+    }
+
+    /// Wait for an edge on any pin, taken by the GPIO interrupt, see [`edges`]. The edge
+    /// counters, encoders and rules are updated by the interrupt.
+    ///
+    /// Returns whether INT_OUT should be asserted, which is the case unless every pin that
+    /// changed is counting edges or part of an encoder, so counted pulses don't interrupt the
+    /// host. Encoders that trigger INT_OUT assert it whenever their position changes.
+    pub async fn wait_for_any_edge(&mut self) -> bool {
+        let changed = edges::wait_for_changes().await;
+        let moved = edges::take_moved();
         info!("INTERRUPT!");
-        // SET_INT_OUT.signal(true);
-        // yield_now().await;
+
+        let mut trigger = false;
+        for group in self.groups.iter_mut() {
+            let changed = group.pin_mask(changed);
+            if group.triggers_int_out() {
+                group.flag_interrupts(changed & !group.quiet_pins());
+            }
+            let moved = group.encoder_moved(moved);
+            trigger |= group.triggers_int_out() && changed & !group.quiet_pins() != 0;
+            if let Some(encoder) = group.encoder().filter(|encoder| encoder.trigger_int_out) {
+                if moved {
+                    group.flag_interrupts(changed & encoder.pins());
+                    trigger = true;
+                }
+            }
+        }
+        trigger
+    }
+}
+
+/// The bank and modes a batch of commands will have left, for checking each command's arguments
+/// before any are applied, see [`Device::handle_write_command`]
+struct Plan<const N: usize> {
+    bank: usize,
+    modes: [u8; N],
+    staged_modes: [Option<u8>; N],
+    /// Mask of the GPIOs that will be pulsing, see [`timed::pulsing`]
+    pulsing: u32,
+    /// Mask of the steppers that will be moving, see [`stepper::busy`]
+    busy_steppers: u8,
+}
+
+impl<const N: usize> Plan<N> {
+    /// Each byte with the index of its group in the selected bank, missing groups are skipped
+    fn banked<'a>(&self, bytes: &'a [u8; BANK_SIZE]) -> impl Iterator<Item = (usize, u8)> + 'a {
+        let first = self.bank * BANK_SIZE;
+        bytes
+            .iter()
+            .enumerate()
+            .map(move |(index, byte)| (first + index, *byte))
+            .filter(|(group, _)| *group < N)
     }
 }
 
 /// I2C functionality
-impl Device {
+impl<const N: usize> Device<N> {
+    /// Handle a write of one or more concatenated commands, applied in order, see
+    /// [`GpioCommand::batch`]. Every command is parsed and has its arguments checked before any
+    /// is applied, so a malformed command, one that responds, one with an invalid bank, group,
+    /// pin, slot or index, or one that needs a pulse slot or an idle stepper that won't be there
+    /// rejects the whole batch. A command that conflicts with a pattern or servo in use can
+    /// still fail when it's applied, which stops the batch there. Either way the index of the failing
+    /// command and the number of commands applied are kept for [`GpioCommand::ReadBatchStatus`].
     pub fn handle_write_command(&mut self, bytes: &[u8]) -> Result<(), Error> {
-        let command = GpioCommand::from_bytes(bytes)?;
+        self.batch_status = [0, 0];
+        if bytes.is_empty() {
+            return Err(crate::commands::Error::BadOffset.into());
+        }
+        let mut plan = Plan {
+            bank: self.bank,
+            modes: core::array::from_fn(|group| self.groups[group].get_pin_modes()),
+            staged_modes: core::array::from_fn(|group| self.staged[group].modes),
+            pulsing: timed::pulsing(),
+            busy_steppers: stepper::busy(),
+        };
+        for (index, command) in GpioCommand::batch(bytes).enumerate() {
+            self.batch_status[0] = index as u8;
+            let (command, _) = command?;
+            self.check_write_command(&mut plan, command)?;
+        }
+        let mut applied = 0;
+        let result = GpioCommand::batch(bytes).try_for_each(|command| {
+            let (command, bytes) = command?;
+            self.apply_write_command(command, bytes)?;
+            applied += 1;
+            Ok(())
+        });
+        self.batch_status = match result {
+            Ok(()) => [BATCH_OK, applied],
+            Err(_) => [applied, applied],
+        };
+        // Writes may have changed a rule's inputs, or overwritten its output
+        self.apply_rules();
+        result
+    }
+
+    /// Check the arguments of a command that doesn't respond against `plan`, the state the
+    /// commands before it in the batch will have left, then update `plan` with its changes
+    fn check_write_command(&self, plan: &mut Plan<N>, command: GpioCommand) -> Result<(), Error> {
+        let output_pin = |plan: &Plan<N>, group: u8, pin: u8| {
+            self.pin_gpio(group, pin)?;
+            match plan.modes[group as usize] & 1 << pin {
+                0 => Err(Error::NotAnOutput(pin)),
+                _ => Ok(()),
+            }
+        };
+        let check_index = |index: u8, max: usize, error: Error| match index as usize {
+            index if index < max => Ok(()),
+            _ => Err(error),
+        };
+        let check_stepper = |stepper: u8| {
+            let error = stepper::Error::InvalidStepper(stepper).into();
+            check_index(stepper, stepper::MAX_STEPPERS, error)
+        };
+        let check_pattern = |pattern: u8| {
+            let error = pattern::Error::InvalidPattern(pattern).into();
+            check_index(pattern, pattern::MAX_PATTERNS, error)
+        };
+        let check_idle = |plan: &Plan<N>, stepper: u8| match plan.busy_steppers & 1 << stepper {
+            0 => Ok(()),
+            _ => Err(stepper::Error::Busy(stepper)),
+        };
+        match command {
+            command if command.responds() => return Err(Error::InvalidWriteCmd(command)),
+            GpioCommand::SelectBank(bank) => {
+                if bank as usize * BANK_SIZE >= N {
+                    return Err(Error::InvalidBank(bank));
+                }
+                plan.bank = bank as usize;
+            }
+            GpioCommand::SetIoModes(gpio_group_0, gpio_group_1) => {
+                for (group, byte) in plan.banked(&[gpio_group_0, gpio_group_1]) {
+                    plan.modes[group] = byte;
+                }
+            }
+            GpioCommand::StageIoModes(gpio_group_0, gpio_group_1) => {
+                for (group, byte) in plan.banked(&[gpio_group_0, gpio_group_1]) {
+                    plan.staged_modes[group] = Some(byte);
+                }
+            }
+            GpioCommand::CommitStaged => {
+                for (modes, staged) in plan.modes.iter_mut().zip(plan.staged_modes.iter_mut()) {
+                    *modes = staged.take().unwrap_or(*modes);
+                }
+            }
+            GpioCommand::DiscardStaged => plan.staged_modes = [None; N],
+            GpioCommand::SetMeasured(gpio_group_0, gpio_group_1) => {
+                // The same checks as `check_measured`, but against the planned bank and modes
+                let bytes = [gpio_group_0, gpio_group_1];
+                let (mut gpios, mut released) = (0, 0);
+                for (group, byte) in plan.banked(&bytes) {
+                    let measured = self.groups[group].get_pin_measured();
+                    for pin in 0..self.groups[group].len() as u8 {
+                        let bit = self.groups[group].gpio(pin).map_or(0, |gpio| 1 << gpio);
+                        if byte & 1 << pin != 0 {
+                            gpios |= bit;
+                        } else if measured & 1 << pin != 0 {
+                            released |= bit;
+                        }
+                    }
+                }
+                measure::check(gpios, released).map_err(Error::CantMeasure)?;
+                for (group, byte) in plan.banked(&bytes) {
+                    let outputs = byte & plan.modes[group];
+                    if outputs != 0 {
+                        return Err(Error::NotAnInput(outputs.trailing_zeros() as u8));
+                    }
+                }
+            }
+            GpioCommand::SetEncoder(group, a, b, index, _) => {
+                let index = (index != NO_INDEX_PIN).then_some(index);
+                self.group(group as usize)
+                    .ok_or(Error::InvalidGroup(group))?
+                    .check_encoder(
+                        &Encoder::new(a, b, index, false),
+                        plan.modes[group as usize],
+                    )?;
+            }
+            GpioCommand::ClearEncoder(group) | GpioCommand::ResetEncoder(group) => {
+                self.group(group as usize)
+                    .ok_or(Error::InvalidGroup(group))?;
+            }
+            GpioCommand::Pulse(group, pin, ..) | GpioCommand::PulseMicros(group, pin, ..) => {
+                output_pin(plan, group, pin)?;
+                // Pulsing a pin that's already pulsing extends the pulse without another slot
+                let bit = 1 << self.pin_gpio(group, pin)?;
+                if plan.pulsing & bit == 0
+                    && plan.pulsing.count_ones() as usize == timed::MAX_PULSES
+                {
+                    return Err(timed::Error::Full.into());
+                }
+                plan.pulsing |= bit;
+            }
+            GpioCommand::CancelScheduled(slot) => check_index(
+                slot,
+                timed::MAX_CHANGES,
+                timed::Error::EmptySlot(slot).into(),
+            )?,
+            GpioCommand::DefinePattern(pattern, gpio_group_0, gpio_group_1, _) => {
+                check_pattern(pattern)?;
+                for (group, byte) in plan.banked(&[gpio_group_0, gpio_group_1]) {
+                    let inputs = byte & !plan.modes[group];
+                    if inputs != 0 {
+                        return Err(Error::NotAnOutput(inputs.trailing_zeros() as u8));
+                    }
+                }
+            }
+            GpioCommand::AddPatternStep(pattern, ..)
+            | GpioCommand::StartPattern(pattern)
+            | GpioCommand::PausePattern(pattern)
+            | GpioCommand::StopPattern(pattern) => check_pattern(pattern)?,
+            GpioCommand::SetServo(group, pin, enable) if enable != 0 => {
+                output_pin(plan, group, pin)?
+            }
+            GpioCommand::SetServo(group, pin, _)
+            | GpioCommand::SetServoSlew(group, pin, _)
+            | GpioCommand::WriteServoMicros(group, pin, _)
+            | GpioCommand::WriteServoAngle(group, pin, _) => {
+                self.pin_gpio(group, pin)?;
+            }
+            GpioCommand::SetServoLimits(group, pin, min_us, max_us) => {
+                self.pin_gpio(group, pin)?;
+                servo::check_limits(min_us, max_us)?;
+            }
+            GpioCommand::SetStepper(stepper, group, step_pin, dir_pin, _) => {
+                check_stepper(stepper)?;
+                output_pin(plan, group, step_pin)?;
+                output_pin(plan, group, dir_pin)?;
+                if step_pin == dir_pin {
+                    return Err(Error::InvalidPin(dir_pin));
+                }
+                check_idle(plan, stepper)?;
+            }
+            GpioCommand::MoveStepper(stepper, _, max_speed, _) => {
+                check_stepper(stepper)?;
+                if max_speed == 0 {
+                    return Err(stepper::Error::InvalidSpeed.into());
+                }
+                check_idle(plan, stepper)?;
+                plan.busy_steppers |= 1 << stepper;
+            }
+            GpioCommand::ClearStepper(stepper) | GpioCommand::SetStepperPosition(stepper, _) => {
+                check_stepper(stepper)?;
+                check_idle(plan, stepper)?;
+            }
+            GpioCommand::StopStepper(stepper) => check_stepper(stepper)?,
+            GpioCommand::SetPixel(index, ..) => ws2812::check(index as usize)?,
+            GpioCommand::FillPixels(start, count, ..) => ws2812::check_fill(start, count)?,
+            GpioCommand::SetPixelCount(count) => ws2812::check_count(count)?,
+            GpioCommand::SetUartConfig(baud, format) => {
+                UartConfig::new(baud, format)?;
+            }
+            GpioCommand::SetUartInterrupts(_, rx_threshold, tx_threshold) => {
+                uart::check_thresholds(rx_threshold, tx_threshold)?
+            }
+            GpioCommand::SetAnalogThreshold(channel, ..)
+            | GpioCommand::SetAnalogHysteresis(channel, _) => check_index(
+                channel,
+                analog::CHANNELS,
+                Error::InvalidAnalogChannel(channel),
+            )?,
+            GpioCommand::SetRule(slot, input_group, inputs, output_group, output_pin, flags) => {
+                check_index(slot, MAX_RULES, Error::InvalidRule(slot))?;
+                self.check_rule(&Rule::new(
+                    input_group,
+                    inputs,
+                    output_group,
+                    output_pin,
+                    flags,
+                ))?;
+            }
+            GpioCommand::ClearRule(slot) | GpioCommand::ResetLatch(slot) => {
+                check_index(slot, MAX_RULES, Error::InvalidRule(slot))?
+            }
+            GpioCommand::SaveConfig | GpioCommand::ClearConfig => self.check_storage()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Apply a command that doesn't respond, parsed from `bytes`
+    fn apply_write_command(&mut self, command: GpioCommand, bytes: &[u8]) -> Result<(), Error> {
         info!("Command: {:?}", command);
         match command {
             GpioCommand::WriteAllOutputs(gpio_group_0, gpio_group_1) => {
-                Ok(self.write(&[gpio_group_0, gpio_group_1]))
+                self.write(&[gpio_group_0, gpio_group_1])
             }
             GpioCommand::SetIoModes(gpio_group_0, gpio_group_1) => {
-                Ok(self.set_pin_modes(&[gpio_group_0, gpio_group_1]))
+                self.set_pin_modes(&[gpio_group_0, gpio_group_1])
             }
-            GpioCommand::WriteOutputs1(gpio_group_1) => Ok(self.write1(gpio_group_1)),
-            GpioCommand::WriteOutputs2(gpio_group_2) => Ok(self.write2(gpio_group_2)),
+            GpioCommand::WriteOutputs1(gpio_group_1) => self.write_group(0, gpio_group_1),
+            GpioCommand::WriteOutputs2(gpio_group_2) => self.write_group(1, gpio_group_2),
             GpioCommand::SetPullDowns(gpio_group1, gpio_group_2) => {
-                Ok(self.set_pin_pulls(&[gpio_group1, gpio_group_2], Pull::Down))
+                self.set_pin_pulls(&[gpio_group1, gpio_group_2], Pull::Down)
             }
             GpioCommand::SetPullUps(gpio_group1, gpio_group_2) => {
-                Ok(self.set_pin_pulls(&[gpio_group1, gpio_group_2], Pull::Up))
+                self.set_pin_pulls(&[gpio_group1, gpio_group_2], Pull::Up)
             }
             GpioCommand::SetPullNone(gpio_group1, gpio_group_2) => {
-                Ok(self.set_pin_pulls(&[gpio_group1, gpio_group_2], Pull::None))
+                self.set_pin_pulls(&[gpio_group1, gpio_group_2], Pull::None)
+            }
+            GpioCommand::SetPolarity(gpio_group_0, gpio_group_1) => {
+                self.set_pin_polarity(&[gpio_group_0, gpio_group_1])
+            }
+            GpioCommand::SetOpenDrain(gpio_group_0, gpio_group_1) => {
+                self.set_pin_open_drain(&[gpio_group_0, gpio_group_1])
+            }
+            GpioCommand::SetDriveStrength(gpio_group_0, gpio_group_1, strength) => {
+                self.set_pin_drive_strengths(&[gpio_group_0, gpio_group_1], strength)
+            }
+            GpioCommand::SetSlewRates(gpio_group_0, gpio_group_1) => {
+                self.set_pin_slew_rates(&[gpio_group_0, gpio_group_1])
+            }
+            GpioCommand::SetSchmitt(gpio_group_0, gpio_group_1) => {
+                self.set_pin_schmitt(&[gpio_group_0, gpio_group_1])
+            }
+            GpioCommand::SetCountRising(gpio_group_0, gpio_group_1) => {
+                self.set_count_rising(&[gpio_group_0, gpio_group_1])
+            }
+            GpioCommand::SetCountFalling(gpio_group_0, gpio_group_1) => {
+                self.set_count_falling(&[gpio_group_0, gpio_group_1])
+            }
+            GpioCommand::ClearCounts(gpio_group_0, gpio_group_1) => {
+                self.clear_counts(&[gpio_group_0, gpio_group_1])
+            }
+            GpioCommand::SetMeasured(gpio_group_0, gpio_group_1) => {
+                self.set_pin_measured(&[gpio_group_0, gpio_group_1])?
+            }
+            GpioCommand::SetMeasureGate(gate_ms) => measure::set_gate_ms(gate_ms),
+            GpioCommand::SetEncoder(group, a, b, index, flags) => {
+                let index = (index != NO_INDEX_PIN).then_some(index);
+                let encoder = Encoder::new(a, b, index, flags & ENCODER_TRIGGER_INT_OUT != 0);
+                self.set_encoder(group, Some(encoder))?
+            }
+            GpioCommand::ClearEncoder(group) => self.set_encoder(group, None)?,
+            GpioCommand::ResetEncoder(group) => self.encoder_group_mut(group)?.reset_encoder(),
+            GpioCommand::Pulse(group, pin, level, duration_ms) => {
+                let duration = Duration::from_millis(duration_ms as u64);
+                self.pulse(group, pin, level != 0, duration)?
+            }
+            GpioCommand::PulseMicros(group, pin, level, duration_us) => {
+                let duration = Duration::from_micros(duration_us as u64);
+                self.pulse(group, pin, level != 0, duration)?
+            }
+            GpioCommand::CancelScheduled(slot) => {
+                timed::cancel(slot)?;
             }
-            otherwise => Err(Error::InvalidWriteCmd(otherwise)),
+            GpioCommand::DefinePattern(pattern, gpio_group_0, gpio_group_1, loops) => {
+                self.define_pattern(pattern, &[gpio_group_0, gpio_group_1], loops)?
+            }
+            GpioCommand::AddPatternStep(pattern, gpio_group_0, gpio_group_1, duration_ms) => {
+                let levels = self.gpio_mask(&[gpio_group_0, gpio_group_1]);
+                let duration = Duration::from_millis(duration_ms as u64);
+                pattern::add_step(pattern, levels, duration)?
+            }
+            GpioCommand::StartPattern(pattern) => pattern::start(pattern)?,
+            GpioCommand::PausePattern(pattern) => pattern::pause(pattern)?,
+            GpioCommand::StopPattern(pattern) => pattern::stop(pattern)?,
+            GpioCommand::SetServo(group, pin, enable) => self.set_servo(group, pin, enable != 0)?,
+            GpioCommand::SetServoLimits(group, pin, min_us, max_us) => {
+                servo::set_limits(self.pin_gpio(group, pin)?, min_us, max_us)?
+            }
+            GpioCommand::SetServoSlew(group, pin, slew_us) => {
+                servo::set_slew(self.pin_gpio(group, pin)?, slew_us)?
+            }
+            GpioCommand::WriteServoMicros(group, pin, us) => {
+                servo::set_micros(self.pin_gpio(group, pin)?, us)?
+            }
+            GpioCommand::WriteServoAngle(group, pin, angle) => {
+                servo::set_angle(self.pin_gpio(group, pin)?, angle)?
+            }
+            GpioCommand::SetStepper(stepper, group, step_pin, dir_pin, flags) => {
+                self.set_stepper(stepper, group, step_pin, dir_pin, flags)?
+            }
+            GpioCommand::ClearStepper(stepper) => stepper::configure(stepper, None)?,
+            GpioCommand::MoveStepper(stepper, steps, max_speed, acceleration) => {
+                stepper::start_move(stepper, steps, max_speed, acceleration)?
+            }
+            GpioCommand::StopStepper(stepper) => stepper::stop(stepper)?,
+            GpioCommand::SetStepperPosition(stepper, position) => {
+                stepper::set_position(stepper, position)?
+            }
+            GpioCommand::SetPixel(index, r, g, b) => ws2812::set_pixel(index, Rgb::new(r, g, b))?,
+            GpioCommand::FillPixels(start, count, r, g, b) => {
+                ws2812::fill(start, count, Rgb::new(r, g, b))?
+            }
+            GpioCommand::SetPixelBrightness(brightness) => ws2812::set_brightness(brightness),
+            GpioCommand::SetPixelCount(count) => ws2812::set_pixel_count(count)?,
+            GpioCommand::LatchPixels => ws2812::latch(),
+            GpioCommand::SetUartConfig(baud, format) => {
+                uart::configure(UartConfig::new(baud, format)?)
+            }
+            GpioCommand::SetUartInterrupts(enabled, rx_threshold, tx_threshold) => {
+                uart::set_interrupts(enabled, rx_threshold, tx_threshold)?
+            }
+            // The bytes follow the count, the command has checked they're all there
+            GpioCommand::WriteUart(count) => uart::write(&bytes[2..2 + count as usize])?,
+            GpioCommand::SelectBank(bank) => self.select_bank(bank)?,
+            GpioCommand::SetAnalogChannels(mask) => analog::set_enabled(mask),
+            GpioCommand::SetAnalogAveraging(samples) => analog::set_averaging(samples),
+            GpioCommand::SetAnalogThreshold(channel, low, high) => {
+                analog::set_threshold(channel, low, high)
+                    .ok_or(Error::InvalidAnalogChannel(channel))?
+            }
+            GpioCommand::SetAnalogHysteresis(channel, hysteresis) => {
+                analog::set_hysteresis(channel, hysteresis)
+                    .ok_or(Error::InvalidAnalogChannel(channel))?
+            }
+            GpioCommand::SetRule(slot, input_group, inputs, output_group, output_pin, flags) => {
+                let rule = Rule::new(input_group, inputs, output_group, output_pin, flags);
+                self.set_rule(slot, rule)?
+            }
+            GpioCommand::ClearRule(slot) => self.clear_rule(slot)?,
+            GpioCommand::ResetLatch(slot) => self.reset_latch(slot)?,
+            GpioCommand::SaveConfig => self.save_config()?,
+            GpioCommand::ClearConfig => self.clear_config()?,
+            GpioCommand::StageIoModes(gpio_group_0, gpio_group_1) => {
+                self.stage(&[gpio_group_0, gpio_group_1], |staged| &mut staged.modes)
+            }
+            GpioCommand::StagePullUps(gpio_group_0, gpio_group_1) => {
+                self.stage(&[gpio_group_0, gpio_group_1], |staged| &mut staged.pull_ups)
+            }
+            GpioCommand::StagePullDowns(gpio_group_0, gpio_group_1) => self
+                .stage(&[gpio_group_0, gpio_group_1], |staged| {
+                    &mut staged.pull_downs
+                }),
+            GpioCommand::StageOutputs(gpio_group_0, gpio_group_1) => {
+                self.stage(&[gpio_group_0, gpio_group_1], |staged| &mut staged.outputs)
+            }
+            GpioCommand::StagePolarity(gpio_group_0, gpio_group_1) => {
+                self.stage(&[gpio_group_0, gpio_group_1], |staged| &mut staged.polarity)
+            }
+            GpioCommand::CommitStaged => self.commit_staged(),
+            GpioCommand::DiscardStaged => self.discard_staged(),
+            otherwise => return Err(Error::InvalidWriteCmd(otherwise)),
         }
+        Ok(())
     }
 
+    /// Handle a command that responds with data, returning the number of bytes written to `out`.
+    /// `out` must be at least [`MAX_RESPONSE_LEN`] bytes long to fit every response.
     pub fn handle_write_read_command(
         &mut self,
         bytes: &[u8],
-        out: &mut [u8; 2],
+        out: &mut [u8],
     ) -> Result<usize, Error> {
         let command = GpioCommand::from_bytes(bytes)?;
         match command {
             GpioCommand::ReadIoModes => {
-                self.get_pin_modes(out);
-                Ok(2)
+                self.get_pin_modes(banked_response(out)?);
+                Ok(BANK_SIZE)
+            }
+            GpioCommand::ReadPolarity => {
+                self.get_pin_polarity(banked_response(out)?);
+                Ok(BANK_SIZE)
+            }
+            GpioCommand::ReadOpenDrain => {
+                self.get_pin_open_drain(banked_response(out)?);
+                Ok(BANK_SIZE)
+            }
+            GpioCommand::ReadDriveStrength(group) => {
+                let packed = self
+                    .group(group as usize)
+                    .ok_or(Error::InvalidGroup(group))?
+                    .get_pin_drive_strengths();
+                respond(out, packed.to_le_bytes())
+            }
+            GpioCommand::ReadSlewRates => {
+                self.get_pin_slew_rates(banked_response(out)?);
+                Ok(BANK_SIZE)
+            }
+            GpioCommand::ReadSchmitt => {
+                self.get_pin_schmitt(banked_response(out)?);
+                Ok(BANK_SIZE)
+            }
+            GpioCommand::ReadCountRising => {
+                self.get_count_rising(banked_response(out)?);
+                Ok(BANK_SIZE)
+            }
+            GpioCommand::ReadCountFalling => {
+                self.get_count_falling(banked_response(out)?);
+                Ok(BANK_SIZE)
+            }
+            GpioCommand::ReadCounter(group, pin) => {
+                let count = self
+                    .group_mut(group as usize)
+                    .ok_or(Error::InvalidGroup(group))?
+                    .take_count(pin)
+                    .ok_or(Error::InvalidPin(pin))?;
+                respond(out, count.to_le_bytes())
+            }
+            GpioCommand::ReadEncoder(group) => {
+                let position = self
+                    .group(group as usize)
+                    .and_then(PinGroup::encoder_position)
+                    .ok_or(Error::NoEncoder(group))?;
+                respond(out, position.to_le_bytes())
+            }
+            GpioCommand::ScheduleOutput(group, pin, level, delay_ms) => {
+                let delay = Duration::from_millis(delay_ms as u64);
+                let slot = self.schedule_output(group, pin, level != 0, delay)?;
+                respond(out, [slot])
+            }
+            GpioCommand::ReadSchedule => respond(out, [timed::slots(), timed::take_status()]),
+            GpioCommand::ReadScheduled(slot) => respond(out, self.scheduled(slot)?),
+            GpioCommand::ReadPattern(pattern) => respond(out, pattern::status(pattern)?),
+            GpioCommand::ReadRule(slot) => {
+                let rule = self.rule(slot)?;
+                let latched = if self.is_latched(slot) {
+                    RULE_LATCHED
+                } else {
+                    0
+                };
+                let output = rule.output_group << 3 | rule.output_pin;
+                respond(
+                    out,
+                    [rule.input_group, rule.inputs, output, rule.flags | latched],
+                )
+            }
+            GpioCommand::ReadServo(group, pin) => {
+                let servo = self.servo(group, pin)?;
+                let [current_low, current_high] = servo.current_us.to_le_bytes();
+                let [target_low, target_high] = servo.target_us.to_le_bytes();
+                respond(out, [current_low, current_high, target_low, target_high])
+            }
+            GpioCommand::ReadStepperPosition(stepper) => {
+                let position = stepper::get(stepper)?.position();
+                respond(out, position.to_le_bytes())
+            }
+            GpioCommand::ReadStepperStatus => {
+                let completed = stepper::take_completed();
+                self.update_int_out();
+                respond(out, [stepper::busy(), completed])
+            }
+            GpioCommand::ReadPixel(index) => {
+                let Rgb { r, g, b } = ws2812::pixel(index)?;
+                respond(out, [r, g, b])
+            }
+            GpioCommand::ReadUart(max) => {
+                let len = (max as usize).min(uart::MAX_READ);
+                let data = out.get_mut(1..=len).ok_or(Error::ResponseTooLong)?;
+                let count = uart::read(data);
+                out[0] = count as u8;
+                self.update_int_out();
+                Ok(1 + count)
+            }
+            GpioCommand::ReadUartStatus => {
+                let flags = uart::take_flags();
+                self.update_int_out();
+                respond(out, [uart::rx_level(), uart::tx_free(), flags])
             }
+            GpioCommand::ReadMeasured => {
+                self.get_pin_measured(banked_response(out)?);
+                Ok(BANK_SIZE)
+            }
+            GpioCommand::ReadMeasurement(group, pin, quantity) => {
+                let value = self.measurement(group, pin, quantity)?;
+                respond(out, value.to_le_bytes())
+            }
+            GpioCommand::ReadAnalog(channel) => {
+                let reading =
+                    analog::reading(channel).ok_or(Error::InvalidAnalogChannel(channel))?;
+                respond(out, reading.to_le_bytes())
+            }
+            GpioCommand::ReadAnalogChannels => {
+                respond(out, [analog::available(), analog::enabled()])
+            }
+            GpioCommand::ReadAnalogFlags => {
+                let flags = analog::take_threshold_flags();
+                self.update_int_out();
+                respond(out, flags.to_le_bytes())
+            }
+            GpioCommand::ReadBank => respond(out, [self.get_bank()]),
+            GpioCommand::ReadBatchStatus => respond(out, self.batch_status),
             GpioCommand::ReadInputs1 => {
-                out[0] = self.gpio_group_0.read_pins();
-                self.gpio_group_0.clear_int_out();
-                Ok(1)
+                let group = self.banked_group_mut(0).ok_or(Error::InvalidGroup(0))?;
+                group.take_int_flags();
+                let inputs = group.read_pins();
+                self.update_int_out();
+                respond(out, [inputs])
             }
             GpioCommand::ReadInputs2 => {
-                out[0] = self.gpio_group_1.read_pins();
-                Ok(1)
+                let group = self.banked_group_mut(1).ok_or(Error::InvalidGroup(1))?;
+                group.take_int_flags();
+                let inputs = group.read_pins();
+                self.update_int_out();
+                respond(out, [inputs])
             }
+            GpioCommand::ReadOutputLatch => {
+                self.get_output_latch(banked_response(out)?);
+                Ok(BANK_SIZE)
+            }
+            GpioCommand::ReadSnapshot => respond(out, self.snapshot()),
             otherwise => Err(Error::InvalidWriteReadCmd(otherwise)),
         }
     }
 
-    pub fn handle_read_command(&self, out: &mut [u8; 2]) {
-        SET_INT_OUT.signal(false);
-        self.read(out);
+    /// A plain read returns the state of every group, clearing the interrupt flags
+    pub fn handle_read_command(&mut self, out: &mut [u8; N]) {
+        self.read_all(out);
+        for group in self.groups.iter_mut() {
+            group.take_int_flags();
+        }
+        self.update_int_out();
     }
 }
 
+/// Copy `bytes` to the start of `out`, returning the response length
+fn respond<const L: usize>(out: &mut [u8], bytes: [u8; L]) -> Result<usize, Error> {
+    out.get_mut(..L)
+        .ok_or(Error::ResponseTooLong)?
+        .copy_from_slice(&bytes);
+    Ok(L)
+}
+
+/// The part of `out` that holds a byte per group in the selected bank
+fn banked_response(out: &mut [u8]) -> Result<&mut [u8; BANK_SIZE], Error> {
+    out.first_chunk_mut().ok_or(Error::ResponseTooLong)
+}
+
 #[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
 pub enum Error {
     FailedToParseCmd(crate::commands::Error),
     InvalidWriteCmd(GpioCommand),
     InvalidWriteReadCmd(GpioCommand),
+    InvalidGroup(u8),
+    InvalidPin(u8),
+    /// The GPIO can't be measured, or its PWM slice is measuring another pin or driving servos
+    CantMeasure(u8),
+    NotMeasured(u8),
+    NoEncoder(u8),
+    InvalidRule(u8),
+    NotAnOutput(u8),
+    NotAnInput(u8),
+    Timed(timed::Error),
+    Pattern(pattern::Error),
+    Servo(servo::Error),
+    Stepper(stepper::Error),
+    Ws2812(ws2812::Error),
+    Uart(uart::Error),
+    /// The response doesn't fit in the output buffer
+    ResponseTooLong,
+    InvalidBank(u8),
+    InvalidAnalogChannel(u8),
+    Config(crate::config::Error),
 }
 
 impl From<crate::commands::Error> for Error {
@@ -131,3 +1288,54 @@ impl From<crate::commands::Error> for Error {
         Self::FailedToParseCmd(err)
     }
 }
+
+impl From<encoder::Error> for Error {
+    fn from(err: encoder::Error) -> Self {
+        match err {
+            encoder::Error::InvalidPin(pin) => Self::InvalidPin(pin),
+            encoder::Error::NotAnInput(pin) => Self::NotAnInput(pin),
+        }
+    }
+}
+
+impl From<timed::Error> for Error {
+    fn from(err: timed::Error) -> Self {
+        Self::Timed(err)
+    }
+}
+
+impl From<pattern::Error> for Error {
+    fn from(err: pattern::Error) -> Self {
+        Self::Pattern(err)
+    }
+}
+
+impl From<servo::Error> for Error {
+    fn from(err: servo::Error) -> Self {
+        Self::Servo(err)
+    }
+}
+
+impl From<stepper::Error> for Error {
+    fn from(err: stepper::Error) -> Self {
+        Self::Stepper(err)
+    }
+}
+
+impl From<ws2812::Error> for Error {
+    fn from(err: ws2812::Error) -> Self {
+        Self::Ws2812(err)
+    }
+}
+
+impl From<uart::Error> for Error {
+    fn from(err: uart::Error) -> Self {
+        Self::Uart(err)
+    }
+}
+
+impl From<crate::config::Error> for Error {
+    fn from(err: crate::config::Error) -> Self {
+        Self::Config(err)
+    }
+}
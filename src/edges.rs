@@ -0,0 +1,315 @@
+//! Edges on the groups' pins, taken from the edges IO_BANK0 latches for every GPIO by the GPIO
+//! interrupt, so a pulse shorter than the time between two wakeups of the device task is still
+//! seen. The interrupt counts the edges of the counted inputs and decodes the encoders as they
+//! happen, see [`crate::gpios::PinGroup::set_count_rising`] and
+//! [`crate::gpios::PinGroup::set_encoder`], and collects the pins that changed for the device
+//! task, which reads the counts and positions back.
+//!
+//! embassy-rp owns the GPIO interrupt, waking the pins it's waiting on, so [`install`] puts
+//! [`handle`] in front of its handler. Tests don't install it and call [`handle`] themselves,
+//! as they do with the tasks.
+
+use core::cell::RefCell;
+use core::ptr::addr_of_mut;
+
+use embassy_rp::interrupt::{InterruptExt, Priority};
+use embassy_rp::{interrupt, pac};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use portable_atomic::{AtomicUsize, Ordering};
+
+use crate::{gpios, rules};
+
+/// Number of GPIOs in IO_BANK0
+pub const GPIOS: usize = 30;
+/// IO_BANK0 latches an edge in each of these bits of a pin's nibble of the INTR registers
+const EDGE_LOW: u32 = 0b0100;
+const EDGE_HIGH: u32 = 0b1000;
+/// The 16 system exceptions come before the interrupts in the vector table
+const IO_IRQ_BANK0_VECTOR: usize = 16 + 13;
+const VECTORS: usize = 16 + 32;
+/// The state that follows each A/B state when turning forwards, A leads B
+const FORWARD: [u8; 4] = [0b10, 0b00, 0b11, 0b01];
+
+/// A quadrature encoder on two GPIOs, decoded on every state change (x4)
+#[derive(Clone, Copy)]
+struct Decoder {
+    a: u8,
+    b: u8,
+    /// Resets the position to 0 on its rising edge
+    index: Option<u8>,
+    position: i32,
+    state: u8,
+    index_level: bool,
+}
+
+impl Decoder {
+    /// Start decoding from the logical `levels` of the GPIOs
+    fn new(a: u8, b: u8, index: Option<u8>, levels: u32) -> Self {
+        Self {
+            a,
+            b,
+            index,
+            position: 0,
+            state: Self::state(a, b, levels),
+            index_level: index.is_some_and(|index| levels & 1 << index != 0),
+        }
+    }
+
+    fn state(a: u8, b: u8, levels: u32) -> u8 {
+        ((levels >> a & 1) << 1 | (levels >> b & 1)) as u8
+    }
+
+    /// Decode the change from the previous levels, returning whether the position changed.
+    /// A change of both A and B at once is a missed step and is ignored.
+    fn update(&mut self, levels: u32) -> bool {
+        let previous = self.position;
+        let state = Self::state(self.a, self.b, levels);
+        if state == FORWARD[self.state as usize] {
+            self.position = self.position.wrapping_add(1);
+        } else if self.state == FORWARD[state as usize] {
+            self.position = self.position.wrapping_sub(1);
+        }
+        self.state = state;
+
+        let index_level = self.index.is_some_and(|index| levels & 1 << index != 0);
+        if index_level && !self.index_level {
+            self.position = 0;
+        }
+        self.index_level = index_level;
+        self.position != previous
+    }
+}
+
+struct Edges {
+    /// GPIOs whose edges are taken, the pins of every group
+    watched: u32,
+    /// GPIOs whose logical rising or falling edges are counted
+    rising: u32,
+    falling: u32,
+    counts: [u32; GPIOS],
+    /// Encoders by the GPIO of their A pin
+    decoders: [Option<Decoder>; GPIOS],
+    /// GPIOs that had an edge since the device task last took them
+    changed: u32,
+    /// A pins of the encoders whose position changed since the device task last took them
+    moved: u32,
+}
+
+static EDGES: Mutex<CriticalSectionRawMutex, RefCell<Edges>> = Mutex::new(RefCell::new(Edges {
+    watched: 0,
+    rising: 0,
+    falling: 0,
+    counts: [0; GPIOS],
+    decoders: [None; GPIOS],
+    changed: 0,
+    moved: 0,
+}));
+/// Wakes the device task when a pin has changed
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The vector table, copied to RAM so the GPIO interrupt's handler can be replaced. It's
+/// aligned to the next power of two above its size, as VTOR requires.
+#[repr(C, align(256))]
+struct VectorTable([usize; VECTORS]);
+
+static mut VECTOR_TABLE: VectorTable = VectorTable([0; VECTORS]);
+/// embassy-rp's handler of the GPIO interrupt, called after [`handle`]
+static EMBASSY_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Take the edges of `gpios` from the interrupt, on top of those already watched. Edges latched
+/// before are dropped.
+pub fn watch(gpios: u32) {
+    EDGES.lock(|edges| {
+        edges.borrow_mut().watched |= gpios;
+        take_latched(gpios);
+        enable(gpios);
+    });
+}
+
+/// Enable the interrupt on both edges of `gpios`
+fn enable(gpios: u32) {
+    for gpio in (0..GPIOS).filter(|gpio| gpios & 1 << gpio != 0) {
+        pac::IO_BANK0
+            .int_proc(0)
+            .inte(gpio / 8)
+            .modify(|w| w.0 |= (EDGE_LOW | EDGE_HIGH) << (gpio % 8 * 4));
+    }
+}
+
+/// Count the logical rising edges of the inputs in `rising` and the falling edges of those in
+/// `falling`, replacing what was counted for the GPIOs in `gpios`
+pub fn set_counted(gpios: u32, rising: u32, falling: u32) {
+    EDGES.lock(|edges| {
+        let mut edges = edges.borrow_mut();
+        edges.rising = edges.rising & !gpios | rising;
+        edges.falling = edges.falling & !gpios | falling;
+    });
+}
+
+/// Returns and resets the count of `gpio`
+pub fn take_count(gpio: u8) -> u32 {
+    EDGES.lock(|edges| core::mem::take(&mut edges.borrow_mut().counts[gpio as usize]))
+}
+
+/// Reset the counts of the GPIOs in `gpios`
+pub fn clear_counts(gpios: u32) {
+    EDGES.lock(|edges| {
+        let mut edges = edges.borrow_mut();
+        for gpio in (0..GPIOS).filter(|gpio| gpios & 1 << gpio != 0) {
+            edges.counts[gpio] = 0;
+        }
+    });
+}
+
+/// Decode the encoder on GPIOs `a`, `b` and `index` from position 0, replacing any encoder on
+/// `gpios`, or stop decoding the encoders on `gpios`
+pub fn set_encoder(gpios: u32, encoder: Option<(u8, u8, Option<u8>)>) {
+    EDGES.lock(|edges| {
+        let mut edges = edges.borrow_mut();
+        for gpio in (0..GPIOS).filter(|gpio| gpios & 1 << gpio != 0) {
+            edges.decoders[gpio] = None;
+        }
+        edges.moved &= !gpios;
+        if let Some((a, b, index)) = encoder {
+            edges.decoders[a as usize] = Some(Decoder::new(a, b, index, gpios::read_gpios()));
+        }
+    });
+}
+
+/// Position of the encoder whose A pin is `gpio`
+pub fn position(gpio: u8) -> Option<i32> {
+    EDGES.lock(|edges| edges.borrow().decoders[gpio as usize].map(|decoder| decoder.position))
+}
+
+/// Reset the position of the encoder whose A pin is `gpio` to 0
+pub fn reset_position(gpio: u8) {
+    EDGES.lock(|edges| {
+        if let Some(decoder) = edges.borrow_mut().decoders[gpio as usize].as_mut() {
+            decoder.position = 0;
+        }
+    });
+}
+
+/// Returns the A pins of the encoders that moved since the last call
+pub fn take_moved() -> u32 {
+    EDGES.lock(|edges| core::mem::take(&mut edges.borrow_mut().moved))
+}
+
+/// Returns the GPIOs that changed since the last call
+pub fn take_changed() -> u32 {
+    EDGES.lock(|edges| core::mem::take(&mut edges.borrow_mut().changed))
+}
+
+/// Wait for a GPIO to change, returning those that changed since the last call
+pub async fn wait_for_changes() -> u32 {
+    loop {
+        CHANGED.wait().await;
+        let changed = take_changed();
+        if changed != 0 {
+            return changed;
+        }
+    }
+}
+
+/// Clear the edges latched for `gpios`, returning the electrical rising and falling edges
+fn take_latched(gpios: u32) -> (u32, u32) {
+    let (mut rising, mut falling) = (0, 0);
+    for reg in 0..GPIOS.div_ceil(8) {
+        let intr = pac::IO_BANK0.intr(reg);
+        let latched = intr.read().0;
+        let mut taken = 0;
+        for pin in 0..8 {
+            let gpio = reg * 8 + pin;
+            if gpio >= GPIOS || gpios & 1 << gpio == 0 {
+                continue;
+            }
+            let edges = latched >> (pin * 4) & (EDGE_LOW | EDGE_HIGH);
+            if edges & EDGE_HIGH != 0 {
+                rising |= 1 << gpio;
+            }
+            if edges & EDGE_LOW != 0 {
+                falling |= 1 << gpio;
+            }
+            taken |= edges << (pin * 4);
+        }
+        // The edge bits are cleared by writing 1s, so edges latched since the read are kept
+        intr.write_value(pac::io::regs::Int(taken));
+    }
+    (rising, falling)
+}
+
+/// Take the latched edges of the watched GPIOs, counting them, decoding the encoders from the
+/// levels that follow them, applying the rules and waking the device task. Edges are logical, so
+/// an inverted input counts its electrical falling edges as rising.
+///
+/// The rules aren't applied again for the edges of their own outputs, so a rule whose output
+/// feeds its inputs can't keep the interrupt busy.
+pub fn handle() {
+    let changed = EDGES.lock(|edges| {
+        let mut edges = edges.borrow_mut();
+        let (high, low) = take_latched(edges.watched);
+        let inverted = gpios::inverted_gpios();
+        let rising = high & !inverted | low & inverted;
+        let falling = low & !inverted | high & inverted;
+        let (rising, falling) = (rising & edges.rising, falling & edges.falling);
+        let inputs = !gpios::output_gpios();
+        for gpio in (0..GPIOS).filter(|gpio| inputs & 1 << gpio != 0) {
+            let counted = (rising >> gpio & 1) + (falling >> gpio & 1);
+            let count = &mut edges.counts[gpio];
+            *count = count.wrapping_add(counted);
+        }
+        if high | low != 0 {
+            let levels = gpios::read_gpios();
+            let mut moved = 0;
+            for decoder in edges.decoders.iter_mut().flatten() {
+                if decoder.update(levels) {
+                    moved |= 1 << decoder.a;
+                }
+            }
+            edges.moved |= moved;
+        }
+        edges.changed |= high | low;
+        high | low
+    });
+    if changed & !rules::outputs() != 0 {
+        rules::apply();
+    }
+    if changed != 0 {
+        CHANGED.signal(());
+    }
+}
+
+/// Handle the edges of the watched GPIOs in the GPIO interrupt, ahead of embassy-rp's handler,
+/// which runs the interrupt for the pins it's waiting on. The interrupt is raised above the
+/// high priority executor, so edges are taken as they happen.
+pub fn install() {
+    cortex_m::interrupt::free(|_| {
+        // SAFETY: Interrupts are disabled while the table is copied and VTOR is moved to it,
+        // and the copy is only written here
+        unsafe {
+            let scb = &*cortex_m::peripheral::SCB::PTR;
+            let table = &mut (*addr_of_mut!(VECTOR_TABLE)).0;
+            let vectors = scb.vtor.read() as *const usize;
+            for (index, vector) in table.iter_mut().enumerate() {
+                *vector = vectors.add(index).read_volatile();
+            }
+            EMBASSY_HANDLER.store(table[IO_IRQ_BANK0_VECTOR], Ordering::Relaxed);
+            table[IO_IRQ_BANK0_VECTOR] = io_irq_bank0 as *const () as usize;
+            scb.vtor.write(table.as_ptr() as u32);
+        }
+    });
+    interrupt::IO_IRQ_BANK0.set_priority(Priority::P1);
+}
+
+extern "C" fn io_irq_bank0() {
+    handle();
+    // SAFETY: This is only the handler once `install` has stored embassy-rp's
+    let embassy: extern "C" fn() =
+        unsafe { core::mem::transmute(EMBASSY_HANDLER.load(Ordering::Relaxed)) };
+    embassy();
+    // embassy-rp disables the interrupt of a pin with an edge, which a watched GPIO has if it
+    // changed again since it was handled
+    EDGES.lock(|edges| enable(edges.borrow().watched));
+}
@@ -1,23 +1,74 @@
-use core::marker::PhantomData;
-
-use embassy_rp::gpio::{Flex, Pin, Pull};
-use embassy_rp::peripherals::*;
-
-pub type PinGroup0 = PinGroup<
-    PIN_6,
-    // PIN_3, // Replace PIN_6 with PIN_3 because i shorted it
-    PIN_7,
-    PIN_8,
-    PIN_9,
-    PIN_10,
-    PIN_11,
-    PIN_12,
-    PIN_13,
-    IntOutTrigger,
->;
-
-pub type PinGroup1 =
-    PinGroup<PIN_14, PIN_15, PIN_16, PIN_17, PIN_18, PIN_19, PIN_20, PIN_21, IntOutNoTrigger>;
+use embassy_rp::gpio::{AnyPin, Drive, Flex, Pin, Pull, SlewRate};
+use embassy_rp::pac;
+use heapless::Vec;
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::config::GroupConfig;
+use crate::{edges, servo};
+
+pub use encoder::Encoder;
+pub use pad::DriveStrength;
+
+/// Maximum number of pins in a single [`PinGroup`]
+pub const GROUP_SIZE: usize = 8;
+/// The RP2040 only has 30 GPIOs, so there can never be more than 4 groups
+pub const MAX_GROUPS: usize = 4;
+
+/// GPIOs that are outputs in their group, by GPIO number, kept up to date by the groups so that
+/// [`drive_gpios`] can check them from another executor
+static OUTPUT_GPIOS: AtomicU32 = AtomicU32::new(0);
+/// GPIOs that are open drain in their group, by GPIO number
+static OPEN_DRAIN_GPIOS: AtomicU32 = AtomicU32::new(0);
+/// GPIOs whose input is inverted in their group, by GPIO number
+static INVERTED_GPIOS: AtomicU32 = AtomicU32::new(0);
+
+/// GPIOs that are outputs in their group
+pub fn output_gpios() -> u32 {
+    OUTPUT_GPIOS.load(Ordering::Relaxed)
+}
+
+/// GPIOs that are inverted inputs in their group, see [`PinGroup::set_pin_polarity`]
+pub fn inverted_gpios() -> u32 {
+    INVERTED_GPIOS.load(Ordering::Relaxed) & !output_gpios()
+}
+
+/// Drive the GPIOs in `mask` to `levels` directly through SIO, the registers the groups use.
+///
+/// GPIOs that aren't outputs in their group are left alone. Open drain pins are released before
+/// their latch goes high, and only driven once it's low, so they never drive the line high.
+pub fn drive_gpios(mask: u32, levels: u32) {
+    write_gpios(mask & output_gpios(), levels);
+}
+
+/// Set the output latches of the GPIOs in `mask` to `levels` through SIO, as
+/// [`PinGroup::write_pin`] does. Outputs drive them straight away, inputs keep them.
+pub fn write_gpios(mask: u32, levels: u32) {
+    let open_drain = mask & OPEN_DRAIN_GPIOS.load(Ordering::Relaxed);
+    let (out, oe) = (pac::SIO.gpio_out(0), pac::SIO.gpio_oe(0));
+    oe.value_clr().write_value(open_drain & levels);
+    out.value_set().write_value(mask & levels);
+    out.value_clr().write_value(mask & !levels);
+    oe.value_set()
+        .write_value(open_drain & output_gpios() & !levels);
+}
+
+/// Read the logical levels of the GPIOs, with the inverted inputs inverted, as
+/// [`PinGroup::read_pins`] does
+pub fn read_gpios() -> u32 {
+    pac::SIO.gpio_in(0).read() ^ inverted_gpios()
+}
+
+/// Publish `gpio`, an open drain output outside the groups, so [`drive_gpios`] and through it
+/// [`crate::timed`] can drive it, or stop publishing it
+pub fn publish_open_drain(gpio: u8, publish: bool) {
+    for gpios in [&OUTPUT_GPIOS, &OPEN_DRAIN_GPIOS] {
+        if publish {
+            gpios.fetch_or(1 << gpio, Ordering::Relaxed);
+        } else {
+            gpios.fetch_and(!(1 << gpio), Ordering::Relaxed);
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -48,53 +99,133 @@ impl PinMask {
         *self as u8
     }
 
+    pub const fn index(&self) -> usize {
+        self.to_u8().trailing_zeros() as usize
+    }
+
     pub const fn is_in_mask(&self, mask: u8) -> bool {
         mask & self.to_u8() == self.to_u8()
     }
 }
 
-/// Marker for GPIO pin group that triggers the INT_OUT signal on input
-pub struct IntOutTrigger;
-/// Marker for GPIO pin group that does not trigger the INT_OUT signal on input
-pub struct IntOutNoTrigger;
-
-/// Groups 8 pins together so that they can be read from, and written to, as a single byte
-pub struct PinGroup<P0: Pin, P1: Pin, P2: Pin, P3: Pin, P4: Pin, P5: Pin, P6: Pin, P7: Pin, IntOut>
-{
-    p0: Flex<'static, P0>,
-    p1: Flex<'static, P1>,
-    p2: Flex<'static, P2>,
-    p3: Flex<'static, P3>,
-    p4: Flex<'static, P4>,
-    p5: Flex<'static, P5>,
-    p6: Flex<'static, P6>,
-    p7: Flex<'static, P7>,
+/// Groups up to 8 pins together so that they can be read from, and written to, as a single byte.
+///
+/// Groups with fewer than 8 pins ignore writes to, and read as 0 from, the missing pins.
+pub struct PinGroup {
+    pins: Vec<Flex<'static, AnyPin>, GROUP_SIZE>,
+    /// GPIO number of each pin
+    gpios: Vec<u8, GROUP_SIZE>,
     pin_modes: u8,
-    _trigger_int_out: PhantomData<IntOut>,
+    polarity: u8,
+    open_drain: u8,
+    pull_ups: u8,
+    pull_downs: u8,
+    drive_strength: u16,
+    slew_fast: u8,
+    schmitt: u8,
+    count_rising: u8,
+    count_falling: u8,
+    measured: u8,
+    encoder: Option<Encoder>,
+    trigger_int_out: bool,
+    /// Pins whose edges have asserted INT_OUT since the inputs were last read
+    int_flags: u8,
 }
 
-impl<P0: Pin, P1: Pin, P2: Pin, P3: Pin, P4: Pin, P5: Pin, P6: Pin, P7: Pin, IntOut>
-    PinGroup<P0, P1, P2, P3, P4, P5, P6, P7, IntOut>
-{
-    pub fn new(p0: P0, p1: P1, p2: P2, p3: P3, p4: P4, p5: P5, p6: P6, p7: P7) -> Self {
+impl PinGroup {
+    /// Create a group from up to 8 pins, the first pin is P0.
+    ///
+    /// If `trigger_int_out` is set then an edge on any pin in the group will assert INT_OUT.
+    ///
+    /// # Panics
+    /// If more than 8 pins are given.
+    pub fn new(pins: impl IntoIterator<Item = AnyPin>, trigger_int_out: bool) -> Self {
+        let mut gpios = Vec::new();
+        let pins = pins
+            .into_iter()
+            .map(|pin| {
+                // Collecting `pins` panics if there are too many, so this can't overflow
+                let _ = gpios.push(pin.pin());
+                Flex::new(pin)
+            })
+            .collect();
         let mut this = Self {
-            p0: Flex::new(p0),
-            p1: Flex::new(p1),
-            p2: Flex::new(p2),
-            p3: Flex::new(p3),
-            p4: Flex::new(p4),
-            p5: Flex::new(p5),
-            p6: Flex::new(p6),
-            p7: Flex::new(p7),
+            pins,
+            gpios,
             pin_modes: 0,
-            _trigger_int_out: PhantomData,
+            polarity: 0,
+            open_drain: 0,
+            pull_ups: 0,
+            pull_downs: 0,
+            drive_strength: 0,
+            slew_fast: 0,
+            schmitt: 0,
+            count_rising: 0,
+            count_falling: 0,
+            measured: 0,
+            encoder: None,
+            trigger_int_out,
+            int_flags: 0,
         };
         this.set_pin_modes(0); // Initially set all pins to input mode
+
+        // `Flex::new` clears the pad config, so restore the RP2040's reset defaults
+        this.set_pin_drive_strengths(0xFF, DriveStrength::_4mA);
+        this.set_pin_slew_rates(0);
+        this.set_pin_schmitt(0xFF);
+        edges::watch(this.gpio_mask(0xFF));
         this
     }
 
+    /// Number of pins in the group
+    pub fn len(&self) -> usize {
+        self.pins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pins.is_empty()
+    }
+
+    /// GPIO number of the pin at `index`
+    pub fn gpio(&self, index: u8) -> Option<u8> {
+        self.gpios.get(index as usize).copied()
+    }
+
+    /// Translate a mask of pins to a mask of their GPIO numbers
+    pub fn gpio_mask(&self, bits: u8) -> u32 {
+        self.pin_masks()
+            .iter()
+            .filter(|pin| pin.is_in_mask(bits))
+            .fold(0, |mask, pin| mask | 1 << self.gpios[pin.index()])
+    }
+
+    /// Translate a mask of GPIO numbers to a mask of the pins in this group
+    pub fn pin_mask(&self, gpios: u32) -> u8 {
+        self.pin_masks()
+            .iter()
+            .filter(|pin| gpios & 1 << self.gpios[pin.index()] != 0)
+            .fold(0, |mask, pin| mask | pin.to_u8())
+    }
+
+    pub fn triggers_int_out(&self) -> bool {
+        self.trigger_int_out
+    }
+
+    /// The masks of the pins that actually exist in this group
+    fn pin_masks(&self) -> &'static [PinMask] {
+        &PinMask::ARR[..self.pins.len()]
+    }
+
+    fn pin(&self, pin_mask: &PinMask) -> Option<&Flex<'static, AnyPin>> {
+        self.pins.get(pin_mask.index())
+    }
+
+    fn pin_mut(&mut self, pin_mask: &PinMask) -> Option<&mut Flex<'static, AnyPin>> {
+        self.pins.get_mut(pin_mask.index())
+    }
+
     pub fn set_pin_modes(&mut self, bits: u8) {
-        for pin in PinMask::ARR.iter() {
+        for pin in self.pin_masks() {
             self.set_pin_mode(bits, pin);
         }
         self.pin_modes = bits;
@@ -113,179 +244,415 @@ impl<P0: Pin, P1: Pin, P2: Pin, P3: Pin, P4: Pin, P5: Pin, P6: Pin, P7: Pin, Int
     }
 
     pub fn set_pin_output(&mut self, pin_mask: &PinMask) {
-        match pin_mask {
-            PinMask::P0 => self.p0.set_as_output(),
-            PinMask::P1 => self.p1.set_as_output(),
-            PinMask::P2 => self.p2.set_as_output(),
-            PinMask::P3 => self.p3.set_as_output(),
-            PinMask::P4 => self.p4.set_as_output(),
-            PinMask::P5 => self.p5.set_as_output(),
-            PinMask::P6 => self.p6.set_as_output(),
-            PinMask::P7 => self.p7.set_as_output(),
-        }
+        // A measured pin is connected to its PWM slice, which would keep it from being driven
+        self.release_measured(!pin_mask.to_u8());
+        // A released open drain output is electrically an input, so only drive it if it's low
+        let drive = !self.is_pin_open_drain(pin_mask) || !self.read_output_latch(pin_mask);
+        self.set_output_enable(pin_mask, drive);
+        self.publish(pin_mask, &OUTPUT_GPIOS, true);
     }
 
     pub fn set_pin_input(&mut self, pin_mask: &PinMask) {
-        match pin_mask {
-            PinMask::P0 => self.p0.set_as_input(),
-            PinMask::P1 => self.p1.set_as_input(),
-            PinMask::P2 => self.p2.set_as_input(),
-            PinMask::P3 => self.p3.set_as_input(),
-            PinMask::P4 => self.p4.set_as_input(),
-            PinMask::P5 => self.p5.set_as_input(),
-            PinMask::P6 => self.p6.set_as_input(),
-            PinMask::P7 => self.p7.set_as_input(),
-        }
+        self.release_output(pin_mask);
         //TODO: configurable pull up/down
-        match pin_mask {
-            PinMask::P0 => self.p0.set_pull(Pull::Up),
-            PinMask::P1 => self.p1.set_pull(Pull::Up),
-            PinMask::P2 => self.p2.set_pull(Pull::Up),
-            PinMask::P3 => self.p3.set_pull(Pull::Up),
-            PinMask::P4 => self.p4.set_pull(Pull::Up),
-            PinMask::P5 => self.p5.set_pull(Pull::Up),
-            PinMask::P6 => self.p6.set_pull(Pull::Up),
-            PinMask::P7 => self.p7.set_pull(Pull::Up),
+        self.set_pin_pull(pin_mask, Pull::Up);
+    }
+
+    /// Stop driving the pin, from SIO or as a servo, leaving its pull as it is
+    fn release_output(&mut self, pin_mask: &PinMask) {
+        // Unpublished first, so `drive_gpios` can't enable the output again once it's disabled
+        self.publish(pin_mask, &OUTPUT_GPIOS, false);
+        self.set_output_enable(pin_mask, false);
+        if let Some(gpio) = self.gpio(pin_mask.index() as u8) {
+            servo::disable(gpio);
+        }
+    }
+
+    fn set_output_enable(&mut self, pin_mask: &PinMask, enable: bool) {
+        if let Some(pin) = self.pin_mut(pin_mask) {
+            if enable {
+                pin.set_as_output();
+            } else {
+                pin.set_as_input();
+            }
+        }
+    }
+
+    /// Set the pin's bit of a GPIO mask shared with [`drive_gpios`]
+    fn publish(&self, pin_mask: &PinMask, gpios: &AtomicU32, set: bool) {
+        let Some(gpio) = self.gpio(pin_mask.index() as u8) else {
+            return;
+        };
+        if set {
+            gpios.fetch_or(1 << gpio, Ordering::Relaxed);
+        } else {
+            gpios.fetch_and(!(1 << gpio), Ordering::Relaxed);
+        }
+    }
+
+    /// Set which pins are open drain outputs (set bits) and which are push-pull outputs (unset
+    /// bits). Open drain is emulated by only enabling the output driver while the pin is low.
+    pub fn set_pin_open_drain(&mut self, bits: u8) {
+        self.open_drain = bits;
+        for pin in self.pin_masks() {
+            self.publish(pin, &OPEN_DRAIN_GPIOS, pin.is_in_mask(bits));
+        }
+        for pin in self.pin_masks() {
+            if self.is_pin_output(pin) {
+                self.set_pin_output(pin);
+            }
         }
     }
 
+    pub fn get_pin_open_drain(&self) -> u8 {
+        self.open_drain
+    }
+
+    pub fn is_pin_open_drain(&self, pin_mask: &PinMask) -> bool {
+        pin_mask.is_in_mask(self.open_drain)
+    }
+
     pub fn is_pin_output(&self, pin_mask: &PinMask) -> bool {
         self.pin_modes & pin_mask.to_u8() == pin_mask.to_u8()
     }
 
     pub fn write_pins(&mut self, byte: u8) {
-        for pin in PinMask::ARR.iter() {
+        for pin in self.pin_masks() {
             self.write_pin(pin, pin.is_in_mask(byte));
         }
     }
 
+    /// Set the pin's output latch (OLAT). An output drives it straight away, an input keeps it
+    /// and drives it as soon as it becomes an output, so it never briefly drives a stale level.
     pub fn write_pin(&mut self, pin_mask: &PinMask, high: bool) {
+        self.write_output_latch(pin_mask, high);
+        // The latch is already low when an open drain output starts driving
+        if !high && self.is_pin_output(pin_mask) && self.is_pin_open_drain(pin_mask) {
+            self.set_output_enable(pin_mask, true);
+        }
+    }
+
+    fn write_output_latch(&mut self, pin_mask: &PinMask, high: bool) {
+        // Release an open drain pin before its latch goes high, so it never drives the line high
+        if high && self.is_pin_open_drain(pin_mask) {
+            self.set_output_enable(pin_mask, false);
+        }
+        if let Some(pin) = self.pin_mut(pin_mask) {
+            pin.set_level(high.into());
+        }
+    }
+
+    /// Set which input pins are inverted, a set bit means the logical value of that input is the
+    /// inverse of its electrical level. Output pins are unaffected.
+    pub fn set_pin_polarity(&mut self, bits: u8) {
+        self.polarity = bits;
+        for pin in self.pin_masks() {
+            self.publish(pin, &INVERTED_GPIOS, pin.is_in_mask(bits));
+        }
+    }
+
+    pub fn get_pin_polarity(&self) -> u8 {
+        self.polarity
+    }
+
+    /// Read the logical value of all pins, with input polarity applied
+    pub fn read_pins(&self) -> u8 {
+        let existing = self
+            .pin_masks()
+            .iter()
+            .fold(0, |mask, pin| mask | pin.to_u8());
+        (self.read_raw_pins() ^ (self.polarity & !self.pin_modes)) & existing
+    }
+
+    /// Read the electrical level of all pins
+    pub fn read_raw_pins(&self) -> u8 {
+        self.pin_masks()
+            .iter()
+            .filter(|pin| self.read_pin(pin))
+            .fold(0, |result, pin| result | pin.to_u8())
+    }
+
+    pub fn read_pin(&self, pin_mask: &PinMask) -> bool {
         if self.is_pin_output(pin_mask) {
-            self.write_output_pin(pin_mask, high);
+            self.read_output_pin(pin_mask)
+        } else {
+            self.read_input_pin(pin_mask)
         }
     }
 
-    fn write_output_pin(&mut self, pin_mask: &PinMask, high: bool) {
-        if high {
-            match pin_mask {
-                PinMask::P0 => self.p0.set_high(),
-                PinMask::P1 => self.p1.set_high(),
-                PinMask::P2 => self.p2.set_high(),
-                PinMask::P3 => self.p3.set_high(),
-                PinMask::P4 => self.p4.set_high(),
-                PinMask::P5 => self.p5.set_high(),
-                PinMask::P6 => self.p6.set_high(),
-                PinMask::P7 => self.p7.set_high(),
-            }
+    fn read_output_pin(&self, pin_mask: &PinMask) -> bool {
+        // Open drain outputs can be pulled low externally, so report the actual line level
+        if self.is_pin_open_drain(pin_mask) {
+            self.read_input_pin(pin_mask)
         } else {
-            match pin_mask {
-                PinMask::P0 => self.p0.set_low(),
-                PinMask::P1 => self.p1.set_low(),
-                PinMask::P2 => self.p2.set_low(),
-                PinMask::P3 => self.p3.set_low(),
-                PinMask::P4 => self.p4.set_low(),
-                PinMask::P5 => self.p5.set_low(),
-                PinMask::P6 => self.p6.set_low(),
-                PinMask::P7 => self.p7.set_low(),
+            self.read_output_latch(pin_mask)
+        }
+    }
+
+    /// Read the output latches (OLAT), the levels the pins drive or will drive once they're
+    /// outputs. The latch is the SIO output register, so it also follows pulses and patterns.
+    pub fn read_output_latches(&self) -> u8 {
+        self.pin_masks()
+            .iter()
+            .filter(|pin| self.read_output_latch(pin))
+            .fold(0, |result, pin| result | pin.to_u8())
+    }
+
+    fn read_output_latch(&self, pin_mask: &PinMask) -> bool {
+        self.pin(pin_mask).is_some_and(|pin| pin.is_set_high())
+    }
+
+    fn read_input_pin(&self, pin_mask: &PinMask) -> bool {
+        self.pin(pin_mask).is_some_and(|pin| pin.is_high())
+    }
+}
+
+pub mod config {
+    use super::*;
+
+    impl PinGroup {
+        pub fn config(&self) -> GroupConfig {
+            GroupConfig {
+                pin_modes: self.pin_modes,
+                pull_ups: self.pull_ups,
+                pull_downs: self.pull_downs,
+                polarity: self.polarity,
+                open_drain: self.open_drain,
+                drive_strength: self.drive_strength,
+                slew_fast: self.slew_fast,
+                schmitt: self.schmitt,
             }
         }
+
+        pub fn apply_config(&mut self, config: &GroupConfig) {
+            self.set_pin_polarity(config.polarity);
+            self.set_pin_open_drain(config.open_drain);
+            // Setting a pin as an input resets its pull, so pulls have to be applied after modes
+            self.set_pin_modes(config.pin_modes);
+            self.set_pin_pulls(config.pull_ups, Pull::Up);
+            self.set_pin_pulls(config.pull_downs, Pull::Down);
+            self.set_pin_pulls(!(config.pull_ups | config.pull_downs), Pull::None);
+            self.set_packed_drive_strengths(config.drive_strength);
+            self.set_pin_slew_rates(config.slew_fast);
+            self.set_pin_schmitt(config.schmitt);
+        }
     }
+}
 
-    pub fn read_pins(&self) -> u8 {
-        let mut result = 0;
+pub mod interrupts {
+    use super::*;
+
+    impl PinGroup {
+        /// Flag the masked pins as having asserted INT_OUT
+        pub fn flag_interrupts(&mut self, bits: u8) {
+            self.int_flags |= bits;
+        }
+
+        pub fn get_int_flags(&self) -> u8 {
+            self.int_flags
+        }
+
+        /// Returns the pins flagged since the last call, clearing them
+        pub fn take_int_flags(&mut self) -> u8 {
+            core::mem::take(&mut self.int_flags)
+        }
+    }
+}
 
-        if self.read_pin(&PinMask::P0) {
-            result |= PinMask::P0.to_u8();
+pub mod counters {
+    use super::*;
+
+    impl PinGroup {
+        /// Count rising edges on the masked input pins, in the GPIO interrupt, see
+        /// [`crate::edges`]
+        pub fn set_count_rising(&mut self, bits: u8) {
+            self.count_rising = bits;
+            self.publish_counted();
         }
-        if self.read_pin(&PinMask::P1) {
-            result |= PinMask::P1.to_u8();
+
+        pub fn get_count_rising(&self) -> u8 {
+            self.count_rising
         }
-        if self.read_pin(&PinMask::P2) {
-            result |= PinMask::P2.to_u8();
+
+        /// Count falling edges on the masked input pins, pins counting both rising and falling
+        /// edges count every change
+        pub fn set_count_falling(&mut self, bits: u8) {
+            self.count_falling = bits;
+            self.publish_counted();
         }
-        if self.read_pin(&PinMask::P3) {
-            result |= PinMask::P3.to_u8();
+
+        fn publish_counted(&self) {
+            edges::set_counted(
+                self.gpio_mask(0xFF),
+                self.gpio_mask(self.count_rising),
+                self.gpio_mask(self.count_falling),
+            );
         }
-        if self.read_pin(&PinMask::P4) {
-            result |= PinMask::P4.to_u8();
+
+        pub fn get_count_falling(&self) -> u8 {
+            self.count_falling
         }
-        if self.read_pin(&PinMask::P5) {
-            result |= PinMask::P5.to_u8();
+
+        pub fn counts_edges(&self) -> bool {
+            (self.count_rising | self.count_falling) & !self.pin_modes != 0
         }
-        if self.read_pin(&PinMask::P6) {
-            result |= PinMask::P6.to_u8();
+
+        /// Mask of the pins whose edges don't trigger INT_OUT, because they're counted or part
+        /// of an encoder
+        pub fn quiet_pins(&self) -> u8 {
+            let counted = if self.counts_edges() {
+                self.count_rising | self.count_falling
+            } else {
+                0
+            };
+            counted | self.encoder.as_ref().map_or(0, Encoder::pins)
         }
-        if self.read_pin(&PinMask::P7) {
-            result |= PinMask::P7.to_u8();
+
+        /// Returns and resets the count of the pin at `index`
+        pub fn take_count(&mut self, index: u8) -> Option<u32> {
+            Some(edges::take_count(self.gpio(index)?))
         }
 
-        result
+        /// Reset the counts of the masked pins
+        pub fn clear_counts(&mut self, bits: u8) {
+            edges::clear_counts(self.gpio_mask(bits));
+        }
     }
+}
 
-    pub fn read_pin(&self, pin_mask: &PinMask) -> bool {
-        if self.is_pin_output(pin_mask) {
-            self.read_output_pin(pin_mask)
-        } else {
-            self.read_input_pin(pin_mask)
-        }
+pub mod encoder {
+    use super::*;
+    use defmt::Format;
+
+    /// A quadrature encoder on two pins of a [`PinGroup`], decoded on every state change (x4)
+    /// by the GPIO interrupt, see [`crate::edges`]
+    #[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+    pub struct Encoder {
+        pub a: u8,
+        pub b: u8,
+        /// Resets the position to 0 on its rising edge
+        pub index: Option<u8>,
+        /// Assert INT_OUT whenever the position changes
+        pub trigger_int_out: bool,
     }
 
-    fn read_output_pin(&self, pin_mask: &PinMask) -> bool {
-        match pin_mask {
-            PinMask::P0 => self.p0.is_set_high(),
-            PinMask::P1 => self.p1.is_set_high(),
-            PinMask::P2 => self.p2.is_set_high(),
-            PinMask::P3 => self.p3.is_set_high(),
-            PinMask::P4 => self.p4.is_set_high(),
-            PinMask::P5 => self.p5.is_set_high(),
-            PinMask::P6 => self.p6.is_set_high(),
-            PinMask::P7 => self.p7.is_set_high(),
+    impl Encoder {
+        pub const fn new(a: u8, b: u8, index: Option<u8>, trigger_int_out: bool) -> Self {
+            Self {
+                a,
+                b,
+                index,
+                trigger_int_out,
+            }
+        }
+
+        /// Mask of the pins used by the encoder
+        pub fn pins(&self) -> u8 {
+            let index = self.index.map_or(0, |index| 1 << index);
+            1 << self.a | 1 << self.b | index
         }
     }
 
-    fn read_input_pin(&self, pin_mask: &PinMask) -> bool {
-        match pin_mask {
-            PinMask::P0 => self.p0.is_high(),
-            PinMask::P1 => self.p1.is_high(),
-            PinMask::P2 => self.p2.is_high(),
-            PinMask::P3 => self.p3.is_high(),
-            PinMask::P4 => self.p4.is_high(),
-            PinMask::P5 => self.p5.is_high(),
-            PinMask::P6 => self.p6.is_high(),
-            PinMask::P7 => self.p7.is_high(),
+    #[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+    pub enum Error {
+        /// The pin doesn't exist or is used twice
+        InvalidPin(u8),
+        NotAnInput(u8),
+    }
+
+    impl PinGroup {
+        /// Check that the pins of `encoder` exist, are all different and are inputs in
+        /// `pin_modes`, returning the first one that isn't
+        pub fn check_encoder(&self, encoder: &Encoder, pin_modes: u8) -> Result<(), Error> {
+            let mut used = 0u8;
+            for pin in [Some(encoder.a), Some(encoder.b), encoder.index]
+                .into_iter()
+                .flatten()
+            {
+                if pin as usize >= self.len() || used & 1 << pin != 0 {
+                    return Err(Error::InvalidPin(pin));
+                }
+                if pin_modes & 1 << pin != 0 {
+                    return Err(Error::NotAnInput(pin));
+                }
+                used |= 1 << pin;
+            }
+            Ok(())
+        }
+
+        /// Decode the pins of `encoder` as a quadrature encoder from position 0, replacing any
+        /// existing one
+        pub fn set_encoder(&mut self, encoder: Option<Encoder>) -> Result<(), Error> {
+            if let Some(encoder) = &encoder {
+                self.check_encoder(encoder, self.pin_modes)?;
+            }
+            let gpio = |pin: u8| self.gpios[pin as usize];
+            edges::set_encoder(
+                self.gpio_mask(0xFF),
+                encoder.map(|encoder| (gpio(encoder.a), gpio(encoder.b), encoder.index.map(gpio))),
+            );
+            self.encoder = encoder;
+            Ok(())
+        }
+
+        pub fn encoder(&self) -> Option<&Encoder> {
+            self.encoder.as_ref()
+        }
+
+        /// Position of the encoder, decoded by the GPIO interrupt
+        pub fn encoder_position(&self) -> Option<i32> {
+            edges::position(self.gpios[self.encoder?.a as usize])
+        }
+
+        pub fn reset_encoder(&mut self) {
+            if let Some(encoder) = self.encoder {
+                edges::reset_position(self.gpios[encoder.a as usize]);
+            }
+        }
+
+        /// Whether the encoder is among those that moved in `moved`, see [`edges::take_moved`]
+        pub fn encoder_moved(&self, moved: u32) -> bool {
+            self.encoder
+                .is_some_and(|encoder| moved & 1 << self.gpios[encoder.a as usize] != 0)
         }
     }
 }
 
-pub mod interrupts {
-    use crate::SET_INT_OUT;
-
+pub mod measure {
     use super::*;
-    use embassy_futures::select::{select, select4};
-
-    impl<P0: Pin, P1: Pin, P2: Pin, P3: Pin, P4: Pin, P5: Pin, P6: Pin, P7: Pin>
-        PinGroup<P0, P1, P2, P3, P4, P5, P6, P7, IntOutTrigger>
-    {
-        pub async fn wait_for_any_edge(&mut self) {
-            select(
-                select4(
-                    self.p0.wait_for_any_edge(),
-                    self.p1.wait_for_any_edge(),
-                    self.p2.wait_for_any_edge(),
-                    self.p3.wait_for_any_edge(),
-                ),
-                select4(
-                    self.p4.wait_for_any_edge(),
-                    self.p5.wait_for_any_edge(),
-                    self.p6.wait_for_any_edge(),
-                    self.p7.wait_for_any_edge(),
-                ),
-            )
-            .await;
-        }
-
-        pub fn clear_int_out(&self) {
-            SET_INT_OUT.signal(false);
+    use crate::measure;
+
+    impl PinGroup {
+        /// Stop measuring the pins that aren't in `bits`, freeing their slices
+        pub fn release_measured(&mut self, bits: u8) {
+            for pin in self.pin_masks() {
+                if !pin.is_in_mask(bits) {
+                    measure::disable(self.gpios[pin.index()]);
+                    self.measured &= !pin.to_u8();
+                }
+            }
+        }
+
+        /// Measure the frequency and pulse widths of the masked pins, see [`crate::measure`].
+        /// The pins should be checked with [`measure::check`] first, otherwise this returns the
+        /// GPIO number of the first pin that can't be measured and the pins before it are still
+        /// updated.
+        pub fn set_pin_measured(&mut self, bits: u8) -> Result<(), u8> {
+            self.release_measured(bits);
+            for pin in self.pin_masks() {
+                let gpio = self.gpios[pin.index()];
+                if pin.is_in_mask(bits) {
+                    if !measure::enable(gpio) {
+                        return Err(gpio);
+                    }
+                    self.measured |= pin.to_u8();
+                }
+            }
+            Ok(())
+        }
+
+        pub fn get_pin_measured(&self) -> u8 {
+            self.measured
         }
     }
 }
@@ -293,28 +660,190 @@ pub mod interrupts {
 pub mod pull {
     use super::*;
 
-    impl<P0: Pin, P1: Pin, P2: Pin, P3: Pin, P4: Pin, P5: Pin, P6: Pin, P7: Pin, IntOut>
-        PinGroup<P0, P1, P2, P3, P4, P5, P6, P7, IntOut>
-    {
+    impl PinGroup {
         pub fn set_pin_pulls(&mut self, bytes: u8, pull: Pull) {
-            for pin in PinMask::ARR.iter() {
+            for pin in self.pin_masks() {
                 if pin.is_in_mask(bytes) {
                     self.set_pin_pull(pin, pull);
                 }
             }
         }
 
-        fn set_pin_pull(&mut self, pin_mask: &PinMask, pull: Pull) {
-            match pin_mask {
-                PinMask::P0 => self.p0.set_pull(pull),
-                PinMask::P1 => self.p1.set_pull(pull),
-                PinMask::P2 => self.p2.set_pull(pull),
-                PinMask::P3 => self.p3.set_pull(pull),
-                PinMask::P4 => self.p4.set_pull(pull),
-                PinMask::P5 => self.p5.set_pull(pull),
-                PinMask::P6 => self.p6.set_pull(pull),
-                PinMask::P7 => self.p7.set_pull(pull),
+        /// Returns the masks of pins with pull ups and pull downs enabled respectively
+        pub fn get_pin_pulls(&self) -> (u8, u8) {
+            (self.pull_ups, self.pull_downs)
+        }
+
+        pub(super) fn set_pin_pull(&mut self, pin_mask: &PinMask, pull: Pull) {
+            let mask = pin_mask.to_u8();
+            self.pull_ups &= !mask;
+            self.pull_downs &= !mask;
+            match pull {
+                Pull::Up => self.pull_ups |= mask,
+                Pull::Down => self.pull_downs |= mask,
+                Pull::None => {}
+            }
+            if let Some(pin) = self.pin_mut(pin_mask) {
+                pin.set_pull(pull);
+            }
+        }
+    }
+}
+
+pub mod pad {
+    use super::*;
+    use defmt::Format;
+
+    /// Output drive strength of a pin, the discriminant is the value used over I2C
+    #[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+    #[repr(u8)]
+    pub enum DriveStrength {
+        _2mA = 0,
+        _4mA = 1,
+        _8mA = 2,
+        _12mA = 3,
+    }
+
+    impl DriveStrength {
+        pub const fn from_u8(value: u8) -> Option<Self> {
+            match value {
+                0 => Some(Self::_2mA),
+                1 => Some(Self::_4mA),
+                2 => Some(Self::_8mA),
+                3 => Some(Self::_12mA),
+                _ => None,
+            }
+        }
+
+        fn to_drive(self) -> Drive {
+            match self {
+                Self::_2mA => Drive::_2mA,
+                Self::_4mA => Drive::_4mA,
+                Self::_8mA => Drive::_8mA,
+                Self::_12mA => Drive::_12mA,
+            }
+        }
+    }
+
+    impl PinGroup {
+        pub fn set_pin_drive_strengths(&mut self, bits: u8, strength: DriveStrength) {
+            for pin in self.pin_masks() {
+                if pin.is_in_mask(bits) {
+                    let shift = pin.index() * 2;
+                    self.drive_strength &= !(0b11 << shift);
+                    self.drive_strength |= (strength as u16) << shift;
+                    if let Some(pin) = self.pin_mut(pin) {
+                        pin.set_drive_strength(strength.to_drive());
+                    }
+                }
+            }
+        }
+
+        /// Returns the drive strength of each pin, packed as 2 bits per pin with P0 in the
+        /// least significant bits
+        pub fn get_pin_drive_strengths(&self) -> u16 {
+            self.drive_strength
+        }
+
+        /// Restore drive strengths packed as returned by [`Self::get_pin_drive_strengths`]
+        pub fn set_packed_drive_strengths(&mut self, packed: u16) {
+            for pin in self.pin_masks() {
+                let code = ((packed >> (pin.index() * 2)) & 0b11) as u8;
+                if let Some(strength) = DriveStrength::from_u8(code) {
+                    self.set_pin_drive_strengths(pin.to_u8(), strength);
+                }
+            }
+        }
+
+        /// Set bits select a fast slew rate, unset bits a slow slew rate
+        pub fn set_pin_slew_rates(&mut self, bits: u8) {
+            for pin_mask in self.pin_masks() {
+                let slew_rate = if pin_mask.is_in_mask(bits) {
+                    SlewRate::Fast
+                } else {
+                    SlewRate::Slow
+                };
+                if let Some(pin) = self.pin_mut(pin_mask) {
+                    pin.set_slew_rate(slew_rate);
+                }
+            }
+            self.slew_fast = bits;
+        }
+
+        pub fn get_pin_slew_rates(&self) -> u8 {
+            self.slew_fast
+        }
+
+        /// Set bits enable the Schmitt trigger on that pin's input
+        pub fn set_pin_schmitt(&mut self, bits: u8) {
+            for pin_mask in self.pin_masks() {
+                if let Some(pin) = self.pin_mut(pin_mask) {
+                    pin.set_schmitt(pin_mask.is_in_mask(bits));
+                }
+            }
+            self.schmitt = bits;
+        }
+
+        pub fn get_pin_schmitt(&self) -> u8 {
+            self.schmitt
+        }
+    }
+}
+
+pub mod staging {
+    use super::*;
+    use defmt::Format;
+
+    /// Settings waiting to be applied to a [`PinGroup`] together, unset fields are left as they
+    /// are. The pulls are whole registers, a pin in both gets a pull up.
+    #[derive(Debug, Clone, Copy, Default, Format, Eq, PartialEq)]
+    pub struct StagedGroup {
+        pub modes: Option<u8>,
+        pub pull_ups: Option<u8>,
+        pub pull_downs: Option<u8>,
+        pub outputs: Option<u8>,
+        pub polarity: Option<u8>,
+    }
+
+    impl StagedGroup {
+        pub fn is_empty(&self) -> bool {
+            *self == Self::default()
+        }
+    }
+
+    impl PinGroup {
+        /// Apply `staged` without glitches: the output latches and pulls are set before any pin
+        /// changes direction, so a new output drives its staged level straight away and a new
+        /// input is never left floating or pulled the wrong way
+        pub fn apply_staged(&mut self, staged: &StagedGroup) {
+            let modes = staged.modes.unwrap_or(self.pin_modes);
+            let pull_ups = staged.pull_ups.unwrap_or(self.pull_ups);
+            let pull_downs = staged.pull_downs.unwrap_or(self.pull_downs) & !pull_ups;
+            if let Some(polarity) = staged.polarity {
+                self.set_pin_polarity(polarity);
+            }
+            for pin in self.pin_masks() {
+                if let Some(outputs) = staged.outputs {
+                    self.write_output_latch(pin, pin.is_in_mask(outputs));
+                }
+                let pull = if pin.is_in_mask(pull_ups) {
+                    Pull::Up
+                } else if pin.is_in_mask(pull_downs) {
+                    Pull::Down
+                } else {
+                    Pull::None
+                };
+                self.set_pin_pull(pin, pull);
+            }
+            // Unlike `set_pin_input`, this keeps the pulls that were just set
+            for pin in self.pin_masks() {
+                if pin.is_in_mask(modes) {
+                    self.set_pin_output(pin);
+                } else {
+                    self.release_output(pin);
+                }
             }
+            self.pin_modes = modes;
         }
     }
 }
@@ -3,7 +3,6 @@
 
 use cortex_m_semihosting::debug;
 use embassy_executor::{Executor, InterruptExecutor};
-use embassy_rp::peripherals::{PIN_2, PIN_25, PIN_26};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::signal::Signal;
 use static_cell::StaticCell;
@@ -11,32 +10,57 @@ use static_cell::StaticCell;
 #[allow(unused_imports)]
 use {defmt_rtt as _, panic_probe as _};
 
+pub mod analog;
+pub mod board;
 pub mod commands;
+pub mod config;
+pub mod console;
 pub mod device;
+pub mod edges;
 pub mod gpios;
+pub mod measure;
+pub mod pattern;
+pub mod rules;
+pub mod servo;
+#[cfg(spi_transport)]
+pub mod spi;
+pub mod stepper;
 pub mod tasks;
+pub mod timed;
+pub mod transport;
+pub mod uart;
+pub mod ws2812;
+
+pub use board::{DEFAULT_PIN_MODES, GROUPS, P_EN_OUT, P_INT_OUT, P_LED, P_WS2812};
 
 pub static SET_INT_OUT: Signal<CriticalSectionRawMutex, bool> = Signal::new();
-#[allow(non_camel_case_types)]
-pub type P_INT_OUT = PIN_26;
-#[allow(non_camel_case_types)]
-pub type P_LED = PIN_25;
-#[allow(non_camel_case_types)]
-pub type P_EN_OUT = PIN_2;
 
 pub const ADDRESS: u8 = 0x20;
-pub const DEFAULT_PIN_MODES: [u8; 2] = [0b0000_0000, 0b1111_0000];
 pub static EXECUTOR_HIGH: InterruptExecutor = InterruptExecutor::new();
 pub static EXECUTOR: StaticCell<Executor> = StaticCell::new();
 pub static LED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 pub mod prelude {
+    pub use crate::analog;
+    pub use crate::board;
     pub use crate::commands;
+    pub use crate::config;
+    pub use crate::console;
     pub use crate::device;
+    pub use crate::edges;
     pub use crate::gpios;
+    pub use crate::measure;
+    pub use crate::pattern;
+    pub use crate::rules;
+    pub use crate::servo;
+    pub use crate::stepper;
     pub use crate::tasks;
-    pub use crate::{ADDRESS, DEFAULT_PIN_MODES, EXECUTOR, EXECUTOR_HIGH, LED};
-    pub use crate::{P_EN_OUT, P_INT_OUT, P_LED, SET_INT_OUT};
+    pub use crate::timed;
+    pub use crate::transport;
+    pub use crate::uart;
+    pub use crate::ws2812;
+    pub use crate::{ADDRESS, DEFAULT_PIN_MODES, EXECUTOR, EXECUTOR_HIGH, GROUPS, LED};
+    pub use crate::{P_EN_OUT, P_INT_OUT, P_LED, P_WS2812, SET_INT_OUT};
     pub use defmt::*;
 }
 
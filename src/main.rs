@@ -5,71 +5,124 @@ use defmt::*;
 use device::Device;
 use embassy_executor::Executor;
 
+#[cfg(spi_transport)]
+use embassy_rp::gpio::Pin;
 use embassy_rp::gpio::{Level, Output};
 
 use embassy_rp::interrupt::{InterruptExt, Priority};
-use embassy_rp::peripherals::I2C0;
-use embassy_rp::{bind_interrupts, i2c, i2c_slave, interrupt};
+use embassy_rp::peripherals::{I2C0, PIO0, UART0, USB};
+use embassy_rp::pio::Pio;
+use embassy_rp::uart::{BufferedInterruptHandler, BufferedUart};
+use embassy_rp::{adc, bind_interrupts, i2c, i2c_slave, interrupt, pio, usb};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use static_cell::StaticCell;
 
-use gpios::{PinGroup0, PinGroup1};
 use rp_2040_gpio_expander::prelude::*;
 #[allow(unused_imports)]
 use {defmt_rtt as _, panic_probe as _};
 
 bind_interrupts!(struct Irqs {
     I2C0_IRQ => i2c::InterruptHandler<I2C0>;
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+    PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
+    UART0_IRQ => BufferedInterruptHandler<UART0>;
+    USBCTRL_IRQ => usb::InterruptHandler<USB>;
 });
 
 #[cortex_m_rt::entry]
 fn main() -> ! {
     let peripherals = embassy_rp::init(Default::default());
+    edges::install();
 
     // interrupt::I2C0_IRQ.set_priority(Priority::P1);
     interrupt::SWI_IRQ_0.set_priority(Priority::P2);
 
     let high_spawner = EXECUTOR_HIGH.start(interrupt::SWI_IRQ_0);
 
-    let en_out: P_EN_OUT = peripherals.PIN_2;
-    unwrap!(high_spawner.spawn(tasks::trigger_en_out(en_out)));
+    let board = rp_2040_gpio_expander::board_pins!(peripherals);
 
-    // let int_out: P_INT_OUT = peripherals.PIN_26;
-    // unwrap!(high_spawner.spawn(tasks::trigger_int_out(int_out)));
+    unwrap!(high_spawner.spawn(tasks::trigger_en_out(board.en_out)));
+    unwrap!(high_spawner.spawn(tasks::timed_output_task()));
+    unwrap!(high_spawner.spawn(tasks::pattern_task()));
+    unwrap!(high_spawner.spawn(tasks::stepper_task()));
 
-    let executor = EXECUTOR.init(Executor::new());
-    let led = Output::new(peripherals.PIN_25, Level::Low);
+    // unwrap!(high_spawner.spawn(tasks::trigger_int_out(board.int_out)));
 
-    let sda = peripherals.PIN_4;
-    let scl = peripherals.PIN_5;
+    let executor = EXECUTOR.init(Executor::new());
+    let led = Output::new(board.led, Level::Low);
 
     let mut config = i2c_slave::Config::default();
     config.addr = ADDRESS as u16;
-    let slave = i2c_slave::I2cSlave::new(peripherals.I2C0, scl, sda, Irqs, config);
-    let gpio_group_0 = PinGroup0::new(
-        peripherals.PIN_6,
-        peripherals.PIN_7,
-        peripherals.PIN_8,
-        peripherals.PIN_9,
-        peripherals.PIN_10,
-        peripherals.PIN_11,
-        peripherals.PIN_12,
-        peripherals.PIN_13,
+    let slave = i2c_slave::I2cSlave::new(peripherals.I2C0, board.scl, board.sda, Irqs, config);
+    let adc = adc::Adc::new(peripherals.ADC, Irqs, adc::Config::default());
+    let temp_sensor = adc::Channel::new_temp_sensor(peripherals.ADC_TEMP_SENSOR);
+    let Pio {
+        mut common, sm0, ..
+    } = Pio::new(peripherals.PIO0, Irqs);
+    let strip = ws2812::Ws2812::new(&mut common, sm0, peripherals.DMA_CH0, board.ws2812);
+
+    static UART_TX_BUF: StaticCell<[u8; uart::FIFO_SIZE]> = StaticCell::new();
+    static UART_RX_BUF: StaticCell<[u8; uart::FIFO_SIZE]> = StaticCell::new();
+    let mut uart_config = embassy_rp::uart::Config::default();
+    uart_config.baudrate = uart::DEFAULT_BAUD;
+    let serial = BufferedUart::new(
+        peripherals.UART0,
+        Irqs,
+        board.uart_tx,
+        board.uart_rx,
+        UART_TX_BUF.init([0; uart::FIFO_SIZE]),
+        UART_RX_BUF.init([0; uart::FIFO_SIZE]),
+        uart_config,
     );
-    let gpio_group_1 = PinGroup1::new(
-        peripherals.PIN_14,
-        peripherals.PIN_15,
-        peripherals.PIN_16,
-        peripherals.PIN_17,
-        peripherals.PIN_18,
-        peripherals.PIN_19,
-        peripherals.PIN_20,
-        peripherals.PIN_21,
+
+    // pid.codes test VID/PID
+    let mut usb_config = embassy_usb::Config::new(0x1209, 0x0001);
+    usb_config.manufacturer = Some("rp-2040-gpio-expander");
+    usb_config.product = Some("GPIO expander console");
+    usb_config.max_packet_size_0 = console::PACKET_SIZE as u8;
+    static DEVICE_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static CDC_STATE: StaticCell<State> = StaticCell::new();
+    let mut builder = embassy_usb::Builder::new(
+        usb::Driver::new(peripherals.USB, Irqs),
+        usb_config,
+        DEVICE_DESCRIPTOR.init([0; 256]),
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+    let console = CdcAcmClass::new(
+        &mut builder,
+        CDC_STATE.init(State::new()),
+        console::PACKET_SIZE as u16,
     );
-    let device = Device::new(gpio_group_0, gpio_group_1);
-    let int_out: P_INT_OUT = peripherals.PIN_26;
+    let usb = builder.build();
+
+    let mut device = Device::new(board.groups);
+    device.attach_storage(config::Storage::new(peripherals.FLASH));
 
     executor.run(|spawner| {
         unwrap!(spawner.spawn(tasks::led_task(led)));
-        unwrap!(spawner.spawn(tasks::i2c_task(slave, device)));
-        unwrap!(spawner.spawn(tasks::trigger_int_out(int_out)));
+        unwrap!(spawner.spawn(tasks::device_task(device)));
+        unwrap!(spawner.spawn(tasks::i2c_task(slave)));
+        #[cfg(spi_transport)]
+        unwrap!(spawner.spawn(tasks::spi_task(
+            board.spi_sck.degrade(),
+            board.spi_mosi.degrade(),
+            board.spi_miso.degrade(),
+            board.spi_cs.degrade(),
+            [peripherals.DMA_CH1.into(), peripherals.DMA_CH2.into()],
+        )));
+        unwrap!(spawner.spawn(tasks::trigger_int_out(board.int_out)));
+        unwrap!(spawner.spawn(tasks::adc_task(adc, board.adc_channels, temp_sensor)));
+        unwrap!(spawner.spawn(tasks::measure_task()));
+        unwrap!(spawner.spawn(tasks::servo_task()));
+        unwrap!(spawner.spawn(tasks::ws2812_task(strip)));
+        unwrap!(spawner.spawn(tasks::uart_task(serial)));
+        unwrap!(spawner.spawn(tasks::usb_task(usb)));
+        unwrap!(spawner.spawn(tasks::console_task(console)));
     })
 }
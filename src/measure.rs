@@ -0,0 +1,265 @@
+//! Frequency and pulse width measurement of input pins, measured in the background by
+//! [`crate::tasks::measure_task`] using the input modes of the PWM slices.
+//!
+//! Only a slice's B input can be measured, which is every odd GPIO, and GPIO `n` uses slice
+//! `(n >> 1) & 7`, so each slice can only measure one of the two pins that share it.
+//!
+//! Each gate time is spent counting rising edges, which gives the frequency and period, followed
+//! by another counting the time the pin is high, which gives the high and low pulse widths. While
+//! a pin is being measured it's connected to its PWM slice, so it can only be used as an input.
+
+use core::cell::RefCell;
+
+use defmt::Format;
+use embassy_rp::pac;
+use embassy_rp::pac::pwm::vals::Divmode;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicU16, AtomicU8, Ordering};
+
+use crate::servo;
+
+pub const SLICES: usize = 8;
+pub const DEFAULT_GATE_MS: u16 = 100;
+/// The counters are 16-bit, so they're accumulated at least this often to not miss a wrap
+const POLL_MS: u64 = 10;
+/// Rate the slices count at while measuring the high time
+const TICK_HZ: u32 = 1_000_000;
+const NO_GPIO: u8 = 0xFF;
+pub(crate) const FUNCSEL_PWM: u8 = 4;
+pub(crate) const FUNCSEL_SIO: u8 = 5;
+
+/// GPIO measured by each slice, or [`NO_GPIO`]
+static GPIOS: [AtomicU8; SLICES] = [
+    AtomicU8::new(NO_GPIO),
+    AtomicU8::new(NO_GPIO),
+    AtomicU8::new(NO_GPIO),
+    AtomicU8::new(NO_GPIO),
+    AtomicU8::new(NO_GPIO),
+    AtomicU8::new(NO_GPIO),
+    AtomicU8::new(NO_GPIO),
+    AtomicU8::new(NO_GPIO),
+];
+static GATE_MS: AtomicU16 = AtomicU16::new(DEFAULT_GATE_MS);
+static MEASUREMENTS: Mutex<CriticalSectionRawMutex, RefCell<[Measurement; SLICES]>> =
+    Mutex::new(RefCell::new([Measurement::NONE; SLICES]));
+
+/// What to read from a [`Measurement`]
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Quantity {
+    /// In Hz
+    Frequency = 0,
+    /// In µs
+    Period = 1,
+    /// Average high pulse width in µs
+    HighTime = 2,
+    /// Average low pulse width in µs
+    LowTime = 3,
+}
+
+impl Quantity {
+    pub const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Frequency),
+            1 => Some(Self::Period),
+            2 => Some(Self::HighTime),
+            3 => Some(Self::LowTime),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub struct Measurement {
+    pub frequency: u32,
+    pub period_us: u32,
+    pub high_us: u32,
+    pub low_us: u32,
+}
+
+impl Measurement {
+    pub const NONE: Self = Self {
+        frequency: 0,
+        period_us: 0,
+        high_us: 0,
+        low_us: 0,
+    };
+
+    /// Calculate a measurement from the rising `edges` counted in `edges_us`, and the `high_us`
+    /// counted in `level_us`. A pin without edges measures as all zeros.
+    pub fn from_counts(edges: u32, edges_us: u32, high_us: u32, level_us: u32) -> Self {
+        if edges == 0 || edges_us == 0 || level_us == 0 {
+            return Self::NONE;
+        }
+        let (edges, edges_us) = (edges as u64, edges_us as u64);
+        let period_us = edges_us / edges;
+        // The edges were counted in a different gate, so scale them to the level gate
+        let high_us = (high_us as u64 * edges_us / (edges * level_us as u64)).min(period_us);
+        Self {
+            frequency: (edges * 1_000_000 / edges_us) as u32,
+            period_us: period_us as u32,
+            high_us: high_us as u32,
+            low_us: (period_us - high_us) as u32,
+        }
+    }
+
+    pub fn get(&self, quantity: Quantity) -> u32 {
+        match quantity {
+            Quantity::Frequency => self.frequency,
+            Quantity::Period => self.period_us,
+            Quantity::HighTime => self.high_us,
+            Quantity::LowTime => self.low_us,
+        }
+    }
+}
+
+/// The slice that can measure `gpio`, if any
+pub const fn slice(gpio: u8) -> Option<usize> {
+    if gpio < 30 && gpio % 2 == 1 {
+        Some(((gpio >> 1) & 7) as usize)
+    } else {
+        None
+    }
+}
+
+pub fn gate_ms() -> u16 {
+    GATE_MS.load(Ordering::Relaxed)
+}
+
+/// Set the time spent on each half of a measurement, 0 is treated as 1
+pub fn set_gate_ms(gate_ms: u16) {
+    GATE_MS.store(gate_ms.max(1), Ordering::Relaxed);
+}
+
+/// Whether `slice` is measuring a pin
+pub fn uses_slice(slice: usize) -> bool {
+    GPIOS[slice].load(Ordering::Relaxed) != NO_GPIO
+}
+
+/// Start measuring `gpio`. Returns `false` if it can't be measured, or its slice is already
+/// measuring the other pin or driving servos.
+pub fn enable(gpio: u8) -> bool {
+    let Some(slice) = slice(gpio) else {
+        return false;
+    };
+    if servo::uses_slice(slice) {
+        return false;
+    }
+    let claimed =
+        GPIOS[slice].compare_exchange(NO_GPIO, gpio, Ordering::Relaxed, Ordering::Relaxed);
+    claimed.is_ok() || claimed == Err(gpio)
+}
+
+/// Check that every GPIO in the `gpios` mask can be measured at once, once those in `released`
+/// have stopped being measured. Returns the first one that can't be.
+pub fn check(gpios: u32, released: u32) -> Result<(), u8> {
+    let mut slices = 0u8;
+    for gpio in (0..32).filter(|gpio| gpios & 1 << gpio != 0) {
+        let Some(slice) = slice(gpio) else {
+            return Err(gpio);
+        };
+        let measuring = GPIOS[slice].load(Ordering::Relaxed);
+        let free = measuring == NO_GPIO || measuring == gpio || released & 1 << measuring != 0;
+        if !free || servo::uses_slice(slice) || slices & 1 << slice != 0 {
+            return Err(gpio);
+        }
+        slices |= 1 << slice;
+    }
+    Ok(())
+}
+
+/// Stop measuring `gpio`, returning it to SIO so it can be driven again
+pub fn disable(gpio: u8) {
+    let Some(slice) = slice(gpio) else {
+        return;
+    };
+    if GPIOS[slice]
+        .compare_exchange(gpio, NO_GPIO, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+    {
+        pac::PWM.en().modify(|w| w.0 &= !(1 << slice));
+        set_funcsel(gpio, FUNCSEL_SIO);
+        MEASUREMENTS.lock(|measurements| measurements.borrow_mut()[slice] = Measurement::NONE);
+    }
+}
+
+pub fn is_enabled(gpio: u8) -> bool {
+    slice(gpio).is_some_and(|slice| GPIOS[slice].load(Ordering::Relaxed) == gpio)
+}
+
+/// Latest measurement of `gpio`, or `None` if it isn't being measured
+pub fn measurement(gpio: u8) -> Option<Measurement> {
+    let slice = slice(gpio).filter(|_| is_enabled(gpio))?;
+    Some(MEASUREMENTS.lock(|measurements| measurements.borrow()[slice]))
+}
+
+pub(crate) fn set_funcsel(gpio: u8, funcsel: u8) {
+    pac::IO_BANK0
+        .gpio(gpio as usize)
+        .ctrl()
+        .modify(|w| w.set_funcsel(funcsel));
+}
+
+/// Count on every enabled slice in `divmode` for the gate time, returning the counts and how
+/// long they were counted for in µs
+async fn count(divmode: Divmode, divider: u8) -> ([u32; SLICES], [u8; SLICES], u32) {
+    let gpios: [u8; SLICES] = core::array::from_fn(|slice| GPIOS[slice].load(Ordering::Relaxed));
+    let mut mask = 0;
+    for (slice, &gpio) in gpios.iter().enumerate() {
+        if gpio == NO_GPIO {
+            continue;
+        }
+        let regs = pac::PWM.ch(slice);
+        regs.csr().write(|w| w.set_divmode(divmode));
+        regs.div().write(|w| w.set_int(divider));
+        regs.top().write(|w| w.set_top(u16::MAX));
+        regs.ctr().write(|w| w.set_ctr(0));
+        set_funcsel(gpio, FUNCSEL_PWM);
+        mask |= 1 << slice;
+    }
+
+    let mut totals = [0u32; SLICES];
+    let mut last = [0u16; SLICES];
+    let gate = Duration::from_millis(gate_ms() as u64);
+    let start = Instant::now();
+    pac::PWM.en().modify(|w| w.0 |= mask);
+    loop {
+        let remaining = gate.checked_sub(start.elapsed()).unwrap_or_default();
+        Timer::after(remaining.min(Duration::from_millis(POLL_MS))).await;
+        let done = start.elapsed() >= gate;
+        if done {
+            pac::PWM.en().modify(|w| w.0 &= !mask);
+        }
+        for (slice, (total, last)) in totals.iter_mut().zip(last.iter_mut()).enumerate() {
+            if mask & (1 << slice) != 0 {
+                let ctr = pac::PWM.ch(slice).ctr().read().ctr();
+                *total += ctr.wrapping_sub(*last) as u32;
+                *last = ctr;
+            }
+        }
+        if done {
+            return (totals, gpios, start.elapsed().as_micros() as u32);
+        }
+    }
+}
+
+/// Measure every enabled pin once, taking two gate times
+pub(crate) async fn measure() {
+    let (edges, edge_gpios, edges_us) = count(Divmode::RISE, 1).await;
+    let divider = (embassy_rp::clocks::clk_sys_freq() / TICK_HZ) as u8;
+    let (high_us, level_gpios, level_us) = count(Divmode::LEVEL, divider).await;
+
+    MEASUREMENTS.lock(|measurements| {
+        let mut measurements = measurements.borrow_mut();
+        for slice in 0..SLICES {
+            let gpio = GPIOS[slice].load(Ordering::Relaxed);
+            // Skip slices that were enabled or changed partway through
+            if gpio != NO_GPIO && edge_gpios[slice] == gpio && level_gpios[slice] == gpio {
+                measurements[slice] =
+                    Measurement::from_counts(edges[slice], edges_us, high_us[slice], level_us);
+            }
+        }
+    });
+}
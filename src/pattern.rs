@@ -0,0 +1,247 @@
+//! Output patterns, sequences of pin states that are played back autonomously by
+//! [`crate::tasks::pattern_task`] on the high priority executor.
+//!
+//! Each pattern drives its own mask of pins, which can't overlap with any other pattern's, so
+//! several can play independently. Pins are addressed by GPIO number here, [`crate::device`]
+//! translates group bytes to GPIO masks. While a pattern plays it overwrites its pins at every
+//! step, regardless of any writes to them in between. Pins that stop being outputs are masked
+//! out of the steps, see [`crate::gpios::drive_gpios`].
+
+use core::cell::RefCell;
+
+use defmt::Format;
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::gpios;
+
+pub const MAX_PATTERNS: usize = 4;
+pub const MAX_STEPS: usize = 16;
+
+static PATTERNS: Mutex<CriticalSectionRawMutex, RefCell<[Pattern; MAX_PATTERNS]>> =
+    Mutex::new(RefCell::new([Pattern::EMPTY; MAX_PATTERNS]));
+/// Wakes the task when a pattern starts
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub enum Error {
+    InvalidPattern(u8),
+    /// The pattern already has [`MAX_STEPS`] steps
+    Full,
+    /// The pattern has no steps to play
+    Empty,
+    /// The pins overlap with those of another pattern
+    Overlaps(u8),
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+#[repr(u8)]
+pub enum State {
+    Stopped = 0,
+    Playing = 1,
+    Paused = 2,
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+struct Step {
+    /// Level of each pin in the pattern's mask, by GPIO number
+    levels: u32,
+    duration: Duration,
+}
+
+#[derive(Debug, Clone, Format, Eq, PartialEq)]
+struct Pattern {
+    mask: u32,
+    steps: Vec<Step, MAX_STEPS>,
+    /// Number of times to play the steps, 0 repeats forever
+    loops: u8,
+    state: State,
+    step: usize,
+    loops_done: u8,
+    /// When the current step ends while playing, or how long it has left while paused
+    next: Instant,
+    remaining: Duration,
+}
+
+impl Pattern {
+    const EMPTY: Self = Self {
+        mask: 0,
+        steps: Vec::new(),
+        loops: 0,
+        state: State::Stopped,
+        step: 0,
+        loops_done: 0,
+        next: Instant::MIN,
+        remaining: Duration::MIN,
+    };
+
+    /// Drive the pins that are still outputs to the current step's levels
+    fn apply(&self) {
+        gpios::drive_gpios(self.mask, self.steps[self.step].levels);
+    }
+
+    /// Move on to the next step if the current one has ended
+    fn advance(&mut self, now: Instant) {
+        while self.state == State::Playing && self.next <= now {
+            if self.step + 1 < self.steps.len() {
+                self.step += 1;
+            } else {
+                self.loops_done = self.loops_done.saturating_add(1);
+                if self.loops != 0 && self.loops_done >= self.loops {
+                    // The last step's levels are left on the pins
+                    self.state = State::Stopped;
+                    return;
+                }
+                self.step = 0;
+            }
+            self.apply();
+            self.next += self.steps[self.step].duration;
+        }
+    }
+}
+
+fn with_pattern<R>(
+    pattern: u8,
+    f: impl FnOnce(&mut [Pattern; MAX_PATTERNS], usize) -> Result<R, Error>,
+) -> Result<R, Error> {
+    if pattern as usize >= MAX_PATTERNS {
+        return Err(Error::InvalidPattern(pattern));
+    }
+    PATTERNS.lock(|patterns| f(&mut patterns.borrow_mut(), pattern as usize))
+}
+
+/// Stop `pattern` and clear its steps, it will drive the pins in `mask` repeating `loops` times
+/// (0 repeats forever)
+pub fn define(pattern: u8, mask: u32, loops: u8) -> Result<(), Error> {
+    with_pattern(pattern, |patterns, index| {
+        let overlap = patterns
+            .iter()
+            .enumerate()
+            .find(|(other, other_pattern)| *other != index && other_pattern.mask & mask != 0);
+        if let Some((other, _)) = overlap {
+            return Err(Error::Overlaps(other as u8));
+        }
+        patterns[index] = Pattern {
+            mask,
+            loops,
+            ..Pattern::EMPTY
+        };
+        Ok(())
+    })
+}
+
+/// Add a step to the end of `pattern` that holds the pins at `levels` for `duration`, which is
+/// at least a tick so a pattern always takes time to play
+pub fn add_step(pattern: u8, levels: u32, duration: Duration) -> Result<(), Error> {
+    with_pattern(pattern, |patterns, index| {
+        let duration = duration.max(Duration::from_ticks(1));
+        let step = Step { levels, duration };
+        patterns[index].steps.push(step).map_err(|_| Error::Full)
+    })
+}
+
+/// Start `pattern` from its first step, or resume it if it's paused
+pub fn start(pattern: u8) -> Result<(), Error> {
+    with_pattern(pattern, |patterns, index| {
+        let pattern = &mut patterns[index];
+        if pattern.steps.is_empty() {
+            return Err(Error::Empty);
+        }
+        let now = Instant::now();
+        match pattern.state {
+            State::Playing => {}
+            State::Paused => pattern.next = now + pattern.remaining,
+            State::Stopped => {
+                pattern.step = 0;
+                pattern.loops_done = 0;
+                pattern.apply();
+                pattern.next = now + pattern.steps[0].duration;
+            }
+        }
+        pattern.state = State::Playing;
+        Ok(())
+    })?;
+    CHANGED.signal(());
+    Ok(())
+}
+
+/// Hold `pattern` on its current step until it's started again
+pub fn pause(pattern: u8) -> Result<(), Error> {
+    with_pattern(pattern, |patterns, index| {
+        let pattern = &mut patterns[index];
+        if pattern.state == State::Playing {
+            pattern.remaining = pattern.next.saturating_duration_since(Instant::now());
+            pattern.state = State::Paused;
+        }
+        Ok(())
+    })
+}
+
+/// Stop `pattern`, leaving its pins as they are. It restarts from the first step.
+pub fn stop(pattern: u8) -> Result<(), Error> {
+    with_pattern(pattern, |patterns, index| {
+        patterns[index].state = State::Stopped;
+        Ok(())
+    })
+}
+
+/// Returns the state, current step, number of steps and number of completed loops of `pattern`
+pub fn status(pattern: u8) -> Result<[u8; 4], Error> {
+    with_pattern(pattern, |patterns, index| {
+        let pattern = &patterns[index];
+        Ok([
+            pattern.state as u8,
+            pattern.step as u8,
+            pattern.steps.len() as u8,
+            pattern.loops_done,
+        ])
+    })
+}
+
+/// Mask of the patterns that are playing
+pub fn playing() -> u8 {
+    PATTERNS.lock(|patterns| {
+        patterns
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(_, pattern)| pattern.state == State::Playing)
+            .fold(0, |mask, (index, _)| mask | 1 << index)
+    })
+}
+
+/// Advance the playing patterns whose current step has ended
+pub fn advance_due() {
+    let now = Instant::now();
+    PATTERNS.lock(|patterns| {
+        for pattern in patterns.borrow_mut().iter_mut() {
+            pattern.advance(now);
+        }
+    });
+}
+
+/// Play the patterns as their steps come due
+pub(crate) async fn run() -> ! {
+    loop {
+        let next = PATTERNS.lock(|patterns| {
+            patterns
+                .borrow()
+                .iter()
+                .filter(|pattern| pattern.state == State::Playing)
+                .map(|pattern| pattern.next)
+                .min()
+        });
+        match next {
+            Some(at) => {
+                select(Timer::at(at), CHANGED.wait()).await;
+            }
+            None => CHANGED.wait().await,
+        }
+
+        advance_due();
+    }
+}
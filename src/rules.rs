@@ -0,0 +1,205 @@
+//! Local rules linking inputs to outputs, evaluated by the GPIO interrupt on every input edge,
+//! see [`crate::edges`], and by [`crate::device::Device`] after every write command, so an output
+//! reacts without waiting for the host or the device task.
+//!
+//! A rule drives one output pin from a mask of input pins in a group. The output is high when
+//! any ([`RULE_AND`] unset) or all ([`RULE_AND`] set) of the inputs are high, [`RULE_INVERT`]
+//! inverts it, and with [`RULE_LATCH`] the output stays at its active level once the inputs have
+//! activated it, until the latch is reset. Inputs are read after polarity is applied.
+
+use core::cell::RefCell;
+
+use byte::ctx::Endian;
+use byte::{BytesExt, TryRead, TryWrite};
+use defmt::Format;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::gpios;
+
+/// Maximum number of rules, a mask of rules fits in a byte
+pub const MAX_RULES: usize = 8;
+/// The output is active when all the inputs are high, rather than any of them
+pub const RULE_AND: u8 = 0b0000_0001;
+/// The output is low while it's active
+pub const RULE_INVERT: u8 = 0b0000_0010;
+/// The output stays active until the latch is reset
+pub const RULE_LATCH: u8 = 0b0000_0100;
+const RULE_FLAGS: u8 = RULE_AND | RULE_INVERT | RULE_LATCH;
+
+/// A rule with its pins translated to GPIO numbers, so the interrupt can evaluate it
+#[derive(Clone, Copy)]
+struct Wired {
+    rule: Rule,
+    /// GPIOs of the inputs that exist in the input group
+    inputs: u32,
+    /// Whether every input exists, an AND rule with a missing input is never active
+    complete: bool,
+    output: u8,
+}
+
+impl Wired {
+    /// Whether the inputs, from the logical `levels` of the GPIOs, activate the output
+    fn is_active(&self, levels: u32) -> bool {
+        if self.rule.flags & RULE_AND != 0 {
+            self.complete && levels & self.inputs == self.inputs
+        } else {
+            levels & self.inputs != 0
+        }
+    }
+}
+
+struct Rules {
+    rules: [Option<Wired>; MAX_RULES],
+    /// Mask of the latching rules that have been activated
+    latched: u8,
+}
+
+static RULES: Mutex<CriticalSectionRawMutex, RefCell<Rules>> = Mutex::new(RefCell::new(Rules {
+    rules: [None; MAX_RULES],
+    latched: 0,
+}));
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub struct Rule {
+    pub input_group: u8,
+    pub inputs: u8,
+    pub output_group: u8,
+    pub output_pin: u8,
+    pub flags: u8,
+}
+
+impl Rule {
+    pub const fn new(
+        input_group: u8,
+        inputs: u8,
+        output_group: u8,
+        output_pin: u8,
+        flags: u8,
+    ) -> Self {
+        Self {
+            input_group,
+            inputs,
+            output_group,
+            output_pin,
+            flags: flags & RULE_FLAGS,
+        }
+    }
+
+    pub fn latches(&self) -> bool {
+        self.flags & RULE_LATCH != 0
+    }
+
+    /// The level to drive the output to while it's `active`
+    pub fn output(&self, active: bool) -> bool {
+        active ^ (self.flags & RULE_INVERT != 0)
+    }
+}
+
+/// Put `rule` in `slot`, with the GPIOs of its existing `inputs`, whether they're all of them and
+/// the GPIO of its `output`, or clear the slot. The slot's latch is released.
+pub fn set(slot: usize, rule: Option<(Rule, u32, bool, u8)>) {
+    RULES.lock(|rules| {
+        let mut rules = rules.borrow_mut();
+        rules.rules[slot] = rule.map(|(rule, inputs, complete, output)| Wired {
+            rule,
+            inputs,
+            complete,
+            output,
+        });
+        rules.latched &= !(1 << slot);
+    });
+}
+
+pub fn get(slot: usize) -> Option<Rule> {
+    RULES.lock(|rules| rules.borrow().rules.get(slot)?.map(|wired| wired.rule))
+}
+
+/// The rule in every slot
+pub fn all() -> [Option<Rule>; MAX_RULES] {
+    RULES.lock(|rules| {
+        rules
+            .borrow()
+            .rules
+            .map(|wired| wired.map(|wired| wired.rule))
+    })
+}
+
+/// Mask of the latching rules that have been activated
+pub fn latched() -> u8 {
+    RULES.lock(|rules| rules.borrow().latched)
+}
+
+/// Release the latch of the rule in `slot`, its output follows its inputs again
+pub fn reset_latch(slot: usize) {
+    RULES.lock(|rules| rules.borrow_mut().latched &= !(1 << slot));
+}
+
+/// GPIOs driven by a rule
+pub fn outputs() -> u32 {
+    RULES.lock(|rules| {
+        let rules = rules.borrow();
+        rules
+            .rules
+            .iter()
+            .flatten()
+            .fold(0, |mask, wired| mask | 1 << wired.output)
+    })
+}
+
+/// Drive the output of every rule from its inputs, read after polarity is applied. The output
+/// latch is set even while the pin is an input, as [`crate::gpios::PinGroup::write_pin`] does.
+///
+/// A rule sees the outputs of the rules in the slots before it as they're driven, so rules can
+/// be chained without waiting for the edges of their outputs.
+pub fn apply() {
+    RULES.lock(|rules| {
+        let mut rules = rules.borrow_mut();
+        let driven = gpios::output_gpios();
+        let mut levels = gpios::read_gpios();
+        let (mut mask, mut outputs) = (0, 0);
+        for slot in 0..MAX_RULES {
+            let Some(wired) = rules.rules[slot] else {
+                continue;
+            };
+            let active = wired.is_active(levels);
+            if active && wired.rule.latches() {
+                rules.latched |= 1 << slot;
+            }
+            let output = wired.rule.output(active || rules.latched & 1 << slot != 0);
+            let bit = u32::from(output) << wired.output;
+            mask |= 1 << wired.output;
+            outputs = outputs & !(1 << wired.output) | bit;
+            if driven & 1 << wired.output != 0 {
+                levels = levels & !(1 << wired.output) | bit;
+            }
+        }
+        gpios::write_gpios(mask, outputs);
+    });
+}
+
+impl<'a> TryRead<'a, Endian> for Rule {
+    fn try_read(bytes: &'a [u8], ctx: Endian) -> byte::Result<(Self, usize)> {
+        let mut offset = 0;
+        let rule = Self::new(
+            bytes.read_with(&mut offset, ctx)?,
+            bytes.read_with(&mut offset, ctx)?,
+            bytes.read_with(&mut offset, ctx)?,
+            bytes.read_with(&mut offset, ctx)?,
+            bytes.read_with(&mut offset, ctx)?,
+        );
+        Ok((rule, offset))
+    }
+}
+
+impl TryWrite<Endian> for Rule {
+    fn try_write(self, bytes: &mut [u8], ctx: Endian) -> byte::Result<usize> {
+        let mut offset = 0;
+        bytes.write_with(&mut offset, self.input_group, ctx)?;
+        bytes.write_with(&mut offset, self.inputs, ctx)?;
+        bytes.write_with(&mut offset, self.output_group, ctx)?;
+        bytes.write_with(&mut offset, self.output_pin, ctx)?;
+        bytes.write_with(&mut offset, self.flags, ctx)?;
+        Ok(offset)
+    }
+}
@@ -0,0 +1,265 @@
+//! Hobby servo outputs, 50 Hz pulses of a set width generated by the PWM slices and moved
+//! towards their target at a limited rate by [`crate::tasks::servo_task`].
+//!
+//! GPIO `n` is output `n & 1` (A or B) of slice `(n >> 1) & 7`, so both pins that share a slice
+//! can drive servos, but not while the slice is measuring a pin, see [`crate::measure`]. While a
+//! pin is a servo it's connected to its PWM slice rather than its group.
+
+use core::cell::RefCell;
+
+use defmt::Format;
+use embassy_futures::select::select;
+use embassy_rp::pac;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, Timer};
+
+use crate::measure::{self, SLICES};
+
+/// Servos are indexed by `gpio & 0xF`, one for each output of each slice
+pub const MAX_SERVOS: usize = SLICES * 2;
+pub const PERIOD_US: u16 = 20_000;
+pub const DEFAULT_MIN_US: u16 = 1_000;
+pub const DEFAULT_MAX_US: u16 = 2_000;
+pub const MAX_ANGLE: u8 = 180;
+/// Rate the slices count at, so the compare values are in µs
+const TICK_HZ: u32 = 1_000_000;
+
+static SERVOS: Mutex<CriticalSectionRawMutex, RefCell<[Option<Servo>; MAX_SERVOS]>> =
+    Mutex::new(RefCell::new([None; MAX_SERVOS]));
+/// Wakes the task when a servo is given a new target
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub enum Error {
+    /// The GPIO's slice is measuring a pin, or its output is a servo on another GPIO
+    SliceInUse(u8),
+    NotAServo(u8),
+    /// The minimum is above the maximum, or the maximum is longer than the period
+    InvalidLimits,
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub struct Servo {
+    pub gpio: u8,
+    pub min_us: u16,
+    pub max_us: u16,
+    /// Width of the pulses being output
+    pub current_us: u16,
+    pub target_us: u16,
+    /// Fastest change of the pulse width in µs per second, 0 moves to the target immediately
+    pub slew_us: u16,
+    moved_at: Instant,
+}
+
+impl Servo {
+    fn set_compare(&self) {
+        let slice = (self.gpio >> 1) as usize & 7;
+        pac::PWM.ch(slice).cc().modify(|w| {
+            if self.gpio & 1 == 0 {
+                w.set_a(self.current_us)
+            } else {
+                w.set_b(self.current_us)
+            }
+        });
+    }
+
+    /// Move towards the target by as much as the slew rate allows since it last moved
+    fn advance(&mut self, now: Instant) {
+        let elapsed_us = now.saturating_duration_since(self.moved_at).as_micros();
+        let step = if self.slew_us == 0 {
+            u16::MAX
+        } else {
+            // Only whole µs are moved, the remainder carries over to the next update
+            let step = elapsed_us * self.slew_us as u64 / 1_000_000;
+            if step == 0 {
+                return;
+            }
+            step.min(u16::MAX as u64) as u16
+        };
+        self.moved_at = now;
+        self.current_us = if self.current_us < self.target_us {
+            self.current_us.saturating_add(step).min(self.target_us)
+        } else {
+            self.current_us.saturating_sub(step).max(self.target_us)
+        };
+        self.set_compare();
+    }
+
+    fn is_moving(&self) -> bool {
+        self.current_us != self.target_us
+    }
+}
+
+const fn index(gpio: u8) -> usize {
+    (gpio & 0xF) as usize
+}
+
+/// Whether `slice` is driving any servos
+pub fn uses_slice(slice: usize) -> bool {
+    SERVOS.lock(|servos| {
+        let servos = servos.borrow();
+        servos[slice * 2].is_some() || servos[slice * 2 + 1].is_some()
+    })
+}
+
+fn with_servo<R>(gpio: u8, f: impl FnOnce(&mut Servo) -> R) -> Result<R, Error> {
+    SERVOS.lock(|servos| {
+        servos.borrow_mut()[index(gpio)]
+            .as_mut()
+            .filter(|servo| servo.gpio == gpio)
+            .map(f)
+            .ok_or(Error::NotAServo(gpio))
+    })
+}
+
+/// Start driving `gpio` as a servo, centred between the default limits. Enabling a servo that's
+/// already enabled leaves it as it is.
+pub fn enable(gpio: u8) -> Result<(), Error> {
+    let slice = (gpio >> 1) as usize & 7;
+    if measure::uses_slice(slice) {
+        return Err(Error::SliceInUse(gpio));
+    }
+    SERVOS.lock(|servos| {
+        let mut servos = servos.borrow_mut();
+        match servos[index(gpio)] {
+            Some(servo) if servo.gpio == gpio => return Ok(()),
+            Some(_) => return Err(Error::SliceInUse(gpio)),
+            None => {}
+        }
+        let centre = DEFAULT_MIN_US + (DEFAULT_MAX_US - DEFAULT_MIN_US) / 2;
+        let servo = Servo {
+            gpio,
+            min_us: DEFAULT_MIN_US,
+            max_us: DEFAULT_MAX_US,
+            current_us: centre,
+            target_us: centre,
+            slew_us: 0,
+            moved_at: Instant::now(),
+        };
+        if !servos[slice * 2..slice * 2 + 2].iter().any(Option::is_some) {
+            let regs = pac::PWM.ch(slice);
+            let divider = (embassy_rp::clocks::clk_sys_freq() / TICK_HZ) as u8;
+            regs.csr().write(|_| {});
+            regs.div().write(|w| w.set_int(divider));
+            regs.top().write(|w| w.set_top(PERIOD_US - 1));
+            regs.cc().write(|_| {});
+            regs.ctr().write(|w| w.set_ctr(0));
+            pac::PWM.en().modify(|w| w.0 |= 1 << slice);
+        }
+        servo.set_compare();
+        measure::set_funcsel(gpio, measure::FUNCSEL_PWM);
+        servos[index(gpio)] = Some(servo);
+        Ok(())
+    })
+}
+
+/// Stop driving `gpio` as a servo, returning it to SIO. Its output of the slice is turned off,
+/// and the slice is stopped once neither output is a servo.
+pub fn disable(gpio: u8) {
+    let slice = (gpio >> 1) as usize & 7;
+    SERVOS.lock(|servos| {
+        let mut servos = servos.borrow_mut();
+        if let Some(servo) = servos[index(gpio)].take_if(|servo| servo.gpio == gpio) {
+            measure::set_funcsel(gpio, measure::FUNCSEL_SIO);
+            Servo {
+                current_us: 0,
+                ..servo
+            }
+            .set_compare();
+            if !servos[slice * 2..slice * 2 + 2].iter().any(Option::is_some) {
+                pac::PWM.en().modify(|w| w.0 &= !(1 << slice));
+            }
+        }
+    });
+}
+
+pub fn is_enabled(gpio: u8) -> bool {
+    get(gpio).is_some()
+}
+
+pub fn get(gpio: u8) -> Option<Servo> {
+    with_servo(gpio, |servo| *servo).ok()
+}
+
+/// Check that `min_us` and `max_us` are in order and fit in a period
+pub fn check_limits(min_us: u16, max_us: u16) -> Result<(), Error> {
+    if min_us > max_us || max_us >= PERIOD_US {
+        return Err(Error::InvalidLimits);
+    }
+    Ok(())
+}
+
+/// Limit the pulse width of `gpio` to between `min_us` and `max_us`, which the angles are spread
+/// across, moving it inside them if it's outside
+pub fn set_limits(gpio: u8, min_us: u16, max_us: u16) -> Result<(), Error> {
+    check_limits(min_us, max_us)?;
+    with_servo(gpio, |servo| {
+        servo.min_us = min_us;
+        servo.max_us = max_us;
+        servo.target_us = servo.target_us.clamp(min_us, max_us);
+    })?;
+    CHANGED.signal(());
+    Ok(())
+}
+
+/// Set how fast `gpio` moves towards its target in µs per second, 0 moves immediately
+pub fn set_slew(gpio: u8, slew_us: u16) -> Result<(), Error> {
+    with_servo(gpio, |servo| {
+        servo.slew_us = slew_us;
+        servo.moved_at = Instant::now();
+    })
+}
+
+/// Move `gpio` towards a pulse width, clamped to its limits
+pub fn set_micros(gpio: u8, us: u16) -> Result<(), Error> {
+    with_servo(gpio, |servo| {
+        servo.target_us = us.clamp(servo.min_us, servo.max_us);
+        if !servo.is_moving() {
+            return;
+        }
+        servo.moved_at = Instant::now();
+        if servo.slew_us == 0 {
+            servo.advance(servo.moved_at);
+        }
+    })?;
+    CHANGED.signal(());
+    Ok(())
+}
+
+/// Move `gpio` towards an angle from 0 to [`MAX_ANGLE`] degrees, spread across its limits
+pub fn set_angle(gpio: u8, angle: u8) -> Result<(), Error> {
+    let Some(servo) = get(gpio) else {
+        return Err(Error::NotAServo(gpio));
+    };
+    let range = (servo.max_us - servo.min_us) as u32;
+    let us = servo.min_us as u32 + range * angle.min(MAX_ANGLE) as u32 / MAX_ANGLE as u32;
+    set_micros(gpio, us as u16)
+}
+
+/// Move the servos that are slewing towards their targets
+pub fn advance_due() {
+    let now = Instant::now();
+    SERVOS.lock(|servos| {
+        for servo in servos.borrow_mut().iter_mut().flatten() {
+            if servo.is_moving() {
+                servo.advance(now);
+            }
+        }
+    });
+}
+
+/// Move the servos towards their targets once per period
+pub(crate) async fn run() -> ! {
+    loop {
+        let moving = SERVOS.lock(|servos| servos.borrow().iter().flatten().any(Servo::is_moving));
+        if moving {
+            select(Timer::after_micros(PERIOD_US as u64), CHANGED.wait()).await;
+        } else {
+            CHANGED.wait().await;
+        }
+
+        advance_due();
+    }
+}
@@ -0,0 +1,151 @@
+//! SPI slave transport, for hosts that need more throughput than I2C. Enabled by the board
+//! file's `[spi]` section and serviced by [`crate::tasks::spi_task`].
+//!
+//! The host selects the device with CS and clocks a frame in mode 3 (CPOL = 1, CPHA = 1), at up
+//! to a twelfth of `clk_peri`. A frame is a kind byte followed by a command, see
+//! [`Request::from_frame`]. The response to a frame is clocked out during the next one, padded
+//! with [`FILL`], so polling the inputs is a stream of [`crate::transport::FRAME_READ`] frames.
+//! The response is queued while CS is deasserted, so raise CS between frames for long enough for
+//! the command to be handled. A frame that starts before then is skipped whole, rather than read
+//! from the middle with the response out of step.
+//!
+//! The SPI block's FIFOs are serviced by DMA, so a frame doesn't depend on when the task gets to
+//! run, only on CS being raised between frames. A frame longer than `1 + MAX_REQUEST_LEN` bytes is
+//! cut short. A frame that holds CS for longer than [`FRAME_TIMEOUT`] is dropped.
+
+use defmt::warn;
+use embassy_rp::dma::{self, AnyChannel, Channel};
+use embassy_rp::gpio::{AnyPin, Input, Pin, Pull};
+use embassy_rp::pac;
+use embassy_time::{with_timeout, Duration};
+
+use crate::board::SPI_BLOCK;
+use crate::device::MAX_RESPONSE_LEN;
+use crate::measure::set_funcsel;
+use crate::transport::{self, Request, Response, FILL, MAX_REQUEST_LEN};
+
+const FUNCSEL_SPI: u8 = 1;
+/// DMA requests of the SPI block's transmit and receive FIFOs
+const TX_DREQ: u8 = 16 + 2 * SPI_BLOCK;
+const RX_DREQ: u8 = TX_DREQ + 1;
+/// The longest frame that's received
+const FRAME_LEN: usize = 1 + MAX_REQUEST_LEN;
+/// The response padded with [`FILL`], long enough for any response or frame
+const TX_LEN: usize = if MAX_RESPONSE_LEN > FRAME_LEN {
+    MAX_RESPONSE_LEN
+} else {
+    FRAME_LEN
+};
+
+/// The longest CS can be held for one frame
+pub const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn regs() -> pac::spi::Spi {
+    if SPI_BLOCK == 0 {
+        pac::SPI0
+    } else {
+        pac::SPI1
+    }
+}
+
+/// Reset and configure the SPI block as a mode 3 slave with DMA, which also empties its FIFOs
+fn reset() {
+    let reset = |on: bool| {
+        pac::RESETS.reset().modify(|w| {
+            if SPI_BLOCK == 0 {
+                w.set_spi0(on)
+            } else {
+                w.set_spi1(on)
+            }
+        })
+    };
+    let done = || {
+        let done = pac::RESETS.reset_done().read();
+        if SPI_BLOCK == 0 {
+            done.spi0()
+        } else {
+            done.spi1()
+        }
+    };
+    reset(true);
+    reset(false);
+    while !done() {}
+
+    let regs = regs();
+    regs.cpsr().write(|w| w.set_cpsdvsr(2));
+    regs.cr0().write(|w| {
+        w.set_dss(8 - 1);
+        w.set_frf(0);
+        w.set_spo(true);
+        w.set_sph(true);
+    });
+    regs.cr1().write(|w| {
+        w.set_ms(true);
+        w.set_sse(true);
+    });
+    regs.dmacr().write(|w| {
+        w.set_txdmae(true);
+        w.set_rxdmae(true);
+    });
+}
+
+/// Receive a frame with DMA while sending `response`, starting before CS is asserted so the
+/// response is in the transmit FIFO for the first byte. Returns the frame's length, or `None` if
+/// CS is held past [`FRAME_TIMEOUT`].
+async fn exchange(
+    cs: &mut Input<'static, AnyPin>,
+    dma: &mut [AnyChannel; 2],
+    response: &[u8],
+    frame: &mut [u8],
+) -> Option<usize> {
+    let regs = regs();
+    let data = regs.dr().as_ptr() as *mut u8;
+    let [rx, tx] = dma;
+    let received = rx.regs();
+    // SAFETY: The buffers outlive the transfers, which are aborted when they're dropped
+    let _transmit = unsafe { dma::write(&mut *tx, response, data, TX_DREQ) };
+    let receive = unsafe { dma::read(&mut *rx, data, frame as *mut [u8], RX_DREQ) };
+    cs.wait_for_low().await;
+    with_timeout(FRAME_TIMEOUT, cs.wait_for_high()).await.ok()?;
+    // Let DMA take the last bytes from the receive FIFO
+    while regs.sr().read().rne() && received.ctrl_trig().read().busy() {}
+    let len = frame.len() - received.trans_count().read() as usize;
+    drop(receive);
+    Some(len)
+}
+
+/// Serve frames from the host over SPI, with the `dma` channels receiving and transmitting
+pub(crate) async fn run(
+    sck: AnyPin,
+    mosi: AnyPin,
+    miso: AnyPin,
+    cs: AnyPin,
+    mut dma: [AnyChannel; 2],
+) -> ! {
+    let pins = [sck.pin(), mosi.pin(), miso.pin(), cs.pin()];
+    // CS is watched through SIO, which still sees the pin after it's handed to the SPI block
+    let mut cs = Input::new(cs, Pull::Up);
+    for pin in pins {
+        set_funcsel(pin, FUNCSEL_SPI);
+    }
+
+    let mut frame = [0u8; FRAME_LEN];
+    let mut transmit = [FILL; TX_LEN];
+    let mut response = Response::new();
+    loop {
+        // Only queue the response between frames, so it starts with the next frame's first byte
+        cs.wait_for_high().await;
+        reset();
+        transmit[..response.len()].copy_from_slice(&response);
+        transmit[response.len()..].fill(FILL);
+        let Some(len) = exchange(&mut cs, &mut dma, &transmit, &mut frame).await else {
+            warn!("[SPI] FRAME_TIMEOUT");
+            response = Response::new();
+            continue;
+        };
+        response = match Request::from_frame(&frame[..len]) {
+            Some(request) => transport::transact(request).await.unwrap_or_default(),
+            None => Response::new(),
+        };
+    }
+}
@@ -0,0 +1,319 @@
+//! STEP/DIR outputs for external stepper motor drivers, stepped by
+//! [`crate::tasks::stepper_task`] on the high priority executor.
+//!
+//! A move accelerates from rest to its maximum speed and decelerates to stop on its last step, a
+//! trapezoidal profile, or a triangular one if it's too short to reach the maximum. The speed
+//! for each step is `sqrt(2 * acceleration * steps)`, from the steps taken or the steps left,
+//! whichever is slower.
+//!
+//! STEP is raised for a step and lowered again by the task once the pulse has passed, rather than
+//! holding the executor for it.
+
+use core::cell::RefCell;
+
+use defmt::{info, Format};
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicU8, Ordering};
+
+use crate::timed::OutputPin;
+use crate::SET_INT_OUT;
+
+pub const MAX_STEPPERS: usize = 2;
+/// [`Stepper`] flag to raise INT_OUT when a move completes
+pub const STEPPER_TRIGGER_INT_OUT: u8 = 0b0000_0001;
+/// [`Stepper`] flag to drive DIR low, rather than high, for positive moves
+pub const STEPPER_INVERT_DIR: u8 = 0b0000_0010;
+const STEPPER_FLAGS: u8 = STEPPER_TRIGGER_INT_OUT | STEPPER_INVERT_DIR;
+/// How long STEP is held high for each step, at least
+const STEP_PULSE_US: u64 = 2;
+/// How long DIR is held before the first step of a move
+const DIR_SETUP_US: u64 = 5;
+
+static STEPPERS: Mutex<CriticalSectionRawMutex, RefCell<[Option<Stepper>; MAX_STEPPERS]>> =
+    Mutex::new(RefCell::new([None; MAX_STEPPERS]));
+/// Mask of the steppers that have completed a move since it was last read
+static COMPLETED: AtomicU8 = AtomicU8::new(0);
+/// The part of [`COMPLETED`] that raised INT_OUT
+static INTERRUPTS: AtomicU8 = AtomicU8::new(0);
+/// Wakes the task when a move starts
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub enum Error {
+    InvalidStepper(u8),
+    NotConfigured(u8),
+    /// The stepper is still moving
+    Busy(u8),
+    /// A move needs a maximum speed above 0
+    InvalidSpeed,
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+struct Move {
+    forward: bool,
+    taken: u32,
+    remaining: u32,
+    /// In steps per second
+    max_speed: u16,
+    /// In steps per second squared, 0 moves at the maximum speed throughout
+    acceleration: u16,
+    next: Instant,
+}
+
+impl Move {
+    /// Speed of the next step in steps per second
+    fn speed(&self) -> u32 {
+        let max_speed = self.max_speed as u32;
+        if self.acceleration == 0 {
+            return max_speed;
+        }
+        let twice_accel = 2 * self.acceleration as u64;
+        let accelerating = (twice_accel * (self.taken as u64 + 1)).isqrt();
+        let decelerating = (twice_accel * self.remaining as u64).isqrt();
+        (accelerating.min(decelerating) as u32).clamp(1, max_speed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub struct Stepper {
+    step: OutputPin,
+    dir: OutputPin,
+    flags: u8,
+    position: i32,
+    moving: Option<Move>,
+    /// When STEP is lowered, while it's high
+    step_ends: Option<Instant>,
+}
+
+impl Stepper {
+    pub fn new(step: OutputPin, dir: OutputPin, flags: u8) -> Self {
+        Self {
+            step,
+            dir,
+            flags: flags & STEPPER_FLAGS,
+            position: 0,
+            moving: None,
+            step_ends: None,
+        }
+    }
+
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    /// Whether the stepper is moving, or STEP is still high from its last step
+    pub fn is_busy(&self) -> bool {
+        self.moving.is_some() || self.step_ends.is_some()
+    }
+
+    /// When the task next has something to do for this stepper, STEP is lowered before the next
+    /// step can be taken
+    fn next_due(&self) -> Option<Instant> {
+        self.step_ends
+            .or_else(|| self.moving.map(|motion| motion.next))
+    }
+
+    /// Lower STEP if its pulse has ended, then take the next step if it's due, returning whether
+    /// the move has completed
+    fn step_due(&mut self, now: Instant) -> bool {
+        if let Some(ends) = self.step_ends {
+            if ends > now {
+                return false;
+            }
+            self.step.set_level(false);
+            self.step_ends = None;
+        }
+        let Some(motion) = self.moving.as_mut().filter(|motion| motion.next <= now) else {
+            return false;
+        };
+        self.step.set_level(true);
+        self.step_ends = Some(Instant::now() + Duration::from_micros(STEP_PULSE_US));
+        self.position = if motion.forward {
+            self.position.wrapping_add(1)
+        } else {
+            self.position.wrapping_sub(1)
+        };
+        motion.taken += 1;
+        motion.remaining -= 1;
+        if motion.remaining == 0 {
+            self.moving = None;
+            return true;
+        }
+        motion.next += Duration::from_micros(1_000_000 / motion.speed() as u64);
+        false
+    }
+}
+
+fn with_stepper<R>(
+    stepper: u8,
+    f: impl FnOnce(&mut Stepper) -> Result<R, Error>,
+) -> Result<R, Error> {
+    STEPPERS.lock(|steppers| {
+        steppers
+            .borrow_mut()
+            .get_mut(stepper as usize)
+            .ok_or(Error::InvalidStepper(stepper))?
+            .as_mut()
+            .ok_or(Error::NotConfigured(stepper))
+            .and_then(f)
+    })
+}
+
+/// Drive a stepper from `config`, starting with STEP low, or stop driving it if it's `None`.
+/// A stepper can't be reconfigured while it's moving, and its pins are left alone then.
+pub fn configure(stepper: u8, config: Option<Stepper>) -> Result<(), Error> {
+    STEPPERS.lock(|steppers| {
+        let mut steppers = steppers.borrow_mut();
+        let slot = steppers
+            .get_mut(stepper as usize)
+            .ok_or(Error::InvalidStepper(stepper))?;
+        if slot.is_some_and(|existing| existing.is_busy()) {
+            return Err(Error::Busy(stepper));
+        }
+        if let Some(config) = &config {
+            config.step.set_level(false);
+        }
+        *slot = config;
+        Ok(())
+    })
+}
+
+pub fn get(stepper: u8) -> Result<Stepper, Error> {
+    with_stepper(stepper, |stepper| Ok(*stepper))
+}
+
+/// Move `steps` from the current position, accelerating at `acceleration` steps/s² up to
+/// `max_speed` steps/s
+pub fn start_move(stepper: u8, steps: i32, max_speed: u16, acceleration: u16) -> Result<(), Error> {
+    if max_speed == 0 {
+        return Err(Error::InvalidSpeed);
+    }
+    with_stepper(stepper, |config| {
+        if config.is_busy() {
+            return Err(Error::Busy(stepper));
+        }
+        if steps == 0 {
+            return Ok(());
+        }
+        let forward = steps > 0;
+        config
+            .dir
+            .set_level(forward ^ (config.flags & STEPPER_INVERT_DIR != 0));
+        config.moving = Some(Move {
+            forward,
+            taken: 0,
+            remaining: steps.unsigned_abs(),
+            max_speed,
+            acceleration,
+            next: Instant::now() + Duration::from_micros(DIR_SETUP_US),
+        });
+        Ok(())
+    })?;
+    CHANGED.signal(());
+    Ok(())
+}
+
+/// Decelerate to a stop as quickly as the move's acceleration allows, or stop immediately if it
+/// has none
+pub fn stop(stepper: u8) -> Result<(), Error> {
+    with_stepper(stepper, |config| {
+        if let Some(motion) = config.moving.as_mut() {
+            let steps_to_stop = if motion.acceleration == 0 {
+                0
+            } else {
+                let speed = motion.speed() as u64;
+                (speed * speed / (2 * motion.acceleration as u64)) as u32
+            };
+            motion.remaining = motion.remaining.min(steps_to_stop);
+            if motion.remaining == 0 {
+                config.moving = None;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Redefine the current position, a stepper can't be moving
+pub fn set_position(stepper: u8, position: i32) -> Result<(), Error> {
+    with_stepper(stepper, |config| {
+        if config.is_busy() {
+            return Err(Error::Busy(stepper));
+        }
+        config.position = position;
+        Ok(())
+    })
+}
+
+/// Mask of the steppers that are moving
+pub fn busy() -> u8 {
+    STEPPERS.lock(|steppers| {
+        steppers
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(_, stepper)| stepper.is_some_and(|stepper| stepper.is_busy()))
+            .fold(0, |mask, (index, _)| mask | 1 << index)
+    })
+}
+
+/// Returns the mask of the steppers that have completed a move since the last call
+pub fn take_completed() -> u8 {
+    INTERRUPTS.store(0, Ordering::Relaxed);
+    COMPLETED.swap(0, Ordering::Relaxed)
+}
+
+/// Whether a completed move that raised INT_OUT hasn't been read yet
+pub fn interrupt_pending() -> bool {
+    INTERRUPTS.load(Ordering::Relaxed) != 0
+}
+
+/// Take the steps that are due now
+pub fn step_due() {
+    let now = Instant::now();
+    let trigger = STEPPERS.lock(|steppers| {
+        let mut trigger = false;
+        for (index, stepper) in steppers.borrow_mut().iter_mut().enumerate() {
+            let Some(stepper) = stepper else {
+                continue;
+            };
+            if stepper.step_due(now) {
+                info!("[STEPPER] {} AT {}", index, stepper.position);
+                COMPLETED.fetch_or(1 << index, Ordering::Relaxed);
+                if stepper.flags & STEPPER_TRIGGER_INT_OUT != 0 {
+                    INTERRUPTS.fetch_or(1 << index, Ordering::Relaxed);
+                    trigger = true;
+                }
+            }
+        }
+        trigger
+    });
+    if trigger {
+        SET_INT_OUT.signal(true);
+    }
+}
+
+/// Step the moving steppers as their steps come due
+pub(crate) async fn run() -> ! {
+    loop {
+        let next = STEPPERS.lock(|steppers| {
+            steppers
+                .borrow()
+                .iter()
+                .flatten()
+                .filter_map(Stepper::next_due)
+                .min()
+        });
+        match next {
+            Some(at) => {
+                select(Timer::at(at), CHANGED.wait()).await;
+            }
+            None => CHANGED.wait().await,
+        }
+
+        step_due();
+    }
+}
@@ -1,11 +1,18 @@
 use crate::prelude::*;
 use device::Device;
-use embassy_futures::select::{select, Either};
-use embassy_rp::gpio::{Level, Output, OutputOpenDrain};
+use embassy_rp::adc::{self, Adc};
+#[cfg(spi_transport)]
+use embassy_rp::gpio::AnyPin;
+use embassy_rp::gpio::{Level, Output, OutputOpenDrain, Pin};
 use embassy_rp::i2c_slave::Command;
-use embassy_rp::peripherals::I2C0;
+use embassy_rp::peripherals::{I2C0, PIO0, UART0, USB};
+use embassy_rp::uart::BufferedUart;
+use embassy_rp::usb::Driver;
 use embassy_rp::{i2c_slave, interrupt};
-use embassy_time::Timer;
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::UsbDevice;
+use transport::Request;
 
 const EN_DELAY_MS: u64 = 200;
 const EN_DURATION: u64 = 100;
@@ -25,101 +32,138 @@ pub async fn trigger_int_out(int_out: P_INT_OUT) -> ! {
     }
 }
 
+/// Own the [`Device`], handling the transports' requests, see [`transport`]
 #[embassy_executor::task]
-pub async fn i2c_task(mut slave: i2c_slave::I2cSlave<'static, I2C0>, mut device: Device) -> ! {
-    let mut write_buf = [0u8; 128];
-    let mut read_buf = [0u8; 2];
+pub async fn device_task(device: Device<GROUPS>) -> ! {
+    transport::serve(device).await
+}
 
-    device.set_pin_modes(&DEFAULT_PIN_MODES);
-    device.read(&mut read_buf);
+/// Pass the I2C transactions to the device task, see [`transport`]
+#[embassy_executor::task]
+pub async fn i2c_task(mut slave: i2c_slave::I2cSlave<'static, I2C0>) -> ! {
+    let mut write_buf = [0u8; transport::MAX_REQUEST_LEN];
 
-    info!("[MAIN_TASK] STARTING");
-    // info!("[MAIN_TASK] GPIO_STATE: {=[u8;2]:08b}", &read_buf);
+    info!("[I2C_TASK] STARTING");
     loop {
-        write_buf.fill(0);
-        device.read(&mut read_buf);
-        info!("[MAIN_TASK] GPIO_STATE: {=[u8;2]:08b}", &read_buf);
-        read_buf.fill(0);
-        match select(device.wait_for_any_edge(), slave.listen(&mut write_buf)).await {
-            Either::First(_) => {
-                //TODO: move back to wait_for_any_edge()?
-                SET_INT_OUT.signal(true);
-                // device.read(&mut read_buf);
-                // info!("[MAIN_TASK] GPIO_STATE: {=[u8;2]:08b}", &read_buf);
+        let request = match slave.listen(&mut write_buf).await {
+            Ok(Command::GeneralCall(_)) => {
+                info!("[I2C_TASK] GENERAL CALL");
+                continue;
+            }
+            Ok(Command::Read) => Request::Read,
+            Ok(Command::Write(len)) => Request::write(&write_buf[..len]),
+            Ok(Command::WriteRead(len)) => Request::write_read(&write_buf[..len]),
+            Err(e) => {
+                error!("[I2C_TASK] LISTEN_ERROR: {:#?}", e);
+                continue;
             }
-            Either::Second(listen_result) => {
-                // LED.signal(());
-                match listen_result {
-                    Ok(Command::GeneralCall(_)) => {
-                        info!("[MAIN_TASK] GENERAL CALL");
-                    }
-                    Ok(Command::Read) => {
-                        info!("[MAIN_TASK] READ");
-                        device.handle_read_command(&mut read_buf);
-                        match slave.respond_and_fill(&read_buf, 0x00).await {
-                            Ok(read_status) => {
-                                info!("[MAIN_TASK] READ_RESPONSE: {:?}", &read_buf);
-                                info!("[MAIN_TASK] READ_STATUS: {:?}", read_status);
-                            }
-                            Err(e) => {
-                                error!("[MAIN_TASK] READ_RESPONSE: {}", e);
-                            }
-                        }
-                    }
-                    Ok(Command::Write(len)) => {
-                        info!("[MAIN_TASK] WRITE: {:?}", &write_buf[..len]);
-                        if let Err(e) = device.handle_write_command(&write_buf[..len]) {
-                            error!("[MAIN_TASK] WRITE_ERROR: {:?}", e);
-                        }
-                    }
-                    Ok(Command::WriteRead(len)) => {
-                        info!("[MAIN_TASK] WRITE_READ: {:?}", &write_buf[..len]);
-                        match device.handle_write_read_command(&write_buf[..len], &mut read_buf) {
-                            Err(e) => {
-                                error!("[MAIN_TASK] WRITE_READ_ERROR: {:?}", e);
-                            }
-                            Ok(out_len) => {
-                                match slave.respond_and_fill(&read_buf[..out_len], 0x00).await {
-                                    // TODO: fix
-                                    Ok(read_status) => {
-                                        info!(
-                                            "[MAIN_TASK] WRITE_READ_RESPONSE: {:?}",
-                                            &read_buf[..out_len]
-                                        );
-                                        info!("[MAIN_TASK] WRITE_READ_STATUS: {:?}", read_status);
-                                    }
-                                    Err(e) => {
-                                        error!("[MAIN_TASK] WRITE_READ_RESPONSE: {}", e);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("[MAIN_TASK] LISTEN_ERROR: {:#?}", e);
-                    }
-                }
+        };
+        // LED.signal(());
+        let Some(response) = transport::transact(request).await else {
+            continue;
+        };
+        match slave.respond_and_fill(&response, transport::FILL).await {
+            Ok(read_status) => {
+                info!("[I2C_TASK] RESPONSE: {:?}", response.as_slice());
+                info!("[I2C_TASK] READ_STATUS: {:?}", read_status);
+            }
+            Err(e) => {
+                error!("[I2C_TASK] RESPONSE: {}", e);
             }
         }
-        // match i2c_slave.listen(&mut write_buf).await {
-        //     Ok(cmd) => {
-        //         LED.signal(());
-        //         match cmd {
-        //             i2c_slave::Command::Read => {
-        //                 info!("Read");
-        //                 if let Err(e) = i2c_slave.respond_to_read(&[io_state]).await {
-        //                     error!("Error: {:?}", e);
-        //                 }
-        //             }
-        //             cmd => {
-        //                 info!("Command: {:?}", cmd);
-        //             }
-        //         }
-        //     }
-        //     Err(e) => {
-        //         error!("Error: {:?}", e);
-        //     }
-        // }
+    }
+}
+
+/// Pass the SPI frames to the device task, see [`spi`]
+#[cfg(spi_transport)]
+#[embassy_executor::task]
+pub async fn spi_task(
+    sck: AnyPin,
+    mosi: AnyPin,
+    miso: AnyPin,
+    cs: AnyPin,
+    dma: [embassy_rp::dma::AnyChannel; 2],
+) -> ! {
+    crate::spi::run(sck, mosi, miso, cs, dma).await
+}
+
+/// Run the USB device the console is a class of
+#[embassy_executor::task]
+pub async fn usb_task(mut usb: UsbDevice<'static, Driver<'static, USB>>) -> ! {
+    usb.run().await
+}
+
+/// Serve the text console over USB, see [`console`]
+#[embassy_executor::task]
+pub async fn console_task(class: CdcAcmClass<'static, Driver<'static, USB>>) -> ! {
+    console::run(class).await
+}
+
+/// Continuously sample the enabled ADC channels, see [`analog`]
+#[embassy_executor::task]
+pub async fn adc_task(
+    mut adc: Adc<'static, adc::Async>,
+    mut channels: [adc::Channel<'static>; board::ADC_PINS],
+    mut temp_sensor: adc::Channel<'static>,
+) -> ! {
+    let available = board::ADC_CHANNELS
+        .iter()
+        .fold(1 << analog::TEMP_SENSOR_CHANNEL, |mask, channel| {
+            mask | 1 << channel
+        });
+    analog::set_available(available);
+    info!("[ADC_TASK] AVAILABLE_CHANNELS: {=u8:05b}", available);
+
+    loop {
+        for (channel, source) in board::ADC_CHANNELS.iter().zip(channels.iter_mut()) {
+            analog::sample(&mut adc, *channel, source).await;
+        }
+        analog::sample(&mut adc, analog::TEMP_SENSOR_CHANNEL, &mut temp_sensor).await;
+        Timer::after_millis(analog::SAMPLE_PERIOD_MS).await;
+    }
+}
+
+/// Apply the timed output changes, see [`timed`]
+#[embassy_executor::task]
+pub async fn timed_output_task() -> ! {
+    timed::run().await
+}
+
+/// Play the output patterns, see [`pattern`]
+#[embassy_executor::task]
+pub async fn pattern_task() -> ! {
+    pattern::run().await
+}
+
+/// Step the stepper motors, see [`stepper`]
+#[embassy_executor::task]
+pub async fn stepper_task() -> ! {
+    stepper::run().await
+}
+
+/// Send the pixels to the WS2812 strip when they're latched, see [`ws2812`]
+#[embassy_executor::task]
+pub async fn ws2812_task(strip: ws2812::Ws2812<'static, PIO0, 0>) -> ! {
+    ws2812::run(strip).await
+}
+
+/// Bridge the UART to its FIFOs, see [`uart`]
+#[embassy_executor::task]
+pub async fn uart_task(uart: BufferedUart<'static, UART0>) -> ! {
+    uart::run(uart).await
+}
+
+/// Move the servos towards their targets, see [`servo`]
+#[embassy_executor::task]
+pub async fn servo_task() -> ! {
+    servo::run().await
+}
+
+/// Continuously measure the pins selected with [`measure::enable`]
+#[embassy_executor::task]
+pub async fn measure_task() -> ! {
+    loop {
+        measure::measure().await;
     }
 }
 
@@ -135,12 +179,20 @@ pub async fn led_task(mut led: Output<'static, P_LED>) -> ! {
     }
 }
 
-/// Trigger the EN_OUT pin on power up to reboot main board
+/// Trigger the EN_OUT pin on power up to reboot main board, releasing it with a
+/// [`timed::pulse`] so it's timed like the pulses on the groups' pins
 #[embassy_executor::task]
 pub async fn trigger_en_out(en_out: P_EN_OUT) {
-    let mut en_out = OutputOpenDrain::new(en_out, Level::Low);
+    let pin = timed::OutputPin { gpio: en_out.pin() };
+    let _en_out = OutputOpenDrain::new(en_out, Level::Low);
+    gpios::publish_open_drain(pin.gpio, true);
     Timer::after_millis(EN_DELAY_MS).await;
-    en_out.set_level(Level::High);
-    Timer::after_millis(EN_DURATION).await;
-    en_out.set_level(Level::Low);
+    if let Err(e) = timed::pulse(pin, true, Duration::from_millis(EN_DURATION)) {
+        error!("[EN_OUT] PULSE: {:?}", e);
+    }
+    // The pin is given up once the pulse has pulled it low again
+    while timed::pulsing() & 1 << pin.gpio != 0 {
+        Timer::after_millis(1).await;
+    }
+    gpios::publish_open_drain(pin.gpio, false);
 }
@@ -0,0 +1,242 @@
+//! Output changes that happen at a set time, either the end of a [`pulse`] or [`schedule`]d by
+//! the host. Scheduled changes are kept in a fixed number of slots the host can read back and
+//! cancel, and the ends of pulses are kept apart from them, so pulsing doesn't use up the slots.
+//! Both are applied by [`crate::tasks::timed_output_task`] on the high priority executor, so
+//! their timing doesn't depend on the I2C traffic.
+//!
+//! The pins are driven directly through SIO, the same registers their [`crate::gpios::PinGroup`]
+//! uses, so a group still reads back the level a timed change left a pin at. A change to a pin
+//! that has stopped being an output since it was queued is dropped when it comes due.
+
+use core::cell::RefCell;
+
+use defmt::Format;
+use embassy_futures::select::select;
+use embassy_rp::pac;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::gpios;
+
+/// Maximum number of scheduled changes, a slot mask fits in a byte
+pub const MAX_CHANGES: usize = 8;
+/// Maximum number of pins pulsing at once
+pub const MAX_PULSES: usize = 8;
+/// [`take_status`] flag set while every slot is in use
+pub const STATUS_FULL: u8 = 0b0000_0001;
+/// [`take_status`] flag set when a change was rejected because every slot was in use
+pub const STATUS_OVERFLOWED: u8 = 0b0000_0010;
+
+struct Pending {
+    scheduled: [Option<Change>; MAX_CHANGES],
+    /// The changes that end pulses
+    pulses: Vec<Change, MAX_PULSES>,
+}
+
+static PENDING: Mutex<CriticalSectionRawMutex, RefCell<Pending>> =
+    Mutex::new(RefCell::new(Pending {
+        scheduled: [None; MAX_CHANGES],
+        pulses: Vec::new(),
+    }));
+static OVERFLOWED: AtomicBool = AtomicBool::new(false);
+/// Wakes the task when a change is added
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub enum Error {
+    /// There's no room for another scheduled change, or another pulse
+    Full,
+    EmptySlot(u8),
+}
+
+/// An output pin, driven through SIO
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub struct OutputPin {
+    pub gpio: u8,
+}
+
+impl OutputPin {
+    fn bit(&self) -> u32 {
+        1 << self.gpio
+    }
+
+    /// The level the pin is latched to
+    pub fn latch(&self) -> bool {
+        pac::SIO.gpio_out(0).value().read() & self.bit() != 0
+    }
+
+    /// Drive the pin to `high` if it's still an output, see [`gpios::drive_gpios`]
+    pub fn set_level(&self, high: bool) {
+        gpios::drive_gpios(self.bit(), if high { self.bit() } else { 0 });
+    }
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub struct Change {
+    pub pin: OutputPin,
+    pub high: bool,
+    pub at: Instant,
+}
+
+fn add(slots: &mut [Option<Change>; MAX_CHANGES], change: Change) -> Result<u8, Error> {
+    let Some((slot, free)) = slots
+        .iter_mut()
+        .enumerate()
+        .find(|(_, slot)| slot.is_none())
+    else {
+        OVERFLOWED.store(true, Ordering::Relaxed);
+        return Err(Error::Full);
+    };
+    *free = Some(change);
+    Ok(slot as u8)
+}
+
+/// Drive `pin` to `high` now and restore its current level after `duration`.
+///
+/// Pulsing a pin that's already pulsing extends the pulse, it's still restored to the level from
+/// before the first one. Writes to the pin during a pulse are overwritten when it ends. Up to
+/// [`MAX_PULSES`] pins can pulse at once, separately from the [`MAX_CHANGES`] scheduled changes.
+pub fn pulse(pin: OutputPin, high: bool, duration: Duration) -> Result<(), Error> {
+    PENDING.lock(|pending| {
+        let pulses = &mut pending.borrow_mut().pulses;
+        let at = Instant::now() + duration;
+        match pulses.iter_mut().find(|change| change.pin.gpio == pin.gpio) {
+            Some(change) => change.at = at,
+            None => {
+                let restore = Change {
+                    pin,
+                    high: pin.latch(),
+                    at,
+                };
+                pulses.push(restore).map_err(|_| Error::Full)?;
+            }
+        }
+        pin.set_level(high);
+        Ok(())
+    })?;
+    CHANGED.signal(());
+    Ok(())
+}
+
+/// Drive `pin` to `high` after `delay`, returning the slot the change is in
+pub fn schedule(pin: OutputPin, high: bool, delay: Duration) -> Result<u8, Error> {
+    let change = Change {
+        pin,
+        high,
+        at: Instant::now() + delay,
+    };
+    let slot = PENDING.lock(|pending| add(&mut pending.borrow_mut().scheduled, change))?;
+    CHANGED.signal(());
+    Ok(slot)
+}
+
+/// Remove the change in `slot` without applying it
+pub fn cancel(slot: u8) -> Result<Change, Error> {
+    PENDING.lock(|pending| {
+        pending
+            .borrow_mut()
+            .scheduled
+            .get_mut(slot as usize)
+            .and_then(Option::take)
+            .ok_or(Error::EmptySlot(slot))
+    })
+}
+
+/// The change in `slot`, if there is one
+pub fn get(slot: u8) -> Option<Change> {
+    PENDING.lock(|pending| {
+        let pending = pending.borrow();
+        pending.scheduled.get(slot as usize).copied().flatten()
+    })
+}
+
+/// Mask of the slots that hold a change
+pub fn slots() -> u8 {
+    PENDING.lock(|pending| {
+        pending
+            .borrow()
+            .scheduled
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_some())
+            .fold(0, |mask, (slot, _)| mask | 1 << slot)
+    })
+}
+
+/// Mask of the GPIOs that are pulsing
+pub fn pulsing() -> u32 {
+    PENDING.lock(|pending| {
+        let pending = pending.borrow();
+        pending
+            .pulses
+            .iter()
+            .fold(0, |mask, change| mask | change.pin.bit())
+    })
+}
+
+/// Number of changes waiting to be applied, scheduled or ending pulses
+pub fn pending() -> usize {
+    let pulses = PENDING.lock(|pending| pending.borrow().pulses.len());
+    slots().count_ones() as usize + pulses
+}
+
+/// Returns the [`STATUS_FULL`] and [`STATUS_OVERFLOWED`] flags, clearing the latter
+pub fn take_status() -> u8 {
+    let full = if slots().count_ones() as usize == MAX_CHANGES {
+        STATUS_FULL
+    } else {
+        0
+    };
+    let overflowed = if OVERFLOWED.swap(false, Ordering::Relaxed) {
+        STATUS_OVERFLOWED
+    } else {
+        0
+    };
+    full | overflowed
+}
+
+/// Apply the pending changes as they come due
+pub(crate) async fn run() -> ! {
+    loop {
+        let next = PENDING.lock(|pending| {
+            let pending = pending.borrow();
+            let scheduled = pending.scheduled.iter().flatten();
+            scheduled
+                .chain(&pending.pulses)
+                .map(|change| change.at)
+                .min()
+        });
+        match next {
+            Some(at) => {
+                select(Timer::at(at), CHANGED.wait()).await;
+            }
+            None => CHANGED.wait().await,
+        }
+
+        apply_due();
+    }
+}
+
+/// Apply the changes that are due now
+pub fn apply_due() {
+    let now = Instant::now();
+    PENDING.lock(|pending| {
+        let mut pending = pending.borrow_mut();
+        for slot in pending.scheduled.iter_mut() {
+            if let Some(change) = slot.take_if(|change| change.at <= now) {
+                change.pin.set_level(change.high);
+            }
+        }
+        pending.pulses.retain(|change| {
+            let due = change.at <= now;
+            if due {
+                change.pin.set_level(change.high);
+            }
+            !due
+        });
+    });
+}
@@ -0,0 +1,175 @@
+//! The transactions the host makes over I2C, or SPI, independent of the transport.
+//!
+//! [`crate::tasks::device_task`] owns the [`Device`] and [`serve`]s the requests each transport
+//! task makes with [`transact`], one at a time, so every transport shares the same pin state.
+//! The requests are applied by a [`Dispatcher`], which doesn't depend on any transport.
+
+use defmt::{error, info, Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use heapless::Vec;
+
+use crate::device::{Device, BANK_SIZE, MAX_RESPONSE_LEN};
+use crate::SET_INT_OUT;
+
+/// Longest write a transport accepts
+pub const MAX_REQUEST_LEN: usize = 128;
+/// [`Request::from_frame`] kind byte of a plain read
+pub const FRAME_READ: u8 = 0x00;
+/// [`Request::from_frame`] kind byte of a write
+pub const FRAME_WRITE: u8 = 0x01;
+/// [`Request::from_frame`] kind byte of a write-read
+pub const FRAME_WRITE_READ: u8 = 0x02;
+/// Byte a response is padded with to the length the host reads
+pub const FILL: u8 = 0x00;
+
+pub type Response = Vec<u8, MAX_RESPONSE_LEN>;
+
+static REQUESTS: Channel<CriticalSectionRawMutex, Request, 1> = Channel::new();
+static RESPONSES: Channel<CriticalSectionRawMutex, Option<Response>, 1> = Channel::new();
+/// Held for a whole transaction, so each response goes back to the transport that asked for it
+static TRANSACTION: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+#[derive(Debug, Clone, Format, Eq, PartialEq)]
+pub enum Request {
+    /// Read the state of every group
+    Read,
+    /// A command that doesn't respond
+    Write(Vec<u8, MAX_REQUEST_LEN>),
+    /// A command that responds with data
+    WriteRead(Vec<u8, MAX_REQUEST_LEN>),
+}
+
+impl Request {
+    /// The request made by a transaction that writes `write` then reads `read_len` bytes, a read
+    /// that doesn't write first reads the inputs
+    pub fn new(write: &[u8], read_len: usize) -> Option<Self> {
+        match (write.is_empty(), read_len) {
+            (true, 0) => None,
+            (true, _) => Some(Self::Read),
+            (false, 0) => Some(Self::write(write)),
+            (false, _) => Some(Self::write_read(write)),
+        }
+    }
+
+    /// A write of `bytes`, cut to [`MAX_REQUEST_LEN`]
+    pub fn write(bytes: &[u8]) -> Self {
+        Self::Write(truncated(bytes))
+    }
+
+    /// A write-read of `bytes`, cut to [`MAX_REQUEST_LEN`]
+    pub fn write_read(bytes: &[u8]) -> Self {
+        Self::WriteRead(truncated(bytes))
+    }
+
+    /// The request in an SPI frame, whose first byte is [`FRAME_READ`], [`FRAME_WRITE`] or
+    /// [`FRAME_WRITE_READ`] followed by the command. Any other kind byte, or an empty frame,
+    /// makes no request and only clocks out the previous response.
+    pub fn from_frame(frame: &[u8]) -> Option<Self> {
+        let (&kind, command) = frame.split_first()?;
+        match kind {
+            FRAME_READ => Some(Self::Read),
+            FRAME_WRITE => Some(Self::write(command)),
+            FRAME_WRITE_READ => Some(Self::write_read(command)),
+            _ => None,
+        }
+    }
+}
+
+fn truncated(bytes: &[u8]) -> Vec<u8, MAX_REQUEST_LEN> {
+    Vec::from_slice(&bytes[..bytes.len().min(MAX_REQUEST_LEN)]).unwrap_or_default()
+}
+
+/// Applies the transactions to a [`Device`], independently of how they reach it
+pub struct Dispatcher<'d, const N: usize> {
+    device: &'d mut Device<N>,
+    response: [u8; MAX_RESPONSE_LEN],
+}
+
+impl<'d, const N: usize> Dispatcher<'d, N> {
+    pub fn new(device: &'d mut Device<N>) -> Self {
+        Self {
+            device,
+            response: [FILL; MAX_RESPONSE_LEN],
+        }
+    }
+
+    pub fn device(&mut self) -> &mut Device<N> {
+        self.device
+    }
+
+    /// Apply `request`, returning the response for a read or a write-read, or `None` for a
+    /// write or a command that failed
+    pub fn handle(&mut self, request: &Request) -> Option<Response> {
+        let mut out = [0u8; MAX_RESPONSE_LEN];
+        let len = match request {
+            Request::Read => {
+                info!("[DISPATCHER] READ");
+                self.device.handle_read_command(out.first_chunk_mut()?);
+                N
+            }
+            Request::Write(bytes) => {
+                info!("[DISPATCHER] WRITE: {:?}", bytes.as_slice());
+                if let Err(e) = self.device.handle_write_command(bytes) {
+                    error!("[DISPATCHER] WRITE_ERROR: {:?}", e);
+                }
+                return None;
+            }
+            Request::WriteRead(bytes) => {
+                info!("[DISPATCHER] WRITE_READ: {:?}", bytes.as_slice());
+                match self.device.handle_write_read_command(bytes, &mut out) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("[DISPATCHER] WRITE_READ_ERROR: {:?}", e);
+                        return None;
+                    }
+                }
+            }
+        };
+        Vec::from_slice(&out[..len]).ok()
+    }
+
+    /// Apply a transaction that writes `write` then reads `read_len` bytes, returning the bytes
+    /// read. The response is padded with [`FILL`] and cut to [`MAX_RESPONSE_LEN`].
+    pub fn dispatch(&mut self, write: &[u8], read_len: usize) -> &[u8] {
+        let response = Request::new(write, read_len)
+            .and_then(|request| self.handle(&request))
+            .unwrap_or_default();
+        let len = read_len.min(MAX_RESPONSE_LEN);
+        self.response.fill(FILL);
+        let copied = response.len().min(len);
+        self.response[..copied].copy_from_slice(&response[..copied]);
+        &self.response[..len]
+    }
+}
+
+/// Have the device task handle `request`, waiting for its response
+pub async fn transact(request: Request) -> Option<Response> {
+    let _transaction = TRANSACTION.lock().await;
+    REQUESTS.send(request).await;
+    RESPONSES.receive().await
+}
+
+/// Handle the transports' requests, asserting INT_OUT on edges in between
+pub(crate) async fn serve<const N: usize>(mut device: Device<N>) -> ! {
+    let mut state = [0u8; BANK_SIZE];
+    device.load_config();
+    let mut dispatcher = Dispatcher::new(&mut device);
+    info!("[TRANSPORT] STARTING");
+    loop {
+        dispatcher.device().read(&mut state);
+        info!("[TRANSPORT] GPIO_STATE: {=[u8;2]:08b}", &state);
+        match select(dispatcher.device().wait_for_any_edge(), REQUESTS.receive()).await {
+            Either::First(trigger) => {
+                if trigger {
+                    SET_INT_OUT.signal(true);
+                }
+            }
+            Either::Second(request) => {
+                RESPONSES.send(dispatcher.handle(&request)).await;
+            }
+        }
+    }
+}
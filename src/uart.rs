@@ -0,0 +1,276 @@
+//! I2C to UART bridge, like an SC16IS750. The board's UART is serviced by
+//! [`crate::tasks::uart_task`], which moves bytes between it and a receive and a transmit FIFO
+//! that the host reads and writes with commands.
+//!
+//! INT_OUT can be raised when the receive FIFO fills to a threshold, when the transmit FIFO
+//! drains to a threshold, or when received bytes were dropped because the receive FIFO was
+//! full. The flags stay set until the host reads the status.
+
+use core::cell::RefCell;
+
+use defmt::{warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_rp::pac;
+use embassy_rp::peripherals::UART0;
+use embassy_rp::uart::{BufferedUart, BufferedUartRx, BufferedUartTx};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embedded_io_async::{Read, Write};
+use heapless::Deque;
+use portable_atomic::{AtomicU8, Ordering};
+
+use crate::SET_INT_OUT;
+
+pub const FIFO_SIZE: usize = 64;
+/// Most bytes a single read returns
+pub const MAX_READ: usize = 32;
+pub const DEFAULT_BAUD: u32 = 115_200;
+/// Flag set when the receive FIFO has filled to its threshold
+pub const UART_RX_READY: u8 = 0b0000_0001;
+/// Flag set when the transmit FIFO has drained to its threshold
+pub const UART_TX_READY: u8 = 0b0000_0010;
+/// Flag set when received bytes were dropped
+pub const UART_OVERRUN: u8 = 0b0000_0100;
+const UART_FLAGS: u8 = UART_RX_READY | UART_TX_READY | UART_OVERRUN;
+/// Bytes moved to or from the UART at a time
+const CHUNK: usize = 16;
+
+static RX: Mutex<CriticalSectionRawMutex, RefCell<Deque<u8, FIFO_SIZE>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+static TX: Mutex<CriticalSectionRawMutex, RefCell<Deque<u8, FIFO_SIZE>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+static FLAGS: AtomicU8 = AtomicU8::new(0);
+/// Flags that raise INT_OUT
+static INT_ENABLED: AtomicU8 = AtomicU8::new(0);
+static RX_THRESHOLD: AtomicU8 = AtomicU8::new(1);
+static TX_THRESHOLD: AtomicU8 = AtomicU8::new(0);
+/// Wakes the task when there are bytes to transmit
+static TX_PENDING: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub enum Error {
+    /// The baud rate is 0 or the format byte is invalid
+    InvalidFormat,
+    /// A threshold is larger than [`FIFO_SIZE`]
+    InvalidThreshold(u8),
+    /// There's only room for this many more bytes in the transmit FIFO
+    TxFull(u8),
+}
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Parity {
+    None = 0,
+    Odd = 1,
+    Even = 2,
+}
+
+/// The line settings, from a baud rate and a format byte whose bits 0-1 are the number of data
+/// bits minus 5, bit 2 selects 2 stop bits and bits 3-4 are the [`Parity`]
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub struct UartConfig {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub two_stop_bits: bool,
+    pub parity: Parity,
+}
+
+impl UartConfig {
+    pub fn new(baud: u32, format: u8) -> Result<Self, Error> {
+        let parity = match format >> 3 & 0b11 {
+            0 => Parity::None,
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            _ => return Err(Error::InvalidFormat),
+        };
+        if baud == 0 || format >> 5 != 0 {
+            return Err(Error::InvalidFormat);
+        }
+        Ok(Self {
+            baud,
+            data_bits: 5 + (format & 0b11),
+            two_stop_bits: format & 0b100 != 0,
+            parity,
+        })
+    }
+}
+
+/// Change the line settings, waiting for the byte being sent to finish
+pub fn configure(config: UartConfig) {
+    let regs = pac::UART0;
+    regs.uartcr().modify(|w| w.set_uarten(false));
+    while regs.uartfr().read().busy() {}
+
+    // The same divisors as embassy_rp::uart, in 64ths
+    let divisor = 8 * embassy_rp::clocks::clk_peri_freq() / config.baud;
+    let (integer, fraction) = match divisor >> 7 {
+        0 => (1, 0),
+        integer @ 1..=65534 => (integer, (divisor & 0x7f).div_ceil(2)),
+        _ => (65535, 0),
+    };
+    regs.uartibrd()
+        .write_value(pac::uart::regs::Uartibrd(integer));
+    regs.uartfbrd()
+        .write_value(pac::uart::regs::Uartfbrd(fraction));
+    // Writing the line control also latches the divisors
+    regs.uartlcr_h().write(|w| {
+        w.set_wlen(config.data_bits - 5);
+        w.set_stp2(config.two_stop_bits);
+        w.set_pen(config.parity != Parity::None);
+        w.set_eps(config.parity == Parity::Even);
+        w.set_fen(true);
+    });
+    regs.uartcr().modify(|w| w.set_uarten(true));
+}
+
+/// Check that both thresholds fit in the FIFOs
+pub fn check_thresholds(rx_threshold: u8, tx_threshold: u8) -> Result<(), Error> {
+    for threshold in [rx_threshold, tx_threshold] {
+        if threshold as usize > FIFO_SIZE {
+            return Err(Error::InvalidThreshold(threshold));
+        }
+    }
+    Ok(())
+}
+
+/// Raise INT_OUT for the `enabled` flags, when the receive FIFO holds `rx_threshold` bytes and
+/// when the transmit FIFO drains to `tx_threshold` bytes
+pub fn set_interrupts(enabled: u8, rx_threshold: u8, tx_threshold: u8) -> Result<(), Error> {
+    check_thresholds(rx_threshold, tx_threshold)?;
+    INT_ENABLED.store(enabled & UART_FLAGS, Ordering::Relaxed);
+    RX_THRESHOLD.store(rx_threshold.max(1), Ordering::Relaxed);
+    TX_THRESHOLD.store(tx_threshold, Ordering::Relaxed);
+    Ok(())
+}
+
+fn raise(flag: u8) {
+    FLAGS.fetch_or(flag, Ordering::Relaxed);
+    if INT_ENABLED.load(Ordering::Relaxed) & flag != 0 {
+        SET_INT_OUT.signal(true);
+    }
+}
+
+/// Returns the flags, clearing them
+pub fn take_flags() -> u8 {
+    FLAGS.swap(0, Ordering::Relaxed)
+}
+
+/// Whether a flag that raises INT_OUT is set, or the receive FIFO is still at its threshold
+/// with [`UART_RX_READY`] raising INT_OUT
+pub fn interrupt_pending() -> bool {
+    let enabled = INT_ENABLED.load(Ordering::Relaxed);
+    let rx_ready =
+        enabled & UART_RX_READY != 0 && rx_level() >= RX_THRESHOLD.load(Ordering::Relaxed);
+    FLAGS.load(Ordering::Relaxed) & enabled != 0 || rx_ready
+}
+
+/// Number of received bytes waiting to be read
+pub fn rx_level() -> u8 {
+    RX.lock(|rx| rx.borrow().len() as u8)
+}
+
+/// Room left in the transmit FIFO
+pub fn tx_free() -> u8 {
+    TX.lock(|tx| (FIFO_SIZE - tx.borrow().len()) as u8)
+}
+
+/// Queue `bytes` to transmit, either all of them or none if they don't fit
+pub fn write(bytes: &[u8]) -> Result<(), Error> {
+    TX.lock(|tx| {
+        let mut tx = tx.borrow_mut();
+        let free = FIFO_SIZE - tx.len();
+        if bytes.len() > free {
+            return Err(Error::TxFull(free as u8));
+        }
+        for &byte in bytes {
+            let _ = tx.push_back(byte);
+        }
+        Ok(())
+    })?;
+    TX_PENDING.signal(());
+    Ok(())
+}
+
+/// Take up to `out.len()` received bytes, returning how many were taken
+pub fn read(out: &mut [u8]) -> usize {
+    RX.lock(|rx| {
+        let mut rx = rx.borrow_mut();
+        let count = out.len().min(rx.len());
+        for byte in out[..count].iter_mut() {
+            *byte = rx.pop_front().unwrap_or_default();
+        }
+        count
+    })
+}
+
+/// Queue bytes received by the UART, dropping those that don't fit
+pub fn receive(bytes: &[u8]) {
+    let (level, dropped) = RX.lock(|rx| {
+        let mut rx = rx.borrow_mut();
+        let dropped = bytes
+            .iter()
+            .filter(|&&byte| rx.push_back(byte).is_err())
+            .count();
+        (rx.len(), dropped)
+    });
+    if dropped > 0 {
+        warn!("[UART] DROPPED {} BYTES", dropped);
+        raise(UART_OVERRUN);
+    }
+    if level >= RX_THRESHOLD.load(Ordering::Relaxed) as usize {
+        raise(UART_RX_READY);
+    }
+}
+
+/// Take up to `out.len()` bytes waiting to be transmitted, returning how many were taken
+pub fn take_tx(out: &mut [u8]) -> usize {
+    let (count, level) = TX.lock(|tx| {
+        let mut tx = tx.borrow_mut();
+        let count = out.len().min(tx.len());
+        for byte in out[..count].iter_mut() {
+            *byte = tx.pop_front().unwrap_or_default();
+        }
+        (count, tx.len())
+    });
+    let threshold = TX_THRESHOLD.load(Ordering::Relaxed) as usize;
+    if count > 0 && level <= threshold && level + count > threshold {
+        raise(UART_TX_READY);
+    }
+    count
+}
+
+async fn run_rx(rx: &mut BufferedUartRx<'static, UART0>) -> ! {
+    let mut buf = [0u8; CHUNK];
+    loop {
+        match rx.read(&mut buf).await {
+            Ok(len) => receive(&buf[..len]),
+            Err(e) => {
+                warn!("[UART] RX_ERROR: {:?}", e);
+                raise(UART_OVERRUN);
+            }
+        }
+    }
+}
+
+async fn run_tx(tx: &mut BufferedUartTx<'static, UART0>) -> ! {
+    let mut buf = [0u8; CHUNK];
+    loop {
+        let len = take_tx(&mut buf);
+        if len == 0 {
+            TX_PENDING.wait().await;
+            continue;
+        }
+        if let Err(e) = tx.write_all(&buf[..len]).await {
+            warn!("[UART] TX_ERROR: {:?}", e);
+        }
+    }
+}
+
+/// Move bytes between the UART and the FIFOs
+pub(crate) async fn run(uart: BufferedUart<'static, UART0>) -> ! {
+    let (mut rx, mut tx) = uart.split();
+    match select(run_rx(&mut rx), run_tx(&mut tx)).await {
+        Either::First(never) | Either::Second(never) => never,
+    }
+}
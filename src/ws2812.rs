@@ -0,0 +1,213 @@
+//! WS2812 (NeoPixel) strip on the board's `ws2812` pin, clocked out by a PIO state machine and
+//! DMA from [`crate::tasks::ws2812_task`].
+//!
+//! Commands only change the pixel buffer here, the strip shows it once it's [`latch`]ed, so
+//! several pixels can be changed at once. The global brightness scales every pixel as it's sent,
+//! the buffer keeps the colours as they were set.
+
+use core::cell::RefCell;
+
+use defmt::Format;
+use embassy_rp::dma::{AnyChannel, Channel};
+use embassy_rp::pio::{
+    Common, Config, Direction, FifoJoin, Instance, PioPin, ShiftConfig, ShiftDirection,
+    StateMachine,
+};
+use embassy_rp::{clocks, into_ref, Peripheral, PeripheralRef};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+use fixed::types::U24F8;
+use portable_atomic::{AtomicU8, Ordering};
+
+pub const MAX_PIXELS: usize = 64;
+/// The strip latches the data once the line has been low for this long
+const RESET_US: u64 = 300;
+const BIT_KHZ: u32 = 800;
+/// PIO cycles in each bit, the high time of a 0, then of a 1, then the low time of a 1
+const CYCLES_PER_BIT: u32 = 2 + 5 + 3;
+
+static PIXELS: Mutex<CriticalSectionRawMutex, RefCell<[Rgb; MAX_PIXELS]>> =
+    Mutex::new(RefCell::new([Rgb::OFF; MAX_PIXELS]));
+static PIXEL_COUNT: AtomicU8 = AtomicU8::new(MAX_PIXELS as u8);
+static BRIGHTNESS: AtomicU8 = AtomicU8::new(u8::MAX);
+/// Wakes the task to send the buffer to the strip
+static LATCH: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug, Clone, Copy, Format, Eq, PartialEq)]
+pub enum Error {
+    /// The pixel is past [`MAX_PIXELS`]
+    InvalidPixel(u8),
+}
+
+#[derive(Debug, Clone, Copy, Default, Format, Eq, PartialEq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const OFF: Self = Self::new(0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// The colour scaled by `brightness`, out of 255
+    pub fn scaled(self, brightness: u8) -> Self {
+        let scale = |channel: u8| (channel as u16 * brightness as u16 / u8::MAX as u16) as u8;
+        Self::new(scale(self.r), scale(self.g), scale(self.b))
+    }
+
+    /// The word the state machine shifts out, GRB from the top byte down
+    pub fn to_word(self) -> u32 {
+        (self.g as u32) << 24 | (self.r as u32) << 16 | (self.b as u32) << 8
+    }
+}
+
+/// Check that pixel `index` exists
+pub fn check(index: usize) -> Result<(), Error> {
+    if index >= MAX_PIXELS {
+        return Err(Error::InvalidPixel(index.min(u8::MAX as usize) as u8));
+    }
+    Ok(())
+}
+
+pub fn set_pixel(index: u8, colour: Rgb) -> Result<(), Error> {
+    check(index as usize)?;
+    PIXELS.lock(|pixels| pixels.borrow_mut()[index as usize] = colour);
+    Ok(())
+}
+
+/// Check that the `count` pixels from `start` exist
+pub fn check_fill(start: u8, count: u8) -> Result<(), Error> {
+    match count {
+        0 => Ok(()),
+        _ => check(start as usize + count as usize - 1),
+    }
+}
+
+/// Set `count` pixels from `start` to `colour`
+pub fn fill(start: u8, count: u8, colour: Rgb) -> Result<(), Error> {
+    check_fill(start, count)?;
+    let (start, count) = (start as usize, count as usize);
+    PIXELS.lock(|pixels| pixels.borrow_mut()[start..start + count].fill(colour));
+    Ok(())
+}
+
+pub fn pixel(index: u8) -> Result<Rgb, Error> {
+    check(index as usize)?;
+    Ok(PIXELS.lock(|pixels| pixels.borrow()[index as usize]))
+}
+
+pub fn brightness() -> u8 {
+    BRIGHTNESS.load(Ordering::Relaxed)
+}
+
+pub fn set_brightness(brightness: u8) {
+    BRIGHTNESS.store(brightness, Ordering::Relaxed);
+}
+
+pub fn pixel_count() -> u8 {
+    PIXEL_COUNT.load(Ordering::Relaxed)
+}
+
+/// Check that `count` pixels fit in the buffer
+pub fn check_count(count: u8) -> Result<(), Error> {
+    if count as usize > MAX_PIXELS {
+        return Err(Error::InvalidPixel(count));
+    }
+    Ok(())
+}
+
+/// Set how many pixels are sent to the strip
+pub fn set_pixel_count(count: u8) -> Result<(), Error> {
+    check_count(count)?;
+    PIXEL_COUNT.store(count, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Send the pixel buffer to the strip
+pub fn latch() {
+    LATCH.signal(());
+}
+
+/// Fill `words` with the pixels to send at the current brightness, returning how many there are
+pub fn frame(words: &mut [u32; MAX_PIXELS]) -> usize {
+    let (count, brightness) = (pixel_count() as usize, brightness());
+    PIXELS.lock(|pixels| {
+        for (word, pixel) in words.iter_mut().zip(pixels.borrow().iter()).take(count) {
+            *word = pixel.scaled(brightness).to_word();
+        }
+    });
+    count
+}
+
+/// A state machine running the WS2812 program on its pin
+pub struct Ws2812<'d, P: Instance, const S: usize> {
+    dma: PeripheralRef<'d, AnyChannel>,
+    sm: StateMachine<'d, P, S>,
+}
+
+impl<'d, P: Instance, const S: usize> Ws2812<'d, P, S> {
+    pub fn new(
+        pio: &mut Common<'d, P>,
+        mut sm: StateMachine<'d, P, S>,
+        dma: impl Peripheral<P = impl Channel> + 'd,
+        pin: impl PioPin,
+    ) -> Self {
+        into_ref!(dma);
+
+        // Each bit starts high, stays high for a 1 and low for a 0, then ends low
+        let program = pio_proc::pio_asm!(
+            ".side_set 1",
+            ".wrap_target",
+            "bitloop:",
+            "    out x, 1        side 0 [2]",
+            "    jmp !x do_zero  side 1 [1]",
+            "    jmp bitloop     side 1 [4]",
+            "do_zero:",
+            "    nop             side 0 [4]",
+            ".wrap",
+        );
+
+        let mut config = Config::default();
+        let pin = pio.make_pio_pin(pin);
+        config.use_program(&pio.load_program(&program.program), &[&pin]);
+        sm.set_pin_dirs(Direction::Out, &[&pin]);
+        // In kHz to not overflow
+        let clock_khz = U24F8::from_num(clocks::clk_sys_freq() / 1000);
+        config.clock_divider = clock_khz / U24F8::from_num(BIT_KHZ * CYCLES_PER_BIT);
+        config.fifo_join = FifoJoin::TxOnly;
+        config.shift_out = ShiftConfig {
+            auto_fill: true,
+            threshold: 24,
+            direction: ShiftDirection::Left,
+        };
+        sm.set_config(&config);
+        sm.set_enable(true);
+
+        Self {
+            dma: dma.map_into(),
+            sm,
+        }
+    }
+
+    /// Send `words` and wait for the strip to latch them
+    pub async fn write(&mut self, words: &[u32]) {
+        self.sm.tx().dma_push(self.dma.reborrow(), words).await;
+        Timer::after_micros(RESET_US).await;
+    }
+}
+
+/// Send the pixel buffer to the strip each time it's latched
+pub(crate) async fn run<P: Instance, const S: usize>(mut strip: Ws2812<'_, P, S>) -> ! {
+    let mut words = [0u32; MAX_PIXELS];
+    loop {
+        LATCH.wait().await;
+        let count = frame(&mut words);
+        strip.write(&words[..count]).await;
+    }
+}
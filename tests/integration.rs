@@ -1,17 +1,13 @@
 #![no_std]
 #![no_main]
 
-use embassy_rp;
-use rp_2040_gpio_expander;
-
 #[defmt_test::tests]
 mod tests {
-    use super::rp_2040_gpio_expander::prelude::*;
-    use super::*;
-    use defmt::{assert, assert_eq, info, unwrap};
-    use embassy_rp::Peripherals;
-    use rp_2040_gpio_expander::device::Device;
-    use rp_2040_gpio_expander::gpios::{PinGroup0, PinGroup1};
+    use defmt::{assert, assert_eq, info, panic, unwrap};
+    use embassy_rp::gpio::{Pin, Pull};
+    use rp_2040_gpio_expander::config::Error as ConfigError;
+    use rp_2040_gpio_expander::device::{Device, Error};
+    use rp_2040_gpio_expander::gpios::PinGroup;
 
     struct State {
         pub device: Device,
@@ -20,42 +16,124 @@ mod tests {
     #[init]
     fn init() -> State {
         let peripherals = embassy_rp::init(Default::default());
-        let gpio_group_0 = PinGroup0::new(
-            peripherals.PIN_6,
-            peripherals.PIN_7,
-            peripherals.PIN_8,
-            peripherals.PIN_9,
-            peripherals.PIN_10,
-            peripherals.PIN_11,
-            peripherals.PIN_12,
-            peripherals.PIN_13,
-        );
-        let gpio_group_1 = PinGroup1::new(
-            peripherals.PIN_14,
-            peripherals.PIN_15,
-            peripherals.PIN_16,
-            peripherals.PIN_17,
-            peripherals.PIN_18,
-            peripherals.PIN_19,
-            peripherals.PIN_20,
-            peripherals.PIN_21,
-        );
-        let device = Device::new(gpio_group_0, gpio_group_1);
+        let gpio_group_0 = PinGroup::new(
+            [
+                peripherals.PIN_6.degrade(),
+                peripherals.PIN_7.degrade(),
+                peripherals.PIN_8.degrade(),
+                peripherals.PIN_9.degrade(),
+                peripherals.PIN_10.degrade(),
+                peripherals.PIN_11.degrade(),
+                peripherals.PIN_12.degrade(),
+                peripherals.PIN_13.degrade(),
+            ],
+            true,
+        );
+        let gpio_group_1 = PinGroup::new(
+            [
+                peripherals.PIN_14.degrade(),
+                peripherals.PIN_15.degrade(),
+                peripherals.PIN_16.degrade(),
+                peripherals.PIN_17.degrade(),
+                peripherals.PIN_18.degrade(),
+                peripherals.PIN_19.degrade(),
+                peripherals.PIN_20.degrade(),
+                peripherals.PIN_21.degrade(),
+            ],
+            false,
+        );
+        let device = Device::new([gpio_group_0, gpio_group_1]);
         State { device }
     }
 
     #[test]
     fn commands_parse_correctly() {
         use rp_2040_gpio_expander::commands::{Error, GpioCommand};
+        use rp_2040_gpio_expander::gpios::DriveStrength;
+        use rp_2040_gpio_expander::measure::Quantity;
 
         let valid_test_cases = [
             ([0x01, 0, 0], GpioCommand::ReadIoModes),
             ([0x02, 3, 12], GpioCommand::WriteAllOutputs(3, 12)),
             ([0x03, 4, 255], GpioCommand::SetIoModes(4, 255)),
+            ([0x0E, 1, 0], GpioCommand::SelectBank(1)),
+            ([0x0F, 0, 0], GpioCommand::ReadBank),
+            ([0x10, 0, 0], GpioCommand::ReadBatchStatus),
             ([0x11, 1, 0], GpioCommand::WriteOutputs1(1)),
             ([0x12, 4, 3], GpioCommand::WriteOutputs2(4)),
+            ([0x13, 0, 0], GpioCommand::ReadOutputLatch),
+            ([0x20, 0, 0], GpioCommand::ReadSnapshot),
+            ([0x52, 1, 2], GpioCommand::StageIoModes(1, 2)),
+            ([0x53, 3, 4], GpioCommand::StagePullUps(3, 4)),
+            ([0x54, 5, 6], GpioCommand::StagePullDowns(5, 6)),
+            ([0x55, 7, 8], GpioCommand::StageOutputs(7, 8)),
+            ([0x56, 9, 10], GpioCommand::StagePolarity(9, 10)),
+            ([0x57, 0, 0], GpioCommand::CommitStaged),
+            ([0x58, 0, 0], GpioCommand::DiscardStaged),
             ([0x21, 255, 255], GpioCommand::ReadInputs1),
             ([0x22, 255, 255], GpioCommand::ReadInputs2),
+            (
+                [0x40, 0b1000_0001, 0],
+                GpioCommand::SetPolarity(0b1000_0001, 0),
+            ),
+            ([0x41, 0, 0], GpioCommand::ReadPolarity),
+            (
+                [0x42, 0b0000_0011, 1],
+                GpioCommand::SetOpenDrain(0b0000_0011, 1),
+            ),
+            ([0x43, 0, 0], GpioCommand::ReadOpenDrain),
+            (
+                [0x44, 0b0000_0001, 0b1000_0000],
+                GpioCommand::SetDriveStrength(0b0000_0001, 0b1000_0000, DriveStrength::_2mA),
+            ),
+            (
+                [0x45, 0b1111_0000, 0],
+                GpioCommand::SetSlewRates(0b1111_0000, 0),
+            ),
+            (
+                [0x46, 0, 0b0000_1111],
+                GpioCommand::SetSchmitt(0, 0b0000_1111),
+            ),
+            ([0x47, 1, 0], GpioCommand::ReadDriveStrength(1)),
+            ([0x60, 4, 0], GpioCommand::ReadAnalog(4)),
+            (
+                [0x61, 0b0001_0110, 0],
+                GpioCommand::SetAnalogChannels(0b0001_0110),
+            ),
+            ([0x62, 0, 0], GpioCommand::ReadAnalogChannels),
+            ([0x63, 16, 0], GpioCommand::SetAnalogAveraging(16)),
+            ([0x66, 0, 0], GpioCommand::ReadAnalogFlags),
+            ([0x70, 0x10, 0], GpioCommand::SetCountRising(0x10, 0)),
+            ([0x71, 0, 0x20], GpioCommand::SetCountFalling(0, 0x20)),
+            ([0x72, 0, 0], GpioCommand::ReadCountRising),
+            ([0x73, 0, 0], GpioCommand::ReadCountFalling),
+            ([0x74, 1, 7], GpioCommand::ReadCounter(1, 7)),
+            ([0x75, 0xFF, 1], GpioCommand::ClearCounts(0xFF, 1)),
+            ([0x81, 1, 0], GpioCommand::ClearEncoder(1)),
+            ([0x82, 0, 0], GpioCommand::ReadEncoder(0)),
+            ([0x83, 1, 0], GpioCommand::ResetEncoder(1)),
+            ([0x93, 7, 0], GpioCommand::CancelScheduled(7)),
+            ([0x94, 0, 0], GpioCommand::ReadSchedule),
+            ([0x95, 2, 0], GpioCommand::ReadScheduled(2)),
+            ([0xA2, 1, 0], GpioCommand::StartPattern(1)),
+            ([0xA3, 2, 0], GpioCommand::PausePattern(2)),
+            ([0xA4, 3, 0], GpioCommand::StopPattern(3)),
+            ([0xA5, 0, 0], GpioCommand::ReadPattern(0)),
+            ([0xB1, 3, 0], GpioCommand::ClearRule(3)),
+            ([0xC0, 1, 1], GpioCommand::SetServo(1, 1, 1)),
+            ([0xC5, 0, 2], GpioCommand::ReadServo(0, 2)),
+            ([0xD3, 1, 0], GpioCommand::StopStepper(1)),
+            ([0xE2, 128, 0], GpioCommand::SetPixelBrightness(128)),
+            ([0xE4, 0, 0], GpioCommand::LatchPixels),
+            ([0xF3, 8, 0], GpioCommand::ReadUart(8)),
+            ([0xF4, 0, 0], GpioCommand::ReadUartStatus),
+            ([0xD6, 0, 0], GpioCommand::ReadStepperStatus),
+            ([0xB2, 4, 0], GpioCommand::ReadRule(4)),
+            ([0xB3, 5, 0], GpioCommand::ResetLatch(5)),
+            ([0x78, 0x20, 0], GpioCommand::SetMeasured(0x20, 0)),
+            ([0x79, 0, 0], GpioCommand::ReadMeasured),
+            ([0x7A, 0xE8, 0x03], GpioCommand::SetMeasureGate(1000)),
+            ([0x50, 0, 0], GpioCommand::SaveConfig),
         ];
 
         for (input, expected) in valid_test_cases.iter() {
@@ -66,7 +144,54 @@ mod tests {
             }
         }
 
-        let invalid_test_cases = [[0x04, 0, 0], [0x05, 0, 0], [0x06, 0, 0]];
+        let invalid_test_cases = [[0x04, 0, 0], [0x05, 0, 0], [0x06, 0, 0], [0x44, 0, 0]];
+
+        let threshold = GpioCommand::from_bytes(&[0x64, 1, 0x00, 0x01, 0xFF, 0x0E]);
+        assert_eq!(
+            threshold,
+            Ok(GpioCommand::SetAnalogThreshold(1, 0x100, 0xEFF))
+        );
+        let hysteresis = GpioCommand::from_bytes(&[0x65, 4, 0x20, 0x00]);
+        assert_eq!(hysteresis, Ok(GpioCommand::SetAnalogHysteresis(4, 0x20)));
+        let encoder = GpioCommand::from_bytes(&[0x80, 0, 4, 5, 0xFF, 1]);
+        assert_eq!(encoder, Ok(GpioCommand::SetEncoder(0, 4, 5, 0xFF, 1)));
+        let pulse = GpioCommand::from_bytes(&[0x90, 1, 2, 1, 0xF4, 0x01]);
+        assert_eq!(pulse, Ok(GpioCommand::Pulse(1, 2, 1, 500)));
+        let pulse = GpioCommand::from_bytes(&[0x91, 0, 3, 0, 0x0A, 0x00]);
+        assert_eq!(pulse, Ok(GpioCommand::PulseMicros(0, 3, 0, 10)));
+        let schedule = GpioCommand::from_bytes(&[0x92, 1, 2, 1, 0x32, 0x00]);
+        assert_eq!(schedule, Ok(GpioCommand::ScheduleOutput(1, 2, 1, 50)));
+        let pattern = GpioCommand::from_bytes(&[0xA0, 1, 0x0F, 0xF0, 3]);
+        assert_eq!(pattern, Ok(GpioCommand::DefinePattern(1, 0x0F, 0xF0, 3)));
+        let step = GpioCommand::from_bytes(&[0xA1, 1, 0x05, 0x50, 0x64, 0x00]);
+        assert_eq!(step, Ok(GpioCommand::AddPatternStep(1, 0x05, 0x50, 100)));
+        let servo = GpioCommand::from_bytes(&[0xC1, 0, 1, 0x84, 0x03, 0xE4, 0x0C]);
+        assert_eq!(servo, Ok(GpioCommand::SetServoLimits(0, 1, 900, 3300)));
+        let servo = GpioCommand::from_bytes(&[0xC3, 1, 2, 0xDC, 0x05]);
+        assert_eq!(servo, Ok(GpioCommand::WriteServoMicros(1, 2, 1500)));
+        let step = GpioCommand::from_bytes(&[0xD2, 1, 0x38, 0xFF, 0xFF, 0xFF, 0xE8, 0x03, 0x64, 0]);
+        assert_eq!(step, Ok(GpioCommand::MoveStepper(1, -200, 1000, 100)));
+        let step = GpioCommand::from_bytes(&[0xD4, 0, 0x10, 0x27, 0, 0]);
+        assert_eq!(step, Ok(GpioCommand::SetStepperPosition(0, 10_000)));
+        let fill = GpioCommand::from_bytes(&[0xE1, 2, 10, 0xFF, 0x80, 0]);
+        assert_eq!(fill, Ok(GpioCommand::FillPixels(2, 10, 0xFF, 0x80, 0)));
+        let uart = GpioCommand::from_bytes(&[0xF0, 0x00, 0xC2, 0x01, 0x00, 0b1_0011]);
+        assert_eq!(uart, Ok(GpioCommand::SetUartConfig(115_200, 0b1_0011)));
+        let uart = GpioCommand::from_bytes(&[0xF2, 3, b'a', b'b', b'c']);
+        assert_eq!(uart, Ok(GpioCommand::WriteUart(3)));
+        let uart = GpioCommand::from_bytes(&[0xF2, 3, b'a', b'b']);
+        assert_eq!(uart, Err(Error::BadOffset));
+        let rule = GpioCommand::from_bytes(&[0xB0, 2, 0, 0x30, 1, 7, 5]);
+        assert_eq!(rule, Ok(GpioCommand::SetRule(2, 0, 0x30, 1, 7, 5)));
+        let measurement = GpioCommand::from_bytes(&[0x7B, 1, 3, 2]);
+        assert_eq!(
+            measurement,
+            Ok(GpioCommand::ReadMeasurement(1, 3, Quantity::HighTime))
+        );
+        let bad_quantity = GpioCommand::from_bytes(&[0x7B, 1, 3, 4]);
+        assert_eq!(bad_quantity, Err(Error::BadInput));
+        let truncated = GpioCommand::from_bytes(&[0x64, 1, 0x00, 0x01, 0xFF]);
+        assert_eq!(truncated, Err(Error::BadOffset));
 
         for input in invalid_test_cases.iter() {
             let result = GpioCommand::from_bytes(input);
@@ -185,7 +310,7 @@ mod tests {
 
         for i in 0..4 {
             let write = [0b0000_0001 << i, 0b0000_0000];
-            let expected = [write[0] | (0b0000_0001 << i + 4), 0b0000_0000];
+            let expected = [write[0] | (0b0000_0001 << (i + 4)), 0b0000_0000];
             info!("Writing: {=[u8;2]:08b}", &write);
             state.device.write(&write);
             state.device.read(&mut buf);
@@ -196,7 +321,7 @@ mod tests {
             assert_eq!(buf, expected);
 
             let write = [0b0000_0000, 0b0000_0001 << i];
-            let expected = [0b0000_0000, write[1] | (0b0000_0001 << i + 4)];
+            let expected = [0b0000_0000, write[1] | (0b0000_0001 << (i + 4))];
             info!("Writing: {=[u8;2]:08b}", &write);
             state.device.write(&write);
             state.device.read(&mut buf);
@@ -215,7 +340,7 @@ mod tests {
 
         for i in 4..8 {
             let write = [0b0000_0001 << i, 0b0000_0000];
-            let expected = [write[0] | (0b0000_0001 << i - 4), 0b0000_0000];
+            let expected = [write[0] | (0b0000_0001 << (i - 4)), 0b0000_0000];
             info!("Writing: {=[u8;2]:08b}", &write);
             state.device.write(&write);
             state.device.read(&mut buf);
@@ -226,7 +351,7 @@ mod tests {
             assert_eq!(buf, expected);
 
             let write = [0b0000_0000, 0b0000_0001 << i];
-            let expected = [0b0000_0000, (write[1] | 0b0000_0001 << i - 4)];
+            let expected = [0b0000_0000, (write[1] | 0b0000_0001 << (i - 4))];
             info!("Writing: {=[u8;2]:08b}", &write);
             state.device.write(&write);
             state.device.read(&mut buf);
@@ -237,4 +362,1521 @@ mod tests {
             assert_eq!(buf, expected);
         }
     }
+
+    #[test]
+    fn input_polarity_inverts_reads(state: &mut State) {
+        let mut buf = [0u8; 2];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.write(&[0b0000_0000, 0b0000_0000]);
+
+        // Outputs are unaffected by polarity, only the (looped back) inputs are inverted
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x40, 0b1111_1111, 0b0011_0000]));
+        unwrap!(state.device.handle_write_read_command(&[0x41], &mut buf));
+        assert_eq!(buf, [0b1111_1111, 0b0011_0000]);
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b1111_0000, 0b0011_0000]);
+
+        state.device.write(&[0b0000_0001, 0b0000_0001]);
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b1110_0001, 0b0010_0001]);
+
+        unwrap!(state.device.handle_write_command(&[0x40, 0, 0]));
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0001_0001, 0b0001_0001]);
+    }
+
+    #[test]
+    fn open_drain_outputs_release_when_high(state: &mut State) {
+        let mut buf = [0u8; 2];
+        state.device.set_pin_polarity(&[0, 0]);
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.write(&[0b0000_0000, 0b0000_0000]);
+
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x42, 0b0000_0011, 0b0000_0001]));
+        unwrap!(state.device.handle_write_read_command(&[0x43], &mut buf));
+        assert_eq!(buf, [0b0000_0011, 0b0000_0001]);
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0000_0000, 0b0000_0000]);
+
+        // Released open drain outputs are pulled high by the pull ups on both ends of the loop
+        state.device.write(&[0b0000_0011, 0b0000_0001]);
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0011_0011, 0b0001_0001]);
+
+        state.device.write(&[0b0000_0001, 0b0000_0000]);
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0001_0001, 0b0000_0000]);
+
+        state.device.set_pin_open_drain(&[0, 0]);
+        state.device.write(&[0b0000_0011, 0b0000_0001]);
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0011_0011, 0b0001_0001]);
+    }
+
+    #[test]
+    fn pad_config_round_trips(state: &mut State) {
+        let mut buf = [0u8; 2];
+
+        // Defaults match the RP2040's pad reset values: 4mA, slow slew, Schmitt enabled
+        unwrap!(state.device.handle_write_read_command(&[0x47, 0], &mut buf));
+        assert_eq!(u16::from_le_bytes(buf), 0b0101_0101_0101_0101);
+
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x44, 0b1000_0001, 0, 3]));
+        unwrap!(state.device.handle_write_read_command(&[0x47, 0], &mut buf));
+        assert_eq!(u16::from_le_bytes(buf), 0b1101_0101_0101_0111);
+        unwrap!(state.device.handle_write_read_command(&[0x47, 1], &mut buf));
+        assert_eq!(u16::from_le_bytes(buf), 0b0101_0101_0101_0101);
+
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x45, 0b1111_0000, 0b0000_0001]));
+        unwrap!(state.device.handle_write_read_command(&[0x48], &mut buf));
+        assert_eq!(buf, [0b1111_0000, 0b0000_0001]);
+
+        unwrap!(state.device.handle_write_command(&[0x46, 0, 0b1111_1110]));
+        unwrap!(state.device.handle_write_read_command(&[0x49], &mut buf));
+        assert_eq!(buf, [0, 0b1111_1110]);
+
+        let result = state.device.handle_write_read_command(&[0x47, 2], &mut buf);
+        assert_eq!(result, Err(Error::InvalidGroup(2)));
+    }
+
+    #[test]
+    fn config_snapshot_restores_pin_state(state: &mut State) {
+        let mut buf = [0u8; 2];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_0011]);
+        state.device.set_pin_polarity(&[0b0001_0000, 0]);
+        state.device.set_pin_open_drain(&[0b0000_0001, 0]);
+        state.device.set_pin_pulls(&[0, 0b1000_0000], Pull::Down);
+        let config = state.device.config();
+
+        state.device.set_pin_modes(&[0, 0]);
+        state.device.set_pin_polarity(&[0, 0]);
+        state.device.set_pin_open_drain(&[0, 0]);
+        state.device.apply_config(&config);
+
+        assert_eq!(state.device.config(), config);
+        state.device.get_pin_modes(&mut buf);
+        assert_eq!(buf, [0b0000_1111, 0b0000_0011]);
+        assert_eq!(config.groups[1].pull_downs, 0b1000_0000);
+        assert_eq!(config.groups[1].pull_ups, 0b0111_1111);
+
+        // No storage is attached in tests
+        let result = state.device.handle_write_command(&[0x50]);
+        assert_eq!(result, Err(Error::Config(ConfigError::NoStorage)));
+    }
+
+    #[test]
+    fn bank_selection_is_bounded(state: &mut State) {
+        let mut buf = [0u8; 2];
+        unwrap!(state.device.handle_write_command(&[0x0E, 0]));
+        unwrap!(state.device.handle_write_read_command(&[0x0F], &mut buf));
+        assert_eq!(buf[0], 0);
+
+        // Only 2 groups, so there's only a single bank
+        let result = state.device.handle_write_command(&[0x0E, 1]);
+        assert_eq!(result, Err(Error::InvalidBank(1)));
+        assert_eq!(state.device.get_bank(), 0);
+
+        let mut all = [0u8; 2];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.set_pin_polarity(&[0, 0]);
+        state.device.set_pin_open_drain(&[0, 0]);
+        state.device.write(&[0b0000_0101, 0b0000_1010]);
+        state.device.read(&mut buf);
+        state.device.handle_read_command(&mut all);
+        assert_eq!(buf, all);
+        assert_eq!(all, [0b0101_0101, 0b1010_1010]);
+    }
+
+    #[test]
+    fn analog_channels_need_the_adc_task(state: &mut State) {
+        let mut buf = [0u8; 2];
+        // The ADC task isn't running, so no channels are available
+        unwrap!(state.device.handle_write_command(&[0x61, 0b0001_1111]));
+        unwrap!(state.device.handle_write_read_command(&[0x62], &mut buf));
+        assert_eq!(buf, [0, 0]);
+
+        let result = state.device.handle_write_read_command(&[0x60, 4], &mut buf);
+        assert_eq!(result, Err(Error::InvalidAnalogChannel(4)));
+
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x64, 4, 0, 0, 0xFF, 0xFF]));
+        let result = state.device.handle_write_command(&[0x65, 5, 0, 0]);
+        assert_eq!(result, Err(Error::InvalidAnalogChannel(5)));
+        unwrap!(state.device.handle_write_read_command(&[0x66], &mut buf));
+        assert_eq!(buf, [0, 0]);
+    }
+
+    #[test]
+    fn edge_counters_count_logical_edges(state: &mut State) {
+        use rp_2040_gpio_expander::edges;
+        let mut buf = [0u8; 4];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.set_pin_polarity(&[0, 0]);
+        state.device.write(&[0, 0]);
+        // Drop the edges latched by earlier tests, the GPIO interrupt isn't installed
+        edges::handle();
+        edges::take_changed();
+
+        // P4 counts rising edges, P5 falling edges and P6 both
+        unwrap!(state.device.handle_write_command(&[0x70, 0b0101_0000, 0]));
+        unwrap!(state.device.handle_write_command(&[0x71, 0b0110_0000, 0]));
+        unwrap!(state.device.handle_write_read_command(&[0x72], &mut buf));
+        assert_eq!(buf[..2], [0b0101_0000, 0]);
+
+        let group = unwrap!(state.device.group_mut(0));
+        assert!(group.counts_edges());
+        state.device.write(&[0b0000_0111, 0]);
+        edges::handle();
+        let changed = unwrap!(state.device.group(0)).pin_mask(edges::take_changed());
+        assert_eq!(changed, 0b0111_0111);
+        state.device.write(&[0, 0]);
+        edges::handle();
+
+        for (pin, expected) in [(4, 1u32), (5, 1), (6, 2), (7, 0)] {
+            let len = unwrap!(state
+                .device
+                .handle_write_read_command(&[0x74, 0, pin], &mut buf));
+            assert_eq!(len, 4);
+            assert_eq!(u32::from_le_bytes(buf), expected);
+        }
+        // Reading a counter clears it
+        unwrap!(state
+            .device
+            .handle_write_read_command(&[0x74, 0, 6], &mut buf));
+        assert_eq!(u32::from_le_bytes(buf), 0);
+
+        // A pulse is counted from the latched edges, however short
+        state.device.write(&[0b0000_0001, 0]);
+        state.device.write(&[0, 0]);
+        edges::handle();
+        unwrap!(state
+            .device
+            .handle_write_read_command(&[0x74, 0, 4], &mut buf));
+        assert_eq!(u32::from_le_bytes(buf), 1);
+
+        state.device.write(&[0b0000_0100, 0]);
+        edges::handle();
+        unwrap!(state.device.handle_write_command(&[0x75, 0b0100_0000, 0]));
+        unwrap!(state
+            .device
+            .handle_write_read_command(&[0x74, 0, 6], &mut buf));
+        assert_eq!(u32::from_le_bytes(buf), 0);
+
+        let result = state
+            .device
+            .handle_write_read_command(&[0x74, 0, 8], &mut buf);
+        assert_eq!(result, Err(Error::InvalidPin(8)));
+        let result = state
+            .device
+            .handle_write_read_command(&[0x74, 2, 0], &mut buf);
+        assert_eq!(result, Err(Error::InvalidGroup(2)));
+        let result = state
+            .device
+            .handle_write_read_command(&[0x74, 0, 4], &mut buf[..2]);
+        assert_eq!(result, Err(Error::ResponseTooLong));
+
+        unwrap!(state.device.handle_write_command(&[0x70, 0, 0]));
+        unwrap!(state.device.handle_write_command(&[0x71, 0, 0]));
+        assert!(!unwrap!(state.device.group(0)).counts_edges());
+    }
+
+    #[test]
+    fn measurements_need_odd_input_pins(state: &mut State) {
+        let mut buf = [0u8; 4];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+
+        // P5 of group 0 is GPIO 11, P4 is GPIO 10 which has no PWM B input
+        unwrap!(state.device.handle_write_command(&[0x78, 0b0010_0000, 0]));
+        unwrap!(state.device.handle_write_read_command(&[0x79], &mut buf));
+        assert_eq!(buf[..2], [0b0010_0000, 0]);
+        let result = state.device.handle_write_command(&[0x78, 0b0001_0000, 0]);
+        assert_eq!(result, Err(Error::CantMeasure(10)));
+
+        // The measure task isn't running, so nothing has been measured yet
+        let len = unwrap!(state
+            .device
+            .handle_write_read_command(&[0x7B, 0, 5, 0], &mut buf));
+        assert_eq!(len, 4);
+        assert_eq!(u32::from_le_bytes(buf), 0);
+        let result = state
+            .device
+            .handle_write_read_command(&[0x7B, 0, 4, 0], &mut buf);
+        assert_eq!(result, Err(Error::NotMeasured(4)));
+
+        unwrap!(state.device.handle_write_command(&[0x78, 0, 0]));
+        unwrap!(state.device.handle_write_read_command(&[0x79], &mut buf));
+        assert_eq!(buf[..2], [0, 0]);
+
+        // The loop still works once the pins are back on SIO
+        let mut inputs = [0u8; 2];
+        state.device.write(&[0b0000_0010, 0]);
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0010_0010, 0]);
+    }
+
+    #[test]
+    fn measurements_are_scaled_between_gates() {
+        use rp_2040_gpio_expander::measure::Measurement;
+
+        // 100 edges and 25ms high in 100ms gates is a 1kHz signal at 25% duty
+        let measurement = Measurement::from_counts(100, 100_000, 25_000, 100_000);
+        assert_eq!(measurement.frequency, 1000);
+        assert_eq!(measurement.period_us, 1000);
+        assert_eq!(measurement.high_us, 250);
+        assert_eq!(measurement.low_us, 750);
+
+        // The level gate ran twice as long as the edge gate
+        let measurement = Measurement::from_counts(100, 100_000, 50_000, 200_000);
+        assert_eq!(measurement.high_us, 250);
+
+        assert_eq!(
+            Measurement::from_counts(0, 100_000, 0, 100_000),
+            Measurement::NONE
+        );
+    }
+
+    #[test]
+    fn encoder_decodes_quadrature(state: &mut State) {
+        use rp_2040_gpio_expander::edges;
+        let mut buf = [0u8; 4];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.set_pin_polarity(&[0, 0]);
+        state.device.write(&[0, 0]);
+
+        // A on P4, B on P5 and index on P6, driven by P0, P1 and P2
+        unwrap!(state.device.handle_write_command(&[0x80, 0, 4, 5, 6, 1]));
+        let position = |state: &mut State, buf: &mut [u8; 4]| {
+            unwrap!(state.device.handle_write_read_command(&[0x82, 0], buf));
+            i32::from_le_bytes(*buf)
+        };
+        // Step to the A/B state `ab`, P0 drives A and P1 drives B
+        let step = |state: &mut State, ab: u8, index: u8| {
+            state
+                .device
+                .write(&[ab >> 1 | (ab & 1) << 1 | index << 2, 0]);
+            edges::handle();
+            unwrap!(state.device.group(0)).encoder_moved(edges::take_moved())
+        };
+
+        // A leads B when turning forwards
+        for ab in [0b10, 0b11, 0b01, 0b00] {
+            assert!(step(state, ab, 0));
+        }
+        assert_eq!(position(state, &mut buf), 4);
+        for ab in [0b01, 0b11] {
+            assert!(step(state, ab, 0));
+        }
+        assert_eq!(position(state, &mut buf), 2);
+
+        // Skipping a state is ignored
+        assert!(!step(state, 0b00, 0));
+        assert_eq!(position(state, &mut buf), 2);
+
+        // The index resets the position on its rising edge
+        assert!(step(state, 0b00, 1));
+        assert_eq!(position(state, &mut buf), 0);
+
+        assert!(step(state, 0b10, 1));
+        unwrap!(state.device.handle_write_command(&[0x83, 0]));
+        assert_eq!(position(state, &mut buf), 0);
+        assert_eq!(unwrap!(state.device.group(0)).quiet_pins(), 0b0111_0000);
+
+        let result = state.device.handle_write_command(&[0x80, 0, 4, 4, 0xFF, 0]);
+        assert_eq!(result, Err(Error::InvalidPin(4)));
+        let result = state.device.handle_write_command(&[0x80, 0, 4, 8, 0xFF, 0]);
+        assert_eq!(result, Err(Error::InvalidPin(8)));
+        let result = state.device.handle_write_command(&[0x80, 0, 4, 0, 0xFF, 0]);
+        assert_eq!(result, Err(Error::NotAnInput(0)));
+        unwrap!(state.device.handle_write_command(&[0x81, 0]));
+        let result = state.device.handle_write_read_command(&[0x82, 0], &mut buf);
+        assert_eq!(result, Err(Error::NoEncoder(0)));
+    }
+
+    #[test]
+    fn pulses_restore_the_previous_level(state: &mut State) {
+        use embassy_time::{block_for, Duration};
+        use rp_2040_gpio_expander::timed;
+
+        let mut buf = [0u8; 2];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.set_pin_open_drain(&[0b0000_0010, 0]);
+        state.device.write(&[0b0000_0010, 0]);
+
+        // 2ms high pulse on push-pull P0, and 2ms low pulse on open drain P1
+        unwrap!(state.device.handle_write_command(&[0x90, 0, 0, 1, 2, 0]));
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x91, 0, 1, 0, 0xD0, 0x07]));
+        assert_eq!(timed::pending(), 2);
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0001_0001, 0]);
+
+        block_for(Duration::from_millis(3));
+        timed::apply_due();
+        assert_eq!(timed::pending(), 0);
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0010_0010, 0]);
+
+        let result = state.device.handle_write_command(&[0x90, 0, 4, 1, 2, 0]);
+        assert_eq!(result, Err(Error::NotAnOutput(4)));
+        let result = state.device.handle_write_command(&[0x90, 0, 8, 1, 2, 0]);
+        assert_eq!(result, Err(Error::InvalidPin(8)));
+        state.device.set_pin_open_drain(&[0, 0]);
+    }
+
+    #[test]
+    fn scheduled_outputs_fill_their_slots(state: &mut State) {
+        use embassy_time::{block_for, Duration};
+        use rp_2040_gpio_expander::device::SCHEDULED_HIGH;
+        use rp_2040_gpio_expander::timed::{self, Error as TimedError, MAX_CHANGES};
+
+        let mut buf = [0u8; 4];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.write(&[0, 0]);
+
+        // P2 of group 1 high in 2ms, then low 1s later
+        unwrap!(state
+            .device
+            .handle_write_read_command(&[0x92, 1, 2, 1, 2, 0], &mut buf));
+        assert_eq!(buf[0], 0);
+        unwrap!(state
+            .device
+            .handle_write_read_command(&[0x92, 1, 2, 0, 0xE8, 0x03], &mut buf));
+        assert_eq!(buf[0], 1);
+        unwrap!(state.device.handle_write_read_command(&[0x95, 0], &mut buf));
+        assert_eq!(buf[..2], [1 << 3 | 2, SCHEDULED_HIGH]);
+        assert!(u16::from_le_bytes([buf[2], buf[3]]) <= 2);
+
+        block_for(Duration::from_millis(3));
+        timed::apply_due();
+        let mut inputs = [0u8; 2];
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0b0100_0100]);
+        unwrap!(state.device.handle_write_read_command(&[0x94], &mut buf));
+        assert_eq!(buf[..2], [0b0000_0010, 0]);
+
+        // Pulses don't take a slot
+        unwrap!(state.device.handle_write_command(&[0x90, 0, 0, 1, 2, 0]));
+        unwrap!(state.device.handle_write_read_command(&[0x94], &mut buf));
+        assert_eq!(buf[..2], [0b0000_0010, 0]);
+
+        for slot in (0..MAX_CHANGES as u8).filter(|&slot| slot != 1) {
+            let len = unwrap!(state
+                .device
+                .handle_write_read_command(&[0x92, 0, 1, 1, 0xE8, 0x03], &mut buf));
+            assert_eq!((len, buf[0]), (1, slot));
+        }
+        let result = state
+            .device
+            .handle_write_read_command(&[0x92, 0, 1, 1, 0xE8, 0x03], &mut buf);
+        assert_eq!(result, Err(Error::Timed(TimedError::Full)));
+        unwrap!(state.device.handle_write_read_command(&[0x94], &mut buf));
+        assert_eq!(
+            buf[..2],
+            [0xFF, timed::STATUS_FULL | timed::STATUS_OVERFLOWED]
+        );
+
+        for slot in 0..MAX_CHANGES as u8 {
+            unwrap!(state.device.handle_write_command(&[0x93, slot]));
+        }
+        let result = state.device.handle_write_command(&[0x93, 0]);
+        assert_eq!(result, Err(Error::Timed(TimedError::EmptySlot(0))));
+        unwrap!(state.device.handle_write_read_command(&[0x94], &mut buf));
+        assert_eq!(buf[..2], [0, 0]);
+        // The pulse still ends
+        block_for(Duration::from_millis(3));
+        timed::apply_due();
+        assert_eq!(timed::pending(), 0);
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0b0100_0100]);
+    }
+
+    #[test]
+    fn patterns_play_their_steps(state: &mut State) {
+        use embassy_time::{block_for, Duration};
+        use rp_2040_gpio_expander::pattern::{self, Error as PatternError};
+
+        let mut buf = [0u8; 4];
+        let mut inputs = [0u8; 2];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.write(&[0, 0]);
+
+        // P0 then P1 of group 0 for 10ms each, played once
+        unwrap!(state.device.handle_write_command(&[0xA0, 0, 0b0011, 0, 1]));
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xA1, 0, 0b01, 0, 10, 0]));
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xA1, 0, 0b10, 0, 10, 0]));
+        unwrap!(state.device.handle_write_command(&[0xA2, 0]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0001_0001, 0]);
+        unwrap!(state.device.handle_write_read_command(&[0xA5, 0], &mut buf));
+        assert_eq!(buf, [pattern::State::Playing as u8, 0, 2, 0]);
+
+        block_for(Duration::from_millis(12));
+        pattern::advance_due();
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0010_0010, 0]);
+
+        // The last step is left on the pins once the pattern ends
+        block_for(Duration::from_millis(10));
+        pattern::advance_due();
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0010_0010, 0]);
+        unwrap!(state.device.handle_write_read_command(&[0xA5, 0], &mut buf));
+        assert_eq!(buf, [pattern::State::Stopped as u8, 1, 2, 1]);
+
+        // A second pattern on group 1 repeating forever
+        unwrap!(state.device.handle_write_command(&[0xA0, 1, 0, 0b0001, 0]));
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xA1, 1, 0, 0b0001, 10, 0]));
+        unwrap!(state.device.handle_write_command(&[0xA2, 1]));
+        unwrap!(state.device.handle_write_command(&[0xA3, 1]));
+        unwrap!(state.device.handle_write_read_command(&[0xA5, 1], &mut buf));
+        assert_eq!(buf[0], pattern::State::Paused as u8);
+        unwrap!(state.device.handle_write_command(&[0xA2, 1]));
+        unwrap!(state.device.handle_write_read_command(&[0xA5, 1], &mut buf));
+        assert_eq!(buf[0], pattern::State::Playing as u8);
+        unwrap!(state.device.handle_write_command(&[0xA4, 1]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0010_0010, 0b0001_0001]);
+
+        let result = state.device.handle_write_command(&[0xA0, 2, 0b0010, 0, 0]);
+        assert_eq!(result, Err(Error::Pattern(PatternError::Overlaps(0))));
+        let result = state
+            .device
+            .handle_write_command(&[0xA0, 2, 0b0001_0000, 0, 0]);
+        assert_eq!(result, Err(Error::NotAnOutput(4)));
+        let result = state.device.handle_write_command(&[0xA2, 3]);
+        assert_eq!(result, Err(Error::Pattern(PatternError::Empty)));
+        let result = state.device.handle_write_command(&[0xA2, 4]);
+        assert_eq!(result, Err(Error::Pattern(PatternError::InvalidPattern(4))));
+
+        unwrap!(state.device.handle_write_command(&[0xA0, 0, 0, 0, 0]));
+        unwrap!(state.device.handle_write_command(&[0xA0, 1, 0, 0, 0]));
+    }
+
+    #[test]
+    fn rules_drive_outputs_from_inputs(state: &mut State) {
+        use rp_2040_gpio_expander::device::RULE_LATCHED;
+        use rp_2040_gpio_expander::rules::{RULE_AND, RULE_INVERT, RULE_LATCH};
+        use rp_2040_gpio_expander::{edges, gpios};
+
+        let mut inputs = [0u8; 2];
+        let mut buf = [0u8; 4];
+        unwrap!(state.device.handle_write_command(&[0x02, 0, 0]));
+
+        // Group 1 P0, read back on group 1 P4, follows group 0 P4, driven by group 0 P0
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xB0, 0, 0, 0b0001_0000, 1, 0, 0]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0]);
+        unwrap!(state.device.handle_write_command(&[0x02, 0b0001, 0]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0001_0001, 0b0001_0001]);
+        unwrap!(state.device.handle_write_read_command(&[0xB2, 0], &mut buf));
+        assert_eq!(buf, [0, 0b0001_0000, 1 << 3, 0]);
+
+        // The GPIO interrupt applies the rules on an input edge, without the device task
+        gpios::drive_gpios(1 << 6, 0);
+        edges::handle();
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0]);
+
+        // Inverted, and needing both P4 and P5
+        let flags = RULE_AND | RULE_INVERT;
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xB0, 0, 0, 0b0011_0000, 1, 0, flags]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs[1], 0b0001_0001);
+        unwrap!(state.device.handle_write_command(&[0x02, 0b0011, 0]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0011_0011, 0]);
+
+        // A latch holds the output until it's reset
+        unwrap!(state.device.handle_write_command(&[0x02, 0, 0]));
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xB0, 0, 0, 0b0001_0000, 1, 0, RULE_LATCH]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0]);
+        unwrap!(state.device.handle_write_command(&[0x02, 0b0001, 0]));
+        unwrap!(state.device.handle_write_command(&[0x02, 0, 0]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0b0001_0001]);
+        unwrap!(state.device.handle_write_read_command(&[0xB2, 0], &mut buf));
+        assert_eq!(buf[3], RULE_LATCH | RULE_LATCHED);
+        unwrap!(state.device.handle_write_command(&[0xB3, 0]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0]);
+        unwrap!(state.device.handle_write_read_command(&[0xB2, 0], &mut buf));
+        assert_eq!(buf[3], RULE_LATCH);
+
+        // Rules are part of the config snapshot
+        let config = state.device.config();
+        assert!(config.rules[0].is_some());
+
+        let result = state
+            .device
+            .handle_write_command(&[0xB0, 8, 0, 0b0001_0000, 1, 0, 0]);
+        assert_eq!(result, Err(Error::InvalidRule(8)));
+        let result = state
+            .device
+            .handle_write_command(&[0xB0, 1, 2, 0b0001_0000, 1, 0, 0]);
+        assert_eq!(result, Err(Error::InvalidGroup(2)));
+        let result = state
+            .device
+            .handle_write_command(&[0xB0, 1, 0, 0b0001_0000, 1, 8, 0]);
+        assert_eq!(result, Err(Error::InvalidPin(8)));
+        let result = state.device.handle_write_read_command(&[0xB2, 1], &mut buf);
+        assert_eq!(result, Err(Error::InvalidRule(1)));
+
+        unwrap!(state.device.handle_write_command(&[0xB1, 0]));
+        let result = state.device.handle_write_command(&[0xB3, 0]);
+        assert_eq!(result, Err(Error::InvalidRule(0)));
+        unwrap!(state.device.handle_write_command(&[0x02, 0, 0]));
+    }
+
+    #[test]
+    fn servos_move_within_their_limits(state: &mut State) {
+        use embassy_time::{block_for, Duration, Instant};
+        use rp_2040_gpio_expander::servo::{self, Error as ServoError};
+
+        let mut buf = [0u8; 4];
+        let read_servo = |state: &mut State, buf: &mut [u8; 4]| {
+            unwrap!(state.device.handle_write_read_command(&[0xC5, 0, 0], buf));
+            [
+                u16::from_le_bytes([buf[0], buf[1]]),
+                u16::from_le_bytes([buf[2], buf[3]]),
+            ]
+        };
+
+        // P0 of group 0 is GPIO 6, read back on P4, starting centred
+        unwrap!(state.device.handle_write_command(&[0xC0, 0, 0, 1]));
+        assert_eq!(read_servo(state, &mut buf), [1500, 1500]);
+
+        // 1.5ms pulses every 20ms are high 7.5% of the time
+        let mut inputs = [0u8; 2];
+        let (mut high, mut samples) = (0u32, 0u32);
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(40) {
+            state.device.read(&mut inputs);
+            high += (inputs[0] >> 4 & 1) as u32;
+            samples += 1;
+        }
+        let duty = high * 1000 / samples;
+        assert!((50..100).contains(&duty), "duty {}", duty);
+
+        unwrap!(state.device.handle_write_command(&[0xC3, 0, 0, 0xB0, 0x04]));
+        assert_eq!(read_servo(state, &mut buf), [1200, 1200]);
+        unwrap!(state.device.handle_write_command(&[0xC4, 0, 0, 180]));
+        assert_eq!(read_servo(state, &mut buf), [2000, 2000]);
+
+        // Narrower limits pull the target inside them, and the task moves it there
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xC1, 0, 0, 0x4C, 0x04, 0x6C, 0x07]));
+        assert_eq!(read_servo(state, &mut buf), [2000, 1900]);
+        servo::advance_due();
+        assert_eq!(read_servo(state, &mut buf), [1900, 1900]);
+        unwrap!(state.device.handle_write_command(&[0xC4, 0, 0, 0]));
+        assert_eq!(read_servo(state, &mut buf), [1100, 1100]);
+
+        // At 1000µs/s it takes 800ms to move across the limits
+        unwrap!(state.device.handle_write_command(&[0xC2, 0, 0, 0xE8, 0x03]));
+        unwrap!(state.device.handle_write_command(&[0xC3, 0, 0, 0xFF, 0xFF]));
+        assert_eq!(read_servo(state, &mut buf), [1100, 1900]);
+        block_for(Duration::from_millis(100));
+        servo::advance_due();
+        let [current, _] = read_servo(state, &mut buf);
+        assert!((1190..1220).contains(&current), "current {}", current);
+
+        let result = state
+            .device
+            .handle_write_command(&[0xC1, 0, 0, 0xE8, 0x03, 0x20, 0x4E]);
+        assert_eq!(result, Err(Error::Servo(ServoError::InvalidLimits)));
+        let result = state.device.handle_write_command(&[0xC0, 0, 4, 1]);
+        assert_eq!(result, Err(Error::NotAnOutput(4)));
+        let result = state.device.handle_write_command(&[0xC4, 0, 1, 90]);
+        assert_eq!(result, Err(Error::Servo(ServoError::NotAServo(7))));
+
+        // GPIO 7 shares GPIO 6's slice, so it can't be measured while GPIO 6 is a servo
+        let result = state.device.handle_write_command(&[0x78, 0b0000_0010, 0]);
+        assert_eq!(result, Err(Error::CantMeasure(7)));
+
+        unwrap!(state.device.handle_write_command(&[0xC0, 0, 0, 0]));
+        assert!(!servo::is_enabled(6));
+        state.device.write(&[0b0000_0001, 0]);
+        state.device.read(&mut inputs);
+        assert_eq!(inputs[0], 0b0001_0001);
+        state.device.write(&[0, 0]);
+    }
+
+    #[test]
+    fn steppers_ramp_through_their_moves(state: &mut State) {
+        use embassy_time::{Duration, Instant};
+        use rp_2040_gpio_expander::stepper::{
+            self, Error as StepperError, STEPPER_TRIGGER_INT_OUT,
+        };
+
+        let mut buf = [0u8; 4];
+        let mut inputs = [0u8; 2];
+        let run_move = || {
+            let start = Instant::now();
+            while stepper::busy() != 0 && start.elapsed() < Duration::from_secs(1) {
+                stepper::step_due();
+            }
+            start.elapsed()
+        };
+        let position = |state: &mut State, buf: &mut [u8; 4]| {
+            unwrap!(state.device.handle_write_read_command(&[0xD5, 0], buf));
+            i32::from_le_bytes(*buf)
+        };
+
+        // STEP on P0 and DIR on P1 of group 0
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xD0, 0, 0, 0, 1, STEPPER_TRIGGER_INT_OUT]));
+
+        // 50 steps at a constant 1000 steps/s take 50ms
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xD2, 0, 50, 0, 0, 0, 0xE8, 0x03, 0, 0]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs[0] & 0b0010_0000, 0b0010_0000);
+        unwrap!(state.device.handle_write_read_command(&[0xD6], &mut buf));
+        assert_eq!(buf[..2], [0b01, 0]);
+        let result = state
+            .device
+            .handle_write_command(&[0xD2, 0, 1, 0, 0, 0, 0xE8, 0x03, 0, 0]);
+        assert_eq!(result, Err(Error::Stepper(StepperError::Busy(0))));
+        let elapsed = run_move();
+        assert!(
+            (48..55).contains(&elapsed.as_millis()),
+            "{}ms",
+            elapsed.as_millis()
+        );
+        assert_eq!(position(state, &mut buf), 50);
+        unwrap!(state.device.handle_write_read_command(&[0xD6], &mut buf));
+        assert_eq!(buf[..2], [0, 0b01]);
+        unwrap!(state.device.handle_write_read_command(&[0xD6], &mut buf));
+        assert_eq!(buf[..2], [0, 0]);
+
+        // Ramping at 10000 steps/s² takes 100ms to reach 1000 steps/s over 50 steps, and the
+        // same to stop, so 100 steps take twice as long as without the ramps
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xD2, 0, 0x9C, 0xFF, 0xFF, 0xFF, 0xE8, 0x03, 0x10, 0x27]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs[0] & 0b0010_0000, 0);
+        let elapsed = run_move();
+        assert!(
+            (190..215).contains(&elapsed.as_millis()),
+            "{}ms",
+            elapsed.as_millis()
+        );
+        assert_eq!(position(state, &mut buf), -50);
+
+        // Stopping decelerates over the steps it takes to stop
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xD2, 0, 0xE8, 0x03, 0, 0, 0xE8, 0x03, 0x10, 0x27]));
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(150) {
+            stepper::step_due();
+        }
+
+        // Reconfiguring a moving stepper fails without pulling STEP low
+        state.device.read(&mut inputs);
+        while inputs[0] & 0b0001_0000 == 0 && start.elapsed() < Duration::from_millis(200) {
+            stepper::step_due();
+            state.device.read(&mut inputs);
+        }
+        let result = state.device.handle_write_command(&[0xD0, 0, 0, 0, 1, 0]);
+        assert_eq!(result, Err(Error::Stepper(StepperError::Busy(0))));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs[0] & 0b0001_0000, 0b0001_0000);
+        unwrap!(state.device.handle_write_command(&[0xD3, 0]));
+        run_move();
+        let stopped_at = position(state, &mut buf);
+        assert!((140..160).contains(&stopped_at), "{}", stopped_at);
+
+        unwrap!(state.device.handle_write_command(&[0xD4, 0, 0, 0, 0, 0]));
+        assert_eq!(position(state, &mut buf), 0);
+        let result = state
+            .device
+            .handle_write_command(&[0xD2, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(result, Err(Error::Stepper(StepperError::InvalidSpeed)));
+        let result = state.device.handle_write_command(&[0xD0, 1, 0, 0, 0, 0]);
+        assert_eq!(result, Err(Error::InvalidPin(0)));
+        let result = state.device.handle_write_command(&[0xD0, 2, 0, 0, 1, 0]);
+        assert_eq!(result, Err(Error::Stepper(StepperError::InvalidStepper(2))));
+        let result = state.device.handle_write_command(&[0xD0, 1, 0, 4, 1, 0]);
+        assert_eq!(result, Err(Error::NotAnOutput(4)));
+
+        unwrap!(state.device.handle_write_command(&[0xD1, 0]));
+        let result = state.device.handle_write_command(&[0xD3, 0]);
+        assert_eq!(result, Err(Error::Stepper(StepperError::NotConfigured(0))));
+        state.device.write(&[0, 0]);
+    }
+
+    #[test]
+    fn pixels_are_buffered_until_latched(state: &mut State) {
+        use rp_2040_gpio_expander::ws2812::{self, Error as Ws2812Error, MAX_PIXELS};
+
+        let mut buf = [0u8; 4];
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xE0, 0, 0xFF, 0x80, 0x01]));
+        unwrap!(state.device.handle_write_command(&[0xE1, 1, 2, 0, 0, 0x40]));
+        let len = unwrap!(state.device.handle_write_read_command(&[0xE5, 0], &mut buf));
+        assert_eq!(buf[..len], [0xFF, 0x80, 0x01]);
+        unwrap!(state.device.handle_write_read_command(&[0xE5, 2], &mut buf));
+        assert_eq!(buf[..3], [0, 0, 0x40]);
+
+        // The brightness scales the colours as they're sent, in GRB order
+        unwrap!(state.device.handle_write_command(&[0xE3, 3]));
+        unwrap!(state.device.handle_write_command(&[0xE2, 0x80]));
+        let mut words = [0u32; MAX_PIXELS];
+        assert_eq!(ws2812::frame(&mut words), 3);
+        assert_eq!(words[..3], [0x4080_0000, 0x0000_2000, 0x0000_2000]);
+        unwrap!(state.device.handle_write_read_command(&[0xE5, 0], &mut buf));
+        assert_eq!(buf[..3], [0xFF, 0x80, 0x01]);
+
+        let result = state.device.handle_write_command(&[0xE0, 64, 0, 0, 0]);
+        assert_eq!(result, Err(Error::Ws2812(Ws2812Error::InvalidPixel(64))));
+        let result = state.device.handle_write_command(&[0xE1, 60, 5, 0, 0, 0]);
+        assert_eq!(result, Err(Error::Ws2812(Ws2812Error::InvalidPixel(64))));
+        let result = state.device.handle_write_command(&[0xE3, 65]);
+        assert_eq!(result, Err(Error::Ws2812(Ws2812Error::InvalidPixel(65))));
+
+        unwrap!(state.device.handle_write_command(&[0xE1, 0, 64, 0, 0, 0]));
+        unwrap!(state.device.handle_write_command(&[0xE2, 0xFF]));
+        unwrap!(state.device.handle_write_command(&[0xE3, 64]));
+        unwrap!(state.device.handle_write_command(&[0xE4]));
+    }
+
+    #[test]
+    fn uart_fifos_bridge_bytes(state: &mut State) {
+        use core::task::Poll;
+        use embassy_futures::poll_once;
+        use rp_2040_gpio_expander::device::MAX_RESPONSE_LEN;
+        use rp_2040_gpio_expander::uart::{
+            self, Error as UartError, FIFO_SIZE, UART_OVERRUN, UART_RX_READY, UART_TX_READY,
+        };
+        use rp_2040_gpio_expander::SET_INT_OUT;
+
+        let mut buf = [0u8; MAX_RESPONSE_LEN];
+        let int_out = || poll_once(SET_INT_OUT.wait());
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xF0, 0x80, 0x25, 0, 0, 0b0_0011]));
+        let result = state
+            .device
+            .handle_write_command(&[0xF0, 0x80, 0x25, 0, 0, 0b1_1011]);
+        assert_eq!(result, Err(Error::Uart(UartError::InvalidFormat)));
+
+        // The task isn't running, so written bytes wait in the transmit FIFO
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xF1, UART_RX_READY, 4, 0]));
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xF2, 3, b'a', b'b', b'c']));
+        unwrap!(state.device.handle_write_read_command(&[0xF4], &mut buf));
+        assert_eq!(buf[..3], [0, FIFO_SIZE as u8 - 3, 0]);
+        let mut sent = [0u8; 8];
+        assert_eq!(uart::take_tx(&mut sent), 3);
+        assert_eq!(sent[..3], *b"abc");
+        unwrap!(state.device.handle_write_read_command(&[0xF4], &mut buf));
+        assert_eq!(buf[..3], [0, FIFO_SIZE as u8, UART_TX_READY]);
+
+        // Received bytes flag when they reach the threshold, and reads return them with a count
+        uart::receive(b"xyz");
+        unwrap!(state.device.handle_write_read_command(&[0xF4], &mut buf));
+        assert_eq!(buf[..3], [3, FIFO_SIZE as u8, 0]);
+        uart::receive(b"w");
+        let len = unwrap!(state.device.handle_write_read_command(&[0xF3, 2], &mut buf));
+        assert_eq!(buf[..len], [2, b'x', b'y']);
+        let len = unwrap!(state.device.handle_write_read_command(&[0xF3, 8], &mut buf));
+        assert_eq!(buf[..len], [2, b'z', b'w']);
+
+        // Draining the FIFO leaves INT_OUT asserted until the status has been read
+        assert!(matches!(int_out(), Poll::Ready(true)));
+        unwrap!(state.device.handle_write_read_command(&[0xF4], &mut buf));
+        assert_eq!(buf[..3], [0, FIFO_SIZE as u8, UART_RX_READY]);
+        assert!(matches!(int_out(), Poll::Ready(false)));
+
+        // A full receive FIFO drops bytes, a full transmit FIFO rejects the whole write
+        uart::receive(&[0x55; FIFO_SIZE + 1]);
+        let len = unwrap!(state
+            .device
+            .handle_write_read_command(&[0xF3, 0xFF], &mut buf));
+        assert_eq!(len, MAX_RESPONSE_LEN);
+        unwrap!(state.device.handle_write_read_command(&[0xF4], &mut buf));
+        assert_eq!(
+            buf[..3],
+            [
+                FIFO_SIZE as u8 - 32,
+                FIFO_SIZE as u8,
+                UART_RX_READY | UART_OVERRUN
+            ]
+        );
+        let mut rest = [0u8; FIFO_SIZE];
+        assert_eq!(uart::read(&mut rest), FIFO_SIZE - 32);
+        let mut write = [0u8; 2 + 60];
+        write[..2].copy_from_slice(&[0xF2, 60]);
+        unwrap!(state.device.handle_write_command(&write));
+        write[1] = 5;
+        let result = state.device.handle_write_command(&write[..7]);
+        assert_eq!(result, Err(Error::Uart(UartError::TxFull(4))));
+        let result = state.device.handle_write_command(&[0xF1, 0, 65, 0]);
+        assert_eq!(result, Err(Error::Uart(UartError::InvalidThreshold(65))));
+
+        assert_eq!(uart::take_tx(&mut rest), 60);
+        unwrap!(state.device.handle_write_command(&[0xF1, 0, 1, 0]));
+        uart::take_flags();
+    }
+
+    #[test]
+    fn transports_share_requests(state: &mut State) {
+        use rp_2040_gpio_expander::transport::{Dispatcher, Request};
+
+        let mut dispatcher = Dispatcher::new(&mut state.device);
+        assert_eq!(
+            dispatcher.handle(&Request::write(&[0x03, 0x0F, 0x0F])),
+            None
+        );
+        assert_eq!(
+            dispatcher.handle(&Request::write(&[0x02, 0b0101, 0b0011])),
+            None
+        );
+        let response = unwrap!(dispatcher.handle(&Request::Read));
+        assert_eq!(response[..], [0b0101_0101, 0b0011_0011]);
+        let response = unwrap!(dispatcher.handle(&Request::write_read(&[0x01])));
+        assert_eq!(response[..], [0x0F, 0x0F]);
+        assert_eq!(dispatcher.handle(&Request::write_read(&[0xFF])), None);
+
+        // SPI frames carry the kind of transaction in their first byte
+        assert_eq!(
+            Request::from_frame(&[0x02, 0x01]),
+            Some(Request::write_read(&[0x01]))
+        );
+        assert_eq!(
+            Request::from_frame(&[0x01, 0x02, 0, 0]),
+            Some(Request::write(&[0x02, 0, 0]))
+        );
+        assert_eq!(Request::from_frame(&[0x00, 0x55]), Some(Request::Read));
+        assert_eq!(Request::from_frame(&[0xFF, 0x01]), None);
+        assert_eq!(Request::from_frame(&[]), None);
+
+        let request = unwrap!(Request::from_frame(&[0x01, 0x02, 0, 0]));
+        assert_eq!(dispatcher.handle(&request), None);
+        let response = unwrap!(dispatcher.handle(&Request::Read));
+        assert_eq!(response[..], [0, 0]);
+    }
+
+    #[test]
+    fn dispatcher_pads_responses_to_the_read_length(state: &mut State) {
+        use rp_2040_gpio_expander::device::MAX_RESPONSE_LEN;
+        use rp_2040_gpio_expander::transport::{Dispatcher, Request, FILL};
+
+        assert_eq!(Request::new(&[], 0), None);
+        assert_eq!(Request::new(&[], 2), Some(Request::Read));
+        assert_eq!(
+            Request::new(&[0x11, 1], 0),
+            Some(Request::write(&[0x11, 1]))
+        );
+        assert_eq!(Request::new(&[0x01], 1), Some(Request::write_read(&[0x01])));
+
+        let mut dispatcher = Dispatcher::new(&mut state.device);
+        assert!(dispatcher.dispatch(&[0x03, 0x0F, 0x0F], 0).is_empty());
+        assert!(dispatcher.dispatch(&[0x02, 0b0011, 0b0110], 0).is_empty());
+        // A read without a write returns every group, the rest is padding
+        assert_eq!(
+            dispatcher.dispatch(&[], 4),
+            [0b0011_0011, 0b0110_0110, FILL, FILL]
+        );
+        assert_eq!(dispatcher.dispatch(&[], 1), [0b0011_0011]);
+        assert_eq!(dispatcher.dispatch(&[0x01], 3), [0x0F, 0x0F, FILL]);
+        // Failed commands read as padding, and reads are cut to the longest response
+        assert_eq!(dispatcher.dispatch(&[0xFF], 2), [FILL, FILL]);
+        assert_eq!(dispatcher.dispatch(&[0x01], 255).len(), MAX_RESPONSE_LEN);
+
+        assert!(dispatcher.dispatch(&[0x02, 0, 0], 0).is_empty());
+    }
+
+    #[test]
+    fn console_lines_become_requests() {
+        use rp_2040_gpio_expander::console::{parse, Action, Error as ConsoleError};
+        use rp_2040_gpio_expander::transport::Request;
+
+        let valid_test_cases = [
+            ("read", Action::Send(Request::Read)),
+            ("  help ", Action::Help),
+            ("config", Action::Config),
+            ("save", Action::Send(Request::write(&[0x50]))),
+            (
+                "modes 0x0F 0b1111",
+                Action::Send(Request::write(&[0x03, 0x0F, 0x0F])),
+            ),
+            (
+                "write 0b0000_0101 3",
+                Action::Send(Request::write(&[0x02, 0b0101, 3])),
+            ),
+            ("pull up 1 2", Action::Send(Request::write(&[0x31, 1, 2]))),
+            ("pull none 0 0", Action::Send(Request::write(&[0x32, 0, 0]))),
+            ("cmd 0x11 255", Action::Send(Request::write(&[0x11, 0xFF]))),
+            ("query 0x01", Action::Send(Request::write_read(&[0x01]))),
+        ];
+        for (line, action) in valid_test_cases {
+            assert_eq!(parse(line), Ok(action));
+        }
+
+        let invalid_test_cases = [
+            ("", ConsoleError::MissingArgument),
+            ("bogus", ConsoleError::UnknownCommand),
+            ("pull sideways 0 0", ConsoleError::UnknownCommand),
+            ("write 1", ConsoleError::MissingArgument),
+            ("write 1 2 3", ConsoleError::TooManyArguments),
+            ("read 1", ConsoleError::TooManyArguments),
+            ("modes 256 0", ConsoleError::InvalidNumber),
+            ("modes 0x 0", ConsoleError::InvalidNumber),
+            ("modes 0b2 0", ConsoleError::InvalidNumber),
+            ("query", ConsoleError::MissingArgument),
+        ];
+        for (line, error) in invalid_test_cases {
+            assert_eq!(parse(line), Err(error));
+        }
+    }
+
+    #[test]
+    fn snapshots_read_the_whole_bank_at_once(state: &mut State) {
+        use rp_2040_gpio_expander::device::{MAX_RESPONSE_LEN, SNAPSHOT_LEN, STATUS_RULE_LATCHED};
+        use rp_2040_gpio_expander::rules::RULE_LATCH;
+
+        let mut buf = [0u8; MAX_RESPONSE_LEN];
+        unwrap!(state.device.handle_write_command(&[0x03, 0x0F, 0x0F]));
+        unwrap!(state.device.handle_write_command(&[0x32, 0xFF, 0xFF]));
+        unwrap!(state.device.handle_write_command(&[0x31, 0b0001_0000, 0]));
+        unwrap!(state.device.handle_write_command(&[0x30, 0, 0b1000_0000]));
+        unwrap!(state.device.handle_write_command(&[0x02, 0b0101, 0b0011]));
+        unwrap!(state.device.group_mut(0)).flag_interrupts(0b0100_0000);
+
+        let len = unwrap!(state.device.handle_write_read_command(&[0x20], &mut buf));
+        assert_eq!(len, SNAPSHOT_LEN);
+        let snapshot = &buf[..len];
+        assert_eq!(snapshot[0..2], [0x0F, 0x0F]);
+        assert_eq!(snapshot[2..4], [0b0001_0000, 0]);
+        assert_eq!(snapshot[4..6], [0, 0b1000_0000]);
+        assert_eq!(snapshot[6] & 0x0F, 0b0101);
+        assert_eq!(snapshot[7] & 0x0F, 0b0011);
+        assert_eq!(snapshot[8..10], [0b0101_0101, 0b0011_0011]);
+        assert_eq!(snapshot[10..12], [0b0100_0000, 0]);
+        assert_eq!(snapshot[12], 0);
+
+        // The interrupt flags are cleared by reading the inputs
+        unwrap!(state.device.handle_write_read_command(&[0x20], &mut buf));
+        assert_eq!(buf[10..12], [0, 0]);
+        unwrap!(state.device.group_mut(1)).flag_interrupts(0b0001_0000);
+        let mut all = [0u8; 2];
+        state.device.handle_read_command(&mut all);
+        assert_eq!(state.device.snapshot()[10..12], [0, 0]);
+
+        // A latching rule shows in the status
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xB0, 0, 0, 0b0001_0000, 1, 3, RULE_LATCH]));
+        let snapshot = state.device.snapshot();
+        assert_eq!(snapshot[SNAPSHOT_LEN - 1], STATUS_RULE_LATCHED);
+        unwrap!(state.device.handle_write_command(&[0xB1, 0]));
+        unwrap!(state.device.handle_write_command(&[0x31, 0xFF, 0xFF]));
+        unwrap!(state.device.handle_write_command(&[0x02, 0, 0]));
+    }
+
+    #[test]
+    fn batches_apply_all_or_nothing(state: &mut State) {
+        use embassy_time::{Duration, Instant};
+        use rp_2040_gpio_expander::commands::{Error as CmdError, GpioCommand};
+        use rp_2040_gpio_expander::device::BATCH_OK;
+        use rp_2040_gpio_expander::pattern::Error as PatternError;
+        use rp_2040_gpio_expander::timed::{self, Error as TimedError, OutputPin, MAX_PULSES};
+        use rp_2040_gpio_expander::uart;
+
+        let mut inputs = [0u8; 2];
+        let mut status = [0u8; 2];
+        let batch = [
+            0x03, 0x0F, 0x0F, // SetIoModes
+            0xF2, 2, b'h', b'i', // WriteUart, its data isn't parsed as commands
+            0x02, 0b0011, 0b0101, // WriteAllOutputs
+            0x11, 0b1001, // WriteOutputs1, applied after WriteAllOutputs
+        ];
+        let commands: heapless::Vec<_, 4> = GpioCommand::batch(&batch)
+            .map(|command| unwrap!(command).0)
+            .collect();
+        assert_eq!(
+            commands[..],
+            [
+                GpioCommand::SetIoModes(0x0F, 0x0F),
+                GpioCommand::WriteUart(2),
+                GpioCommand::WriteAllOutputs(0b0011, 0b0101),
+                GpioCommand::WriteOutputs1(0b1001),
+            ]
+        );
+        unwrap!(state.device.handle_write_command(&batch));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b1001_1001, 0b0101_0101]);
+        let mut sent = [0u8; 4];
+        assert_eq!(uart::take_tx(&mut sent), 2);
+        assert_eq!(sent[..2], *b"hi");
+
+        // Nothing is applied if any command is malformed, incomplete or responds
+        let invalid_test_cases: [(&[u8], Error); 4] = [
+            (
+                &[0x02, 0, 0, 0x04, 0, 0],
+                Error::FailedToParseCmd(CmdError::BadInput),
+            ),
+            (
+                &[0x02, 0, 0, 0x11],
+                Error::FailedToParseCmd(CmdError::BadOffset),
+            ),
+            (
+                &[0x02, 0, 0, 0x01],
+                Error::InvalidWriteCmd(GpioCommand::ReadIoModes),
+            ),
+            (&[], Error::FailedToParseCmd(CmdError::BadOffset)),
+        ];
+        for (batch, error) in invalid_test_cases {
+            assert_eq!(state.device.handle_write_command(batch), Err(error));
+            state.device.read(&mut inputs);
+            assert_eq!(inputs, [0b1001_1001, 0b0101_0101]);
+        }
+
+        // Nor if a later command has an invalid argument, given what's before it
+        let invalid_arguments: [(&[u8], Error); 3] = [
+            (&[0x11, 0, 0x0E, 1, 0x12, 0], Error::InvalidBank(1)),
+            (&[0x02, 0, 0, 0x90, 0, 8, 1, 2, 0], Error::InvalidPin(8)),
+            (
+                &[0x02, 0, 0, 0x03, 0x0E, 0x0F, 0x90, 0, 0, 1, 2, 0],
+                Error::NotAnOutput(0),
+            ),
+        ];
+        let mut modes = [0u8; 2];
+        for (batch, error) in invalid_arguments {
+            assert_eq!(state.device.handle_write_command(batch), Err(error));
+            state.device.read(&mut inputs);
+            assert_eq!(inputs, [0b1001_1001, 0b0101_0101]);
+            unwrap!(state.device.handle_write_read_command(&[0x01], &mut modes));
+            assert_eq!(modes, [0x0F, 0x0F]);
+        }
+        // A pin made an output earlier in the batch can be pulsed
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x03, 0x1F, 0x0F, 0x90, 0, 4, 1, 0, 0, 0x03, 0x0F, 0x0F]));
+        timed::apply_due();
+
+        // Zeros after a command are padding, so fixed length writes are still accepted
+        unwrap!(state.device.handle_write_command(&[0x11, 0b0110, 0]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0110_0110, 0b0101_0101]);
+        let result = state.device.handle_write_command(&[0x11, 0, 0, 0x12, 0]);
+        assert_eq!(result, Err(Error::FailedToParseCmd(CmdError::BadInput)));
+
+        // A batch that runs out of pulse slots part-way is rejected before anything is applied,
+        // and reports the command that failed
+        for gpio in [10, 11, 12, 13, 18, 19, 20] {
+            // The pins are inputs, so holding their slots leaves them alone
+            unwrap!(timed::pulse(
+                OutputPin { gpio },
+                true,
+                Duration::from_millis(1)
+            ));
+        }
+        assert_eq!(timed::pending(), MAX_PULSES - 1);
+        let batch = [
+            0x11, 0b0001, // WriteOutputs1
+            0x90, 0, 1, 1, 1, 0, // Pulse P1, takes the last slot
+            0x90, 0, 2, 1, 1, 0, // Pulse P2, there's no slot left
+            0x11, 0b1000, // WriteOutputs1
+        ];
+        let result = state.device.handle_write_command(&batch);
+        assert_eq!(result, Err(Error::Timed(TimedError::Full)));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0110_0110, 0b0101_0101]);
+        unwrap!(state.device.handle_write_read_command(&[0x10], &mut status));
+        assert_eq!(status, [2, 0]);
+        let start = Instant::now();
+        while timed::pending() != 0 && start.elapsed() < Duration::from_millis(10) {
+            timed::apply_due();
+        }
+        assert_eq!(timed::pending(), 0);
+
+        // A conflict that can only be seen when it's applied stops the batch there
+        let result = state
+            .device
+            .handle_write_command(&[0x11, 0b0001, 0xA2, 2, 0x11, 0b1000]);
+        assert_eq!(result, Err(Error::Pattern(PatternError::Empty)));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0b0001_0001, 0b0101_0101]);
+        unwrap!(state.device.handle_write_read_command(&[0x10], &mut status));
+        assert_eq!(status, [1, 1]);
+        unwrap!(state.device.handle_write_command(&[0x11, 0, 0x12, 0]));
+        unwrap!(state.device.handle_write_read_command(&[0x10], &mut status));
+        assert_eq!(status, [BATCH_OK, 2]);
+
+        unwrap!(state.device.handle_write_command(&[0x02, 0, 0]));
+    }
+
+    #[test]
+    fn staged_settings_apply_on_commit(state: &mut State) {
+        use rp_2040_gpio_expander::gpios::staging::StagedGroup;
+
+        let mut buf = [0u8; 2];
+        unwrap!(state.device.handle_write_command(&[0x03, 0, 0]));
+        unwrap!(state.device.handle_write_command(&[0x55, 0b0101, 0b0011]));
+        unwrap!(state.device.handle_write_command(&[0x52, 0x0F, 0x0F]));
+        // Nothing changes until the commit
+        unwrap!(state.device.handle_write_read_command(&[0x01], &mut buf));
+        assert_eq!(buf, [0, 0]);
+        assert_eq!(
+            state.device.staged(0),
+            Some(&StagedGroup {
+                modes: Some(0x0F),
+                outputs: Some(0b0101),
+                ..StagedGroup::default()
+            })
+        );
+        unwrap!(state.device.handle_write_command(&[0x57]));
+        unwrap!(state.device.handle_write_read_command(&[0x01], &mut buf));
+        assert_eq!(buf, [0x0F, 0x0F]);
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0101_0101, 0b0011_0011]);
+        assert!(unwrap!(state.device.staged(0)).is_empty());
+
+        // Discarding drops everything staged
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x55, 0, 0, 0x52, 0, 0, 0x58, 0x57]));
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0101_0101, 0b0011_0011]);
+
+        // Pulls are staged as whole registers, and polarity along with them
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x53, 0, 0, 0x54, 0xF0, 0, 0x56, 0xF0, 0, 0x57]));
+        assert_eq!(unwrap!(state.device.group(0)).get_pin_pulls(), (0, 0xF0));
+        assert_eq!(unwrap!(state.device.group(1)).get_pin_pulls(), (0, 0));
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b1010_0101, 0b0011_0011]);
+
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x53, 0xFF, 0xFF, 0x54, 0xFF, 0, 0x56, 0, 0, 0x57]));
+        assert_eq!(unwrap!(state.device.group(0)).get_pin_pulls(), (0xFF, 0));
+        unwrap!(state.device.handle_write_command(&[0x02, 0, 0]));
+    }
+
+    #[test]
+    fn output_latch_is_kept_for_inputs(state: &mut State) {
+        let mut buf = [0u8; 2];
+        unwrap!(state.device.handle_write_command(&[0x03, 0, 0]));
+        // Writes to inputs only set the latch, the pulled up loopback still reads high
+        unwrap!(state.device.handle_write_command(&[0x02, 0b0101, 0b0011]));
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0xFF, 0xFF]);
+        unwrap!(state.device.handle_write_read_command(&[0x13], &mut buf));
+        assert_eq!(buf, [0b0101, 0b0011]);
+
+        // Becoming outputs drives the latch at once, with no read in between
+        unwrap!(state.device.handle_write_command(&[0x03, 0x0F, 0x0F]));
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0101_0101, 0b0011_0011]);
+
+        // The latch survives a round trip through input
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x03, 0, 0, 0x02, 0b1010, 0, 0x03, 0x0F, 0x0F]));
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b1010_1010, 0]);
+        unwrap!(state.device.handle_write_read_command(&[0x13], &mut buf));
+        assert_eq!(buf, [0b1010, 0]);
+
+        unwrap!(state.device.handle_write_command(&[0x02, 0, 0]));
+    }
+
+    #[test]
+    fn timed_changes_skip_pins_that_became_inputs(state: &mut State) {
+        use embassy_time::{block_for, Duration};
+        use rp_2040_gpio_expander::timed;
+
+        let mut buf = [0u8; 2];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.set_pin_open_drain(&[0b0000_0001, 0]);
+        state.device.write(&[0b0000_0001, 0]);
+
+        // Pull open drain P0 low in 2ms, but make it an input first
+        let mut slot = [0u8; 1];
+        unwrap!(state
+            .device
+            .handle_write_read_command(&[0x92, 0, 0, 0, 2, 0], &mut slot));
+        state.device.set_pin_modes(&[0b0000_1110, 0b0000_1111]);
+        block_for(Duration::from_millis(3));
+        timed::apply_due();
+        assert_eq!(timed::pending(), 0);
+        // The input isn't driven, so its pull up still holds the line high
+        state.device.read(&mut buf);
+        assert_eq!(buf, [0b0001_0001, 0]);
+
+        state.device.set_pin_open_drain(&[0, 0]);
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.write(&[0, 0]);
+    }
+
+    #[test]
+    fn patterns_skip_pins_that_became_inputs(state: &mut State) {
+        use embassy_time::{block_for, Duration};
+        use rp_2040_gpio_expander::pattern;
+
+        let mut inputs = [0u8; 2];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        state.device.set_pin_open_drain(&[0, 0b0000_0010]);
+        state.device.write(&[0, 0]);
+
+        // Open drain P1 of group 1 released then pulled low for 10ms each, forever
+        unwrap!(state.device.handle_write_command(&[0xA0, 3, 0, 0b0010, 0]));
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xA1, 3, 0, 0b0010, 10, 0]));
+        unwrap!(state.device.handle_write_command(&[0xA1, 3, 0, 0, 10, 0]));
+        unwrap!(state.device.handle_write_command(&[0xA2, 3]));
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0b0010_0010]);
+
+        // As an input it isn't pulled low by the second step
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1101]);
+        block_for(Duration::from_millis(12));
+        pattern::advance_due();
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0b0010_0010]);
+
+        // It's played again once it's an output
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        block_for(Duration::from_millis(20));
+        pattern::advance_due();
+        state.device.read(&mut inputs);
+        assert_eq!(inputs, [0, 0]);
+
+        unwrap!(state.device.handle_write_command(&[0xA0, 3, 0, 0, 0]));
+        state.device.set_pin_open_drain(&[0, 0]);
+        state.device.write(&[0, 0]);
+    }
+
+    #[test]
+    fn loaded_configs_drop_invalid_rules(state: &mut State) {
+        use rp_2040_gpio_expander::rules::{Rule, MAX_RULES};
+
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+        let mut config = state.device.config();
+        config.rules = [None; MAX_RULES];
+        config.rules[0] = Some(Rule::new(0, 0b0001_0000, 1, 8, 0));
+        config.rules[1] = Some(Rule::new(8, 0b0001_0000, 1, 0, 0));
+        config.rules[2] = Some(Rule::new(0, 0b0001_0000, 1, 0, 0));
+
+        // Applying the rules would panic if they weren't checked first
+        state.device.apply_config(&config);
+        assert_eq!(state.device.rule(0), Err(Error::InvalidRule(0)));
+        assert_eq!(state.device.rule(1), Err(Error::InvalidRule(1)));
+        assert_eq!(state.device.rule(2), Ok(Rule::new(0, 0b0001_0000, 1, 0, 0)));
+
+        unwrap!(state.device.handle_write_command(&[0xB1, 2]));
+        unwrap!(state.device.handle_write_command(&[0x02, 0, 0]));
+    }
+
+    #[test]
+    fn measured_masks_apply_all_or_nothing(state: &mut State) {
+        let mut buf = [0u8; 4];
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+
+        // P5 is GPIO 11, but P6 is GPIO 12 which can't be measured, so neither is
+        let result = state.device.handle_write_command(&[0x78, 0b0110_0000, 0]);
+        assert_eq!(result, Err(Error::CantMeasure(12)));
+        unwrap!(state.device.handle_write_read_command(&[0x79], &mut buf));
+        assert_eq!(buf[..2], [0, 0]);
+
+        // P1 is GPIO 7, which is being driven as an output
+        let result = state.device.handle_write_command(&[0x78, 0b0000_0010, 0]);
+        assert_eq!(result, Err(Error::NotAnInput(1)));
+        unwrap!(state.device.handle_write_read_command(&[0x79], &mut buf));
+        assert_eq!(buf[..2], [0, 0]);
+
+        // Measuring other pins releases the ones that were measured
+        unwrap!(state.device.handle_write_command(&[0x78, 0b0010_0000, 0]));
+        unwrap!(state.device.handle_write_command(&[0x78, 0, 0b0010_0000]));
+        unwrap!(state.device.handle_write_read_command(&[0x79], &mut buf));
+        assert_eq!(buf[..2], [0, 0b0010_0000]);
+        unwrap!(state.device.handle_write_command(&[0x78, 0, 0]));
+    }
+
+    #[test]
+    fn int_out_stays_asserted_while_a_source_is_pending(state: &mut State) {
+        use core::task::Poll;
+        use embassy_futures::poll_once;
+        use embassy_time::{Duration, Instant};
+        use rp_2040_gpio_expander::stepper::{self, STEPPER_TRIGGER_INT_OUT};
+        use rp_2040_gpio_expander::SET_INT_OUT;
+
+        let mut buf = [0u8; 2];
+        let int_out = || poll_once(SET_INT_OUT.wait());
+        state.device.set_pin_modes(&[0b0000_1111, 0b0000_1111]);
+
+        // An edge on group 0 P4 and a completed move both assert INT_OUT
+        unwrap!(state.device.group_mut(0)).flag_interrupts(0b0001_0000);
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xD0, 0, 1, 0, 1, STEPPER_TRIGGER_INT_OUT]));
+        unwrap!(state
+            .device
+            .handle_write_command(&[0xD2, 0, 1, 0, 0, 0, 0xE8, 0x03, 0, 0]));
+        let start = Instant::now();
+        while stepper::busy() != 0 && start.elapsed() < Duration::from_millis(100) {
+            stepper::step_due();
+        }
+
+        // Reading a source that has nothing pending leaves INT_OUT asserted
+        unwrap!(state.device.handle_write_read_command(&[0x66], &mut buf));
+        assert_eq!(buf, [0, 0]);
+        assert!(matches!(int_out(), Poll::Ready(true)));
+
+        // As does reading the inputs while the move is still unread
+        unwrap!(state
+            .device
+            .handle_write_read_command(&[0x21], &mut buf[..1]));
+        assert!(matches!(int_out(), Poll::Ready(true)));
+
+        // And reading the move while an edge is still unread
+        unwrap!(state.device.group_mut(0)).flag_interrupts(0b0001_0000);
+        unwrap!(state.device.handle_write_read_command(&[0xD6], &mut buf));
+        assert_eq!(buf, [0, 0b01]);
+        assert!(matches!(int_out(), Poll::Ready(true)));
+
+        // INT_OUT is released once every source has been read
+        unwrap!(state
+            .device
+            .handle_write_read_command(&[0x21], &mut buf[..1]));
+        assert!(matches!(int_out(), Poll::Ready(false)));
+        unwrap!(state.device.handle_write_command(&[0xD1, 0]));
+        state.device.write(&[0, 0]);
+    }
+
+    #[test]
+    fn servos_are_released_when_their_pin_becomes_an_input(state: &mut State) {
+        use embassy_time::{Duration, Instant};
+        use rp_2040_gpio_expander::servo;
+
+        let mut inputs = [0u8; 2];
+        let mut modes = [0u8; 2];
+        let low_samples = |state: &mut State, inputs: &mut [u8; 2]| {
+            let mut low = 0u32;
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(40) {
+                state.device.read(inputs);
+                low += (inputs[0] >> 4 & 1 == 0) as u32;
+            }
+            low
+        };
+
+        // P0 of group 0 is GPIO 6, read back on P4 which is pulled up
+        unwrap!(state.device.handle_write_command(&[0xC0, 0, 0, 1]));
+        assert!(low_samples(state, &mut inputs) > 0);
+
+        // Once P0 is an input nothing drives the loop, so P4 stays high
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x03, 0b0000_1110, 0x0F]));
+        assert!(!servo::is_enabled(6));
+        assert_eq!(low_samples(state, &mut inputs), 0);
+
+        // Staging P0 as an input releases the servo too
+        unwrap!(state.device.handle_write_command(&[0x03, 0x0F, 0x0F]));
+        unwrap!(state.device.handle_write_command(&[0xC0, 0, 0, 1]));
+        unwrap!(state
+            .device
+            .handle_write_command(&[0x52, 0b0000_1110, 0x0F, 0x57]));
+        assert!(!servo::is_enabled(6));
+        assert_eq!(low_samples(state, &mut inputs), 0);
+
+        // And as an output again it's driven from its latch
+        unwrap!(state.device.handle_write_command(&[0x03, 0x0F, 0x0F]));
+        unwrap!(state.device.handle_write_read_command(&[0x01], &mut modes));
+        assert_eq!(modes, [0x0F, 0x0F]);
+        state.device.write(&[0, 0]);
+        state.device.read(&mut inputs);
+        assert_eq!(inputs[0], 0);
+    }
+
+    #[test]
+    fn measurements_stop_when_their_pin_becomes_an_output(state: &mut State) {
+        let mut buf = [0u8; 2];
+        let mut inputs = [0u8; 2];
+
+        // P1 of group 0 is GPIO 7, read back on P5 which is pulled up
+        state.device.set_pin_modes(&[0b0000_1101, 0x0F]);
+        unwrap!(state.device.handle_write_command(&[0x78, 0b0000_0010, 0]));
+
+        // As an output it's back on SIO, so its low latch pulls P5 low
+        state.device.write(&[0, 0]);
+        state.device.set_pin_modes(&[0x0F, 0x0F]);
+        unwrap!(state.device.handle_write_read_command(&[0x79], &mut buf));
+        assert_eq!(buf, [0, 0]);
+        state.device.read(&mut inputs);
+        assert_eq!(inputs[0] & 0b0010_0000, 0);
+
+        // Staging it as an output stops the measurement too
+        state.device.set_pin_modes(&[0b0000_1101, 0x0F]);
+        unwrap!(state.device.handle_write_command(&[0x78, 0b0000_0010, 0]));
+        unwrap!(state.device.handle_write_command(&[0x52, 0x0F, 0x0F, 0x57]));
+        unwrap!(state.device.handle_write_read_command(&[0x79], &mut buf));
+        assert_eq!(buf, [0, 0]);
+        state.device.read(&mut inputs);
+        assert_eq!(inputs[0] & 0b0010_0000, 0);
+    }
+
+    #[test]
+    fn config_is_not_touched_while_outputs_are_timed(state: &mut State) {
+        use embassy_time::{Duration, Instant};
+        use rp_2040_gpio_expander::timed;
+
+        state.device.set_pin_modes(&[0x0F, 0x0F]);
+
+        // Erasing the flash would stall the end of the pulse
+        unwrap!(state.device.handle_write_command(&[0x90, 0, 0, 1, 1, 0]));
+        for command in [0x50, 0x51] {
+            let result = state.device.handle_write_command(&[command]);
+            assert_eq!(result, Err(Error::Config(ConfigError::Busy)));
+        }
+        let result = state.device.handle_write_command(&[0x50, 0x11, 0]);
+        assert_eq!(result, Err(Error::Config(ConfigError::Busy)));
+
+        let start = Instant::now();
+        while timed::pending() != 0 && start.elapsed() < Duration::from_millis(10) {
+            timed::apply_due();
+        }
+        let result = state.device.handle_write_command(&[0x50]);
+        assert_eq!(result, Err(Error::Config(ConfigError::NoStorage)));
+        state.device.write(&[0, 0]);
+    }
 }